@@ -94,5 +94,45 @@ fn tree_build_perft(c: &mut Criterion) {
     });
 }
 
+fn reduce_strategies_perft(c: &mut Criterion) {
+    use asg::{
+        deftree,
+        facts::{Condition::Positive, Facts},
+        reduce::{ReduceOptions, Strategy},
+        template::RuleSet,
+    };
+    let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
+    let rules = RuleSet::new();
+    let mut group = c.benchmark_group("Reduce strategies");
+    for (name, strategy) in [
+        ("best first", Strategy::BestFirst),
+        ("beam", Strategy::Beam { width: 4 }),
+        (
+            "iterative deepening",
+            Strategy::IterativeDeepening { max_depth: 3 },
+        ),
+        (
+            "hill climbing",
+            Strategy::HillClimbing {
+                restarts: 8,
+                seed: 42,
+            },
+        ),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let options = ReduceOptions {
+                    facts: Facts::new().with('x', Positive).with('y', Positive),
+                    max_iter: 200,
+                    ..Default::default()
+                };
+                strategy.reduce(tree.clone(), &rules, options).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(parser_benches, tree_build_perft);
-criterion_main!(parser_benches);
+criterion_group!(reduce_benches, reduce_strategies_perft);
+criterion_main!(parser_benches, reduce_benches);
@@ -0,0 +1,608 @@
+use crate::{
+    exact::Rational,
+    named::NamedConstant,
+    tree::{BinaryOp, BinaryOp::*, Node::*, Tree, TreeError, UnaryOp, UnaryOp::*},
+};
+use num_bigint::{BigInt, Sign};
+use num_rational::BigRational;
+use std::io::{Read, Write};
+
+/// Every binary stream starts with these bytes.
+pub const MAGIC: [u8; 4] = *b"SYMB";
+
+/// The version of the format written by `BinaryWriter`. Readers
+/// accept any version up to and including this one.
+pub const FORMAT_VERSION: u16 = 3;
+
+/*
+Layout of a binary stream:
+
+    magic           4 bytes, "SYMB"
+    version         u16, little endian
+    tree*           zero or more trees, until the end of the stream
+
+Layout of a single tree:
+
+    node count      varint
+    node*           exactly 'node count' nodes, in topological order
+
+Layout of a single node:
+
+    tag             u8, see `TAG_*` and the op tags below
+    Constant        f64, little endian
+    Rational        varint byte count; numerator, two's complement,
+                    little endian; varint byte count; denominator,
+                    unsigned, little endian (since version 2)
+    Named           varint byte count; name, UTF-8; varint byte count;
+                    LaTeX, UTF-8; f64 value, little endian (since
+                    version 3)
+    Symbol          varint, unicode scalar value of the label
+    Unary           varint, offset of the input
+    Binary          varint, offset of the lhs; varint, offset of the rhs
+
+Offsets are relative, i.e. a node at index 'i' with an input at index
+'j' stores 'i - j'. Valid offsets are therefore always at least 1, and
+small for most nodes, which keeps the varints short. The tags are
+fixed by this module and are independent of the in-memory enums, so
+reordering or extending the ops does not break existing files.
+
+Named constants are stored with their LaTeX and value, but reading
+them does not define them. The reader looks up each name among the
+constants that are already defined, and fails if the name is unknown
+or has a different value. Defining the constants a stream refers to
+is up to the caller.
+*/
+
+const TAG_CONSTANT: u8 = 0x00;
+const TAG_SYMBOL: u8 = 0x01;
+const TAG_RATIONAL: u8 = 0x02;
+const TAG_NAMED: u8 = 0x03;
+const TAG_UNARY: u8 = 0x10;
+const TAG_BINARY: u8 = 0x20;
+const TAG_KIND_MASK: u8 = 0xf0;
+const TAG_OP_MASK: u8 = 0x0f;
+
+/// Errors that can occur when reading or writing binary trees.
+#[derive(Debug)]
+pub enum BinaryError {
+    /// The underlying reader or writer failed.
+    Io(std::io::Error),
+    /// The stream does not start with the expected magic bytes.
+    BadMagic,
+    /// The stream was written by a newer version of the format.
+    UnsupportedVersion(u16),
+    /// A node has a tag that is not recognized.
+    UnknownTag(u8),
+    /// A varint is longer than 64 bits.
+    VarintOverflow,
+    /// A symbol label is not a valid unicode scalar value.
+    InvalidSymbol(u32),
+    /// A rational constant has a zero denominator.
+    InvalidRational,
+    /// A string is not valid UTF-8.
+    InvalidString,
+    /// A named constant is not defined.
+    UnknownNamedConstant(String),
+    /// A named constant is defined with a different value.
+    MismatchedNamedConstant(String),
+    /// The input of the node at `index` does not point to an earlier
+    /// node in the tree.
+    InvalidOffset { index: usize, offset: u64 },
+    /// The decoded nodes do not make a valid tree.
+    InvalidTree(TreeError),
+}
+
+impl From<std::io::Error> for BinaryError {
+    fn from(value: std::io::Error) -> Self {
+        BinaryError::Io(value)
+    }
+}
+
+fn unary_tag(op: UnaryOp) -> u8 {
+    TAG_UNARY
+        | match op {
+            Negate => 0,
+            Sqrt => 1,
+            Abs => 2,
+            Sin => 3,
+            Cos => 4,
+            Tan => 5,
+            Log => 6,
+            Exp => 7,
+        }
+}
+
+fn unary_op(tag: u8) -> Option<UnaryOp> {
+    Some(match tag & TAG_OP_MASK {
+        0 => Negate,
+        1 => Sqrt,
+        2 => Abs,
+        3 => Sin,
+        4 => Cos,
+        5 => Tan,
+        6 => Log,
+        7 => Exp,
+        _ => return None,
+    })
+}
+
+fn binary_tag(op: BinaryOp) -> u8 {
+    TAG_BINARY
+        | match op {
+            Add => 0,
+            Subtract => 1,
+            Multiply => 2,
+            Divide => 3,
+            Pow => 4,
+            Min => 5,
+            Max => 6,
+        }
+}
+
+fn binary_op(tag: u8) -> Option<BinaryOp> {
+    Some(match tag & TAG_OP_MASK {
+        0 => Add,
+        1 => Subtract,
+        2 => Multiply,
+        3 => Divide,
+        4 => Pow,
+        5 => Min,
+        6 => Max,
+        _ => return None,
+    })
+}
+
+/// Writes trees to `out` in the binary format.
+///
+/// Many small writes are issued per tree, so wrapping `out` in a
+/// `std::io::BufWriter` is recommended when writing to files or
+/// sockets.
+pub struct BinaryWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Create a new writer and write the header to `out`.
+    pub fn new(mut out: W) -> Result<BinaryWriter<W>, BinaryError> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        Ok(BinaryWriter { out, buf: vec![] })
+    }
+
+    /// Append `tree` to the stream.
+    pub fn write_tree(&mut self, tree: &Tree) -> Result<(), BinaryError> {
+        self.buf.clear();
+        write_varint(&mut self.buf, tree.len() as u64);
+        for (index, node) in tree.nodes().iter().enumerate() {
+            match node {
+                Constant(val) => {
+                    self.buf.push(TAG_CONSTANT);
+                    self.buf.extend_from_slice(&val.to_le_bytes());
+                }
+                Rational(value) => {
+                    self.buf.push(TAG_RATIONAL);
+                    let num = value.numer().to_signed_bytes_le();
+                    let (_, den) = value.denom().to_bytes_le();
+                    for bytes in [num, den] {
+                        write_varint(&mut self.buf, bytes.len() as u64);
+                        self.buf.extend_from_slice(&bytes);
+                    }
+                }
+                Named(value) => {
+                    self.buf.push(TAG_NAMED);
+                    for text in [value.name(), value.latex()] {
+                        write_varint(&mut self.buf, text.len() as u64);
+                        self.buf.extend_from_slice(text.as_bytes());
+                    }
+                    self.buf.extend_from_slice(&value.value().to_le_bytes());
+                }
+                Symbol(label) => {
+                    self.buf.push(TAG_SYMBOL);
+                    write_varint(&mut self.buf, *label as u64);
+                }
+                Unary(op, input) => {
+                    self.buf.push(unary_tag(*op));
+                    write_varint(&mut self.buf, (index - input) as u64);
+                }
+                Binary(op, lhs, rhs) => {
+                    self.buf.push(binary_tag(*op));
+                    write_varint(&mut self.buf, (index - lhs) as u64);
+                    write_varint(&mut self.buf, (index - rhs) as u64);
+                }
+            }
+            // Flush the buffer periodically so large trees don't
+            // need to be encoded entirely in memory.
+            if self.buf.len() >= 1 << 16 {
+                self.out.write_all(&self.buf)?;
+                self.buf.clear();
+            }
+        }
+        self.out.write_all(&self.buf)?;
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), BinaryError> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Consume this writer and get back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Reads trees written by a `BinaryWriter`.
+pub struct BinaryReader<R: Read> {
+    input: R,
+    version: u16,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Create a new reader, after reading and validating the header
+    /// from `input`.
+    pub fn new(mut input: R) -> Result<BinaryReader<R>, BinaryError> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(BinaryError::BadMagic);
+        }
+        let mut version = [0u8; 2];
+        input.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        Ok(BinaryReader { input, version })
+    }
+
+    /// The format version found in the header of the stream.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Read the next tree from the stream. `None` is returned if the
+    /// stream ended cleanly, i.e. right after the previous tree.
+    pub fn read_tree(&mut self) -> Result<Option<Tree>, BinaryError> {
+        let first = match self.read_first_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let count = read_varint_from(first, &mut self.input)?;
+        // Don't trust the count with the allocation, the stream may
+        // be truncated or corrupt.
+        let mut nodes = Vec::with_capacity(usize::min(count as usize, 1 << 16));
+        for index in 0..count as usize {
+            let tag = self.read_u8()?;
+            nodes.push(match tag & TAG_KIND_MASK {
+                TAG_UNARY => {
+                    let op = unary_op(tag).ok_or(BinaryError::UnknownTag(tag))?;
+                    Unary(op, self.read_offset(index)?)
+                }
+                TAG_BINARY => {
+                    let op = binary_op(tag).ok_or(BinaryError::UnknownTag(tag))?;
+                    let lhs = self.read_offset(index)?;
+                    let rhs = self.read_offset(index)?;
+                    Binary(op, lhs, rhs)
+                }
+                _ if tag == TAG_CONSTANT => {
+                    let mut bytes = [0u8; 8];
+                    self.input.read_exact(&mut bytes)?;
+                    Constant(f64::from_le_bytes(bytes))
+                }
+                _ if tag == TAG_RATIONAL => {
+                    let num = BigInt::from_signed_bytes_le(&self.read_bytes()?);
+                    let den = BigInt::from_bytes_le(Sign::Plus, &self.read_bytes()?);
+                    if den == BigInt::ZERO {
+                        return Err(BinaryError::InvalidRational);
+                    }
+                    Rational(Rational::new(BigRational::new(num, den)))
+                }
+                _ if tag == TAG_NAMED => {
+                    let name = self.read_string()?;
+                    // The LaTeX is only there for other readers.
+                    self.read_string()?;
+                    let mut bytes = [0u8; 8];
+                    self.input.read_exact(&mut bytes)?;
+                    let named = match NamedConstant::get(&name) {
+                        Some(named) => named,
+                        None => return Err(BinaryError::UnknownNamedConstant(name)),
+                    };
+                    if named.value().to_bits() != u64::from_le_bytes(bytes) {
+                        return Err(BinaryError::MismatchedNamedConstant(name));
+                    }
+                    Named(named)
+                }
+                _ if tag == TAG_SYMBOL => {
+                    let code = self.read_varint()?;
+                    let code = u32::try_from(code).map_err(|_| BinaryError::VarintOverflow)?;
+                    Symbol(char::from_u32(code).ok_or(BinaryError::InvalidSymbol(code))?)
+                }
+                _ => return Err(BinaryError::UnknownTag(tag)),
+            });
+        }
+        Tree::from_nodes(nodes)
+            .map(Some)
+            .map_err(BinaryError::InvalidTree)
+    }
+
+    /// Read a single byte, or `None` if the stream has ended.
+    fn read_first_byte(&mut self) -> Result<Option<u8>, BinaryError> {
+        let mut byte = [0u8; 1];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(BinaryError::Io(e)),
+            }
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BinaryError> {
+        let mut byte = [0u8; 1];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_varint(&mut self) -> Result<u64, BinaryError> {
+        let first = self.read_u8()?;
+        read_varint_from(first, &mut self.input)
+    }
+
+    /// Read a varint byte count, followed by that many bytes.
+    fn read_bytes(&mut self) -> Result<Vec<u8>, BinaryError> {
+        let len = self.read_varint()?;
+        // Don't trust the length with the allocation either.
+        let mut bytes = Vec::new();
+        (&mut self.input).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(BinaryError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(bytes)
+    }
+
+    /// Read a varint byte count, followed by that many bytes of UTF-8.
+    fn read_string(&mut self) -> Result<String, BinaryError> {
+        String::from_utf8(self.read_bytes()?).map_err(|_| BinaryError::InvalidString)
+    }
+
+    /// Read a relative offset and convert it to the absolute index of
+    /// the input of the node at `index`.
+    fn read_offset(&mut self, index: usize) -> Result<usize, BinaryError> {
+        let offset = self.read_varint()?;
+        if offset == 0 || offset > index as u64 {
+            return Err(BinaryError::InvalidOffset { index, offset });
+        }
+        Ok(index - offset as usize)
+    }
+}
+
+/// Write `value` as an unsigned LEB128 varint.
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read the rest of an unsigned LEB128 varint, whose first byte has
+/// already been read.
+fn read_varint_from<R: Read>(first: u8, input: &mut R) -> Result<u64, BinaryError> {
+    let mut value = (first & 0x7f) as u64;
+    let mut byte = first;
+    let mut shift = 7u32;
+    while byte & 0x80 != 0 {
+        let mut next = [0u8; 1];
+        input.read_exact(&mut next)?;
+        byte = next[0];
+        let bits = (byte & 0x7f) as u64;
+        if shift >= 64 || (shift == 63 && bits > 1) {
+            return Err(BinaryError::VarintOverflow);
+        }
+        value |= bits << shift;
+        shift += 7;
+    }
+    Ok(value)
+}
+
+impl Tree {
+    /// Encode this tree in the binary format, including the header.
+    pub fn to_binary(&self) -> Vec<u8> {
+        // Writing to a Vec cannot fail.
+        let mut writer = BinaryWriter::new(Vec::new()).unwrap();
+        writer.write_tree(self).unwrap();
+        writer.into_inner()
+    }
+
+    /// Decode a single tree from `bytes`, that were produced by
+    /// `to_binary`.
+    pub fn from_binary(bytes: &[u8]) -> Result<Tree, BinaryError> {
+        match BinaryReader::new(bytes)?.read_tree()? {
+            Some(tree) => Ok(tree),
+            None => Err(BinaryError::InvalidTree(TreeError::EmptyTree)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{dedup::Deduplicater, deftree, prune::Pruner};
+
+    #[test]
+    fn t_varint_roundtrip() {
+        let mut buf = Vec::new();
+        for value in [0u64, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+            buf.clear();
+            write_varint(&mut buf, value);
+            let mut input = &buf[1..];
+            assert_eq!(read_varint_from(buf[0], &mut input).unwrap(), value);
+            assert!(input.is_empty());
+        }
+        // 10 bytes with too many significant bits.
+        let buf = [0xffu8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert!(matches!(
+            read_varint_from(buf[0], &mut &buf[1..]),
+            Err(BinaryError::VarintOverflow)
+        ));
+    }
+
+    #[test]
+    fn t_binary_roundtrip() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let tree = deftree!(
+            (max (min
+                  (- (sqrt (+ (+ (pow (- x 2.) 2.) (pow (- y 3.) 2.)) (pow (- z 4.) 2.))) 2.75)
+                  (- (sqrt (+ (+ (pow (+ x 2.) 2.) (pow (- y 3.) 2.)) (pow (- z 4.) 2.))) 4.))
+             (- (exp (log (abs (+ (sin x) (cos (tan (/ y (* z (- x))))))))) 5.25))
+        );
+        assert_eq!(Tree::from_binary(&tree.to_binary()).unwrap(), tree);
+        // Shared subtrees must survive the roundtrip.
+        let tree = tree.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
+        assert_eq!(Tree::from_binary(&tree.to_binary()).unwrap(), tree);
+    }
+
+    #[test]
+    fn t_binary_rational() {
+        let r = |num, den| Tree::rational(Rational::from_fraction(num, den).unwrap());
+        let big = Rational::new(BigRational::new(
+            BigInt::from(3).pow(200u32),
+            -BigInt::from(7).pow(90u32),
+        ));
+        let tree = (r(1, 3) * Tree::symbol('x') + r(-128, 1)) / (Tree::rational(big) + r(0, 1));
+        assert_eq!(Tree::from_binary(&tree.to_binary()).unwrap(), tree);
+        let mut bytes = r(1, 3).to_binary();
+        // The last byte is the denominator, with a length of one.
+        let last = bytes.len() - 1;
+        bytes[last] = 0;
+        assert!(matches!(
+            Tree::from_binary(&bytes),
+            Err(BinaryError::InvalidRational)
+        ));
+    }
+
+    #[test]
+    fn t_binary_named() {
+        let tau = NamedConstant::define("t_binary_tau", "\\tau", 6.283).unwrap();
+        let tree = deftree!(+ (* (@ pi) x) {Tree::named(tau)});
+        assert_eq!(Tree::from_binary(&tree.to_binary()).unwrap(), tree);
+        // The last 8 bytes are the value, which conflicts with the
+        // existing definition when changed.
+        let mut bytes = Tree::named(tau).to_binary();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            Tree::from_binary(&bytes),
+            Err(BinaryError::MismatchedNamedConstant(name)) if name == "t_binary_tau"
+        ));
+        // Reading a constant does not define it. Rename a defined
+        // constant to one with a name of the same length.
+        let known = NamedConstant::define("t_binary_known", "k", 1.5).unwrap();
+        let mut renamed = Tree::named(known).to_binary();
+        let start = renamed
+            .windows(14)
+            .position(|w| w == b"t_binary_known")
+            .unwrap();
+        renamed[start..start + 14].copy_from_slice(b"t_binary_other");
+        assert!(matches!(
+            Tree::from_binary(&renamed),
+            Err(BinaryError::UnknownNamedConstant(name)) if name == "t_binary_other"
+        ));
+        assert!(NamedConstant::get("t_binary_other").is_none());
+    }
+
+    #[test]
+    fn t_binary_stream() {
+        let trees = [
+            deftree!(/ (+ (* k x) (* k y)) (+ x y)),
+            deftree!(2.5),
+            deftree!(sqrt (+ (pow x 2.) (pow y 2.))),
+        ];
+        let mut writer = BinaryWriter::new(Vec::new()).unwrap();
+        for tree in trees.iter() {
+            writer.write_tree(tree).unwrap();
+        }
+        let bytes = writer.into_inner();
+        let mut reader = BinaryReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.version(), FORMAT_VERSION);
+        for tree in trees.iter() {
+            assert_eq!(&reader.read_tree().unwrap().unwrap(), tree);
+        }
+        assert!(reader.read_tree().unwrap().is_none());
+    }
+
+    #[test]
+    fn t_binary_large_tree() {
+        // A long chain of nodes, each referring to the previous node
+        // and to a node far behind it.
+        let mut tree = deftree!(x);
+        for i in 0..20000 {
+            tree = if i % 2 == 0 {
+                tree + deftree!(y)
+            } else {
+                tree * Tree::constant(i as f64)
+            };
+        }
+        let bytes = tree.to_binary();
+        assert!(bytes.len() < tree.len() * 6);
+        assert_eq!(Tree::from_binary(&bytes).unwrap(), tree);
+    }
+
+    #[test]
+    fn t_binary_invalid() {
+        let bytes = deftree!(+ x (sin y)).to_binary();
+        // Bad magic.
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            Tree::from_binary(&bad),
+            Err(BinaryError::BadMagic)
+        ));
+        // Newer version.
+        let mut bad = bytes.clone();
+        bad[4] = 0xff;
+        assert!(matches!(
+            Tree::from_binary(&bad),
+            Err(BinaryError::UnsupportedVersion(_))
+        ));
+        // Truncated stream.
+        assert!(matches!(
+            Tree::from_binary(&bytes[..bytes.len() - 1]),
+            Err(BinaryError::Io(_))
+        ));
+        // Unknown tag, in place of the first symbol.
+        let mut bad = bytes.clone();
+        bad[7] = 0x3f;
+        assert!(matches!(
+            Tree::from_binary(&bad),
+            Err(BinaryError::UnknownTag(0x3f))
+        ));
+        // Input pointing at the node itself.
+        let mut bad = bytes.clone();
+        let last = bad.len() - 1;
+        bad[last] = 0;
+        assert!(matches!(
+            Tree::from_binary(&bad),
+            Err(BinaryError::InvalidOffset {
+                index: 3,
+                offset: 0
+            })
+        ));
+        // NaN constants are rejected like everywhere else.
+        let bytes = Tree::constant(f64::NAN).to_binary();
+        assert!(matches!(
+            Tree::from_binary(&bytes),
+            Err(BinaryError::InvalidTree(TreeError::ContainsNaN))
+        ));
+        // Header only, no tree.
+        assert!(matches!(
+            Tree::from_binary(&bytes[..6]),
+            Err(BinaryError::InvalidTree(TreeError::EmptyTree))
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    dedup::Deduplicater,
+    fold::fold_nodes,
+    tree::{Node, Node::*, Tree, TreeError},
+};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
+impl Tree {
+    /// Bring this tree into canonical form. Constants are folded, the
+    /// operands of commutative operations are sorted, identical
+    /// subtrees are deduplicated and unused nodes are pruned. Finally
+    /// the nodes are sorted in depth first order, so two trees that
+    /// are `equivalent` have identical nodes in canonical form.
+    ///
+    /// The operands are sorted in the order of `Node`s, with ties
+    /// broken by comparing the inputs from left to right. If folding
+    /// the constants produces an invalid tree, the appropriate
+    /// `TreeError` is returned.
+    pub fn canonicalize(mut self) -> Result<Tree, TreeError> {
+        fold_nodes(self.nodes_mut());
+        let mut nodes = self.validated()?.nodes().to_vec();
+        // After deduplication, equivalent subtrees are the same node,
+        // which `compare` relies on.
+        Deduplicater::new().run(&mut nodes);
+        for index in 0..nodes.len() {
+            match nodes[index] {
+                // Also matches negative zero, which is equivalent.
+                Constant(0.) => nodes[index] = Constant(0.),
+                Binary(op, lhs, rhs)
+                    if op.is_commutative() && compare(&nodes, lhs, rhs) == Ordering::Greater =>
+                {
+                    // The inputs are already in canonical form,
+                    // because they come before this node.
+                    nodes[index] = Binary(op, rhs, lhs);
+                }
+                Constant(_) | Rational(_) | Named(_) | Symbol(_) | Unary(..) | Binary(..) => {}
+            }
+        }
+        Tree::from_nodes(emit(&nodes, nodes.len() - 1))
+    }
+}
+
+/// Compare the subtrees rooted at `a` and `b` in canonical order. The
+/// `nodes` must be deduplicated, so different inputs are never
+/// equivalent, and only the first pair of different inputs decides
+/// the order. That means the comparison follows a single path down
+/// the trees, without recursion, no matter how many subtrees are
+/// shared.
+fn compare(nodes: &[Node], mut a: usize, mut b: usize) -> Ordering {
+    loop {
+        if a == b {
+            return Ordering::Equal;
+        }
+        // Trees can't contain NaN, so this is never None.
+        let ord = nodes[a].partial_cmp(&nodes[b]).unwrap_or(Ordering::Equal);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        (a, b) = match (nodes[a], nodes[b]) {
+            (Unary(_, x), Unary(_, y)) => (x, y),
+            (Binary(_, l1, _), Binary(_, l2, _)) if l1 != l2 => (l1, l2),
+            (Binary(_, _, r1), Binary(_, _, r2)) => (r1, r2),
+            _ => return Ordering::Equal,
+        };
+    }
+}
+
+/// Copy the subtree rooted at `root` in depth first order, sharing
+/// identical subtrees.
+fn emit(nodes: &[Node], root: usize) -> Vec<Node> {
+    let mut out = Vec::<Node>::new();
+    let mut map = vec![usize::MAX; nodes.len()];
+    let mut unique = HashMap::<(u8, u64, usize, usize), usize>::new();
+    let mut stack = vec![(root, false)];
+    while let Some((index, ready)) = stack.pop() {
+        if map[index] != usize::MAX {
+            continue;
+        }
+        if !ready {
+            stack.push((index, true));
+            // Reversed, so the inputs are emitted from left to right.
+            match nodes[index] {
+                Constant(_) | Rational(_) | Named(_) | Symbol(_) => {}
+                Unary(_, input) => stack.push((input, false)),
+                Binary(_, lhs, rhs) => {
+                    stack.push((rhs, false));
+                    stack.push((lhs, false));
+                }
+            }
+            continue;
+        }
+        let (node, key) = match nodes[index] {
+            Constant(val) => (Constant(val), (0, val.to_bits(), 0, 0)),
+            Rational(value) => (Rational(value), (4, value.id() as u64, 0, 0)),
+            Named(value) => (Named(value), (5, value.id() as u64, 0, 0)),
+            Symbol(label) => (Symbol(label), (1, label as u64, 0, 0)),
+            Unary(op, input) => {
+                let input = map[input];
+                (Unary(op, input), (2, op.index() as u64, input, 0))
+            }
+            Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (map[lhs], map[rhs]);
+                (Binary(op, lhs, rhs), (3, op.index() as u64, lhs, rhs))
+            }
+        };
+        map[index] = *unique.entry(key).or_insert_with(|| {
+            out.push(node);
+            out.len() - 1
+        });
+    }
+    out
+}
+
+/// A tree in canonical form, that can be compared and hashed, for
+/// example to use trees as keys in a `HashMap`. Two canonical trees
+/// are equal if the trees they were created from are `equivalent`
+/// after folding their constants. See `Tree::canonicalize`.
+#[derive(Debug, Clone)]
+pub struct CanonicalTree {
+    tree: Tree,
+    hash: u64,
+}
+
+impl CanonicalTree {
+    /// Canonicalize `tree`.
+    pub fn new(tree: Tree) -> Result<CanonicalTree, TreeError> {
+        let tree = tree.canonicalize()?;
+        let hash = tree.hash(&mut Vec::new());
+        Ok(CanonicalTree { tree, hash })
+    }
+
+    /// The tree in canonical form.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> Tree {
+        self.tree
+    }
+}
+
+impl PartialEq for CanonicalTree {
+    fn eq(&self, other: &Self) -> bool {
+        // Canonical trees never contain NaN, so the comparison of the
+        // nodes is reflexive.
+        self.hash == other.hash && self.tree == other.tree
+    }
+}
+
+impl Eq for CanonicalTree {}
+
+impl Hash for CanonicalTree {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deftree, tree::BinaryOp, verify::numerically_equivalent};
+    use std::collections::HashSet;
+
+    #[test]
+    fn t_canonicalize() {
+        let a = deftree!(+ (* (sin x) (+ y 1)) (min (sin y) (sin x)));
+        let b = deftree!(+ (min (sin x) (sin y)) (* (+ 1 y) (sin x)));
+        let ca = a.clone().canonicalize().unwrap();
+        assert_eq!(ca, b.clone().canonicalize().unwrap());
+        numerically_equivalent(&ca, &a, &[('x', -5., 5.), ('y', -5., 5.)], 100, 0.).unwrap();
+        // Canonical forms agree with `equivalent`.
+        assert!(a.equivalent(&b));
+        // Deduplicated and pruned.
+        assert_eq!(ca.len(), 9);
+        // Constants are folded.
+        assert_eq!(
+            deftree!(* x (+ 1 2)).canonicalize().unwrap(),
+            deftree!(* 3 x).canonicalize().unwrap()
+        );
+        assert!(matches!(
+            deftree!(+ x (sqrt (- 1))).canonicalize(),
+            Err(TreeError::ContainsNaN)
+        ));
+        assert_eq!(
+            CanonicalTree::new(deftree!(-0)).unwrap(),
+            CanonicalTree::new(deftree!(0)).unwrap()
+        );
+        // Only commutativity is taken into account.
+        assert_ne!(
+            deftree!(+ (+ x y) z).canonicalize().unwrap(),
+            deftree!(+ x (+ y z)).canonicalize().unwrap()
+        );
+        assert_ne!(
+            deftree!(- x y).canonicalize().unwrap(),
+            deftree!(- y x).canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn t_canonical_equivalent() {
+        // None of these can be folded, so canonical forms are equal
+        // exactly when the trees are equivalent.
+        let trees = [
+            deftree!(min (sin y) (sin x)),
+            deftree!(min (sin x) (sin y)),
+            deftree!(min (cos x) (sin y)),
+            deftree!(* (+ x (@ pi)) (max (exp y) (log x))),
+            deftree!(* (max (log x) (exp y)) (+ (@ pi) x)),
+            deftree!(* (max (log x) (exp y)) (+ (@ e) x)),
+            deftree!(- (+ x y) (+ y x)),
+            deftree!(- (+ y x) (+ x y)),
+        ];
+        for a in trees.iter() {
+            for b in trees.iter() {
+                let ca = CanonicalTree::new(a.clone()).unwrap();
+                let cb = CanonicalTree::new(b.clone()).unwrap();
+                assert_eq!(ca == cb, a.equivalent(b), "{} and {}", a, b);
+            }
+        }
+        // Two separate copies of a deep DAG, where every node uses
+        // the previous node twice. Comparing them without sharing
+        // would take 2^60 steps.
+        let mut nodes = Vec::new();
+        let mut roots = Vec::new();
+        for label in ['x', 'y', 'x'] {
+            nodes.push(Symbol(label));
+            for _ in 0..60 {
+                let last = nodes.len() - 1;
+                nodes.push(Binary(BinaryOp::Pow, last, last));
+            }
+            roots.push(nodes.len() - 1);
+        }
+        nodes.push(Binary(BinaryOp::Min, roots[2], roots[1]));
+        nodes.push(Binary(BinaryOp::Min, roots[1], roots[0]));
+        nodes.push(Binary(BinaryOp::Add, nodes.len() - 2, nodes.len() - 1));
+        let tree = Tree::from_nodes(nodes).unwrap();
+        let canonical = tree.clone().canonicalize().unwrap();
+        assert_eq!(canonical.len(), 124);
+        assert!(canonical.equivalent(&tree));
+    }
+
+    #[test]
+    fn t_canonical_tree_keys() {
+        let trees = [
+            deftree!(+ x y),
+            deftree!(+ y x),
+            deftree!(+ x (* 0 y)),
+            deftree!(+ (* (- 0) y) x),
+            deftree!(* (pow x 2) (exp y)),
+            deftree!(* (exp y) (pow x 2)),
+        ];
+        let set: HashSet<CanonicalTree> = trees
+            .iter()
+            .map(|t| CanonicalTree::new(t.clone()).unwrap())
+            .collect();
+        assert_eq!(set.len(), 3);
+        for tree in trees.iter() {
+            assert!(set.contains(&CanonicalTree::new(tree.clone()).unwrap()));
+        }
+        assert!(!set.contains(&CanonicalTree::new(deftree!(- x y)).unwrap()));
+    }
+}
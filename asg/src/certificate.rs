@@ -0,0 +1,436 @@
+use crate::{
+    facts::{Condition, Facts},
+    mutate::{MutationError, TemplateCapture},
+    parse::{parse_shared_tree, ParseError},
+    reduce::Step,
+    template::RuleSet,
+    tree::Tree,
+};
+use std::path::Path;
+
+/*
+A certificate records how a tree was simplified, so the
+simplification can be checked later, possibly with a different rule
+set. It is stored as text, one entry per line:
+
+    # Comments start with a hash.
+    start: k x (* $0 $1) k y (* $3 $4) (+ $2 $5) x y (+ $7 $8) (/ $6 $9)
+    assume: x positive
+    assume: y positive
+    step: distribute_mul 5 a=1 b=2 k=0
+    step: rearrange_mul_div_1 5 x=2 y=3 z=3
+    step: divide_by_self 4 a=2
+    result: k
+
+Each step names the template that was applied, the index of the node
+it was applied to, and the nodes the symbols of the template were
+bound to, in the tree produced by the previous step. The bindings tell
+apart the different ways the operands of a sum or a product can be
+grouped to match the same node. They are optional, and without them
+the first grouping is used. The assumptions are the facts that were
+used to check the guards of the templates.
+
+The trees are written one node at a time, in the notation of rule
+files, with `$i` referring to the node at index `i`. Shared subtrees
+are written once, so the certificate of a deduplicated tree stays
+small. A single expression in the usual notation, such as `(/ (+ (* k
+x) (* k y)) (+ x y))`, is accepted too.
+*/
+
+/// Errors that can occur when reading or checking a certificate.
+#[derive(Debug)]
+pub enum CertificateError {
+    /// The certificate could not be parsed.
+    Parse(ParseError),
+    /// The template applied in a step (starting from 0) is not in
+    /// the rule set.
+    UnknownRule { step: usize, rule: String },
+    /// The template of a step does not match at the recorded node
+    /// with the recorded bindings, or its guards are not satisfied.
+    IllegalStep { step: usize, rule: String },
+    /// A template could not be applied.
+    Mutation(MutationError),
+    /// Replaying the steps did not produce the recorded result.
+    ResultMismatch,
+}
+
+impl From<ParseError> for CertificateError {
+    fn from(error: ParseError) -> Self {
+        CertificateError::Parse(error)
+    }
+}
+
+impl From<MutationError> for CertificateError {
+    fn from(error: MutationError) -> Self {
+        CertificateError::Mutation(error)
+    }
+}
+
+/// A step of a certificate. Like `reduce::Step`, without the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertifiedStep {
+    /// Name of the template that was applied.
+    pub rule: String,
+    /// Index of the node in the previous tree that matched the ping
+    /// of the template.
+    pub node_index: usize,
+    /// Symbols of the template, sorted by label, and the indices of
+    /// the nodes in the previous tree they were bound to. Empty if
+    /// the bindings were not recorded.
+    pub bindings: Vec<(char, usize)>,
+}
+
+/// A replayable record of a simplification.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    start: Tree,
+    facts: Facts,
+    steps: Vec<CertifiedStep>,
+    result: Tree,
+}
+
+impl Certificate {
+    /// Create a certificate for simplifying `start` into the last of
+    /// `steps`, with the guards of the templates checked against
+    /// `facts`. These are the same arguments passed to, and the steps
+    /// returned from, `reduce::reduce_with_facts`.
+    pub fn new(start: Tree, facts: &Facts, steps: &[Step]) -> Certificate {
+        let result = match steps.last() {
+            Some(step) => step.tree.clone(),
+            None => start.clone(),
+        };
+        Certificate {
+            start,
+            facts: facts.clone(),
+            steps: steps
+                .iter()
+                .map(|s| {
+                    let mut bindings = s.bindings.clone();
+                    bindings.sort();
+                    CertifiedStep {
+                        rule: s.rule.clone(),
+                        node_index: s.node_index,
+                        bindings,
+                    }
+                })
+                .collect(),
+            result,
+        }
+    }
+
+    pub fn start(&self) -> &Tree {
+        &self.start
+    }
+
+    pub fn facts(&self) -> &Facts {
+        &self.facts
+    }
+
+    pub fn result(&self) -> &Tree {
+        &self.result
+    }
+
+    pub fn steps(&self) -> &[CertifiedStep] {
+        &self.steps
+    }
+
+    /// Write the certificate as text. See the top of this module for
+    /// a description of the format.
+    pub fn to_text(&self) -> String {
+        let mut out = format!("start: {}\n", self.start.to_shared_lisp());
+        for (label, condition) in self.facts.known() {
+            out += &format!("assume: {} {}\n", label, condition.name());
+        }
+        for step in self.steps.iter() {
+            out += &format!("step: {} {}", step.rule, step.node_index);
+            for (label, i) in step.bindings.iter() {
+                out += &format!(" {}={}", label, i);
+            }
+            out += "\n";
+        }
+        out += &format!("result: {}\n", self.result.to_shared_lisp());
+        out
+    }
+
+    /// Parse a certificate from `text`.
+    pub fn from_text(text: &str) -> Result<Certificate, CertificateError> {
+        let mut start = None;
+        let mut result = None;
+        let mut facts = Facts::new();
+        let mut steps = Vec::new();
+        for (offset, line) in text.lines().enumerate() {
+            let lineno = offset + 1;
+            let syntax = |message: &str| ParseError::Syntax {
+                line: lineno,
+                message: message.to_string(),
+            };
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| syntax("Expected 'key: value'."))?;
+            let mut words = value.split_whitespace();
+            match key.trim() {
+                "start" => start = Some(parse_shared_tree(value, lineno)?),
+                "result" => result = Some(parse_shared_tree(value, lineno)?),
+                "assume" => match (words.next(), words.next(), words.next()) {
+                    (Some(label), Some(condition), None) => {
+                        let mut chars = label.chars();
+                        let label = match (chars.next(), chars.next()) {
+                            (Some(c), None) if c.is_alphabetic() => c,
+                            _ => return Err(syntax("Invalid symbol.").into()),
+                        };
+                        let condition = Condition::from_name(condition)
+                            .ok_or_else(|| syntax("Unknown condition."))?;
+                        facts.assume(label, condition);
+                    }
+                    _ => return Err(syntax("Expected 'assume: symbol condition'.").into()),
+                },
+                "step" => match (words.next(), words.next()) {
+                    (Some(rule), Some(index)) => {
+                        let index = index
+                            .parse::<usize>()
+                            .map_err(|_| syntax("Invalid node index."))?;
+                        let mut bindings = Vec::new();
+                        for word in words {
+                            let binding = word.split_once('=').and_then(|(label, i)| {
+                                let mut chars = label.chars();
+                                match (chars.next(), chars.next(), i.parse::<usize>()) {
+                                    (Some(c), None, Ok(i)) if c.is_alphabetic() => Some((c, i)),
+                                    _ => None,
+                                }
+                            });
+                            bindings.push(binding.ok_or_else(|| syntax("Invalid binding."))?);
+                        }
+                        bindings.sort();
+                        steps.push(CertifiedStep {
+                            rule: rule.to_string(),
+                            node_index: index,
+                            bindings,
+                        });
+                    }
+                    _ => return Err(syntax("Expected 'step: rule index bindings'.").into()),
+                },
+                _ => return Err(syntax("Unknown key.").into()),
+            }
+        }
+        let last_line = text.lines().count();
+        let missing = |what: &str| ParseError::Syntax {
+            line: last_line,
+            message: format!("Missing '{}'.", what),
+        };
+        Ok(Certificate {
+            start: start.ok_or_else(|| missing("start"))?,
+            facts,
+            steps,
+            result: result.ok_or_else(|| missing("result"))?,
+        })
+    }
+
+    /// Write this certificate to the file at `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    /// Read a certificate from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Certificate, CertificateError> {
+        let text = std::fs::read_to_string(path).map_err(ParseError::Io)?;
+        Certificate::from_text(&text)
+    }
+
+    /// Replay the simplification using the templates in `rules`, and
+    /// check that every step is a legal application of a template,
+    /// and that the recorded result is reached. The replayed steps
+    /// are returned.
+    pub fn check(&self, rules: &RuleSet) -> Result<Vec<Step>, CertificateError> {
+        let mut capture = TemplateCapture::with_facts(self.facts.clone());
+        let mut tree = capture.make_compact_tree(self.start.clone(), None)?;
+        let mut steps = Vec::with_capacity(self.steps.len());
+        for (i, step) in self.steps.iter().enumerate() {
+            let (rule, index) = (&step.rule, &step.node_index);
+            let template = rules
+                .get(rule)
+                .ok_or_else(|| CertificateError::UnknownRule {
+                    step: i,
+                    rule: rule.clone(),
+                })?;
+            let same = |bindings: &[(char, usize)]| {
+                let mut bindings = bindings.to_vec();
+                bindings.sort();
+                step.bindings.is_empty() || bindings == step.bindings
+            };
+            let mut found = capture.match_at(template, &tree, *index);
+            while found && !same(capture.bindings()) {
+                found = capture.next_grouping(template, &tree);
+            }
+            if !found {
+                return Err(CertificateError::IllegalStep {
+                    step: i,
+                    rule: rule.clone(),
+                });
+            }
+            let bindings = capture.bindings().clone();
+            tree = capture.apply(template, &tree)?;
+            steps.push(Step {
+                tree: tree.clone(),
+                rule: rule.clone(),
+                node_index: *index,
+                bindings,
+            });
+        }
+        if !tree.equivalent(&self.result) {
+            return Err(CertificateError::ResultMismatch);
+        }
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        deftree, facts::Condition::Positive, reduce::reduce_with_facts,
+        template::test::get_template_by_name,
+    };
+
+    fn make_certificate() -> Certificate {
+        let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
+        let facts = Facts::new().with('x', Positive).with('y', Positive);
+        let steps = reduce_with_facts(tree.clone(), &RuleSet::new(), facts.clone(), 10).unwrap();
+        Certificate::new(tree, &facts, &steps)
+    }
+
+    #[test]
+    fn t_certificate_roundtrip() {
+        let cert = make_certificate();
+        assert!(cert.result().equivalent(&deftree!(k)));
+        let text = cert.to_text();
+        let parsed = Certificate::from_text(&text).unwrap();
+        assert_eq!(parsed.to_text(), text);
+        assert!(parsed.start().equivalent(cert.start()));
+        assert_eq!(parsed.steps(), cert.steps());
+        assert_eq!(parsed.facts().known(), cert.facts().known());
+        let path = std::env::temp_dir().join("asg_t_certificate_roundtrip.txt");
+        cert.save(&path).unwrap();
+        let loaded = Certificate::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.to_text(), text);
+    }
+
+    #[test]
+    fn t_certificate_large() {
+        use crate::tree::{BinaryOp::Add, Node::*, UnaryOp::Negate};
+        // Deep trees.
+        let mut nodes = vec![Symbol('x')];
+        nodes.extend((0..100_000).map(|i| Unary(Negate, i)));
+        let tree = Tree::from_nodes(nodes).unwrap();
+        let cert = Certificate::new(tree.clone(), &Facts::new(), &[]);
+        let parsed = Certificate::from_text(&cert.to_text()).unwrap();
+        assert_eq!(parsed.start(), &tree);
+        // Shared subtrees are written once. Written out in full, this
+        // tree would have 2^60 leaves.
+        let mut nodes = vec![Symbol('x')];
+        nodes.extend((0..60).map(|i| Binary(Add, i, i)));
+        let tree = Tree::from_nodes(nodes).unwrap();
+        let text = Certificate::new(tree.clone(), &Facts::new(), &[]).to_text();
+        assert!(text.len() < 2000);
+        assert_eq!(Certificate::from_text(&text).unwrap().result(), &tree);
+    }
+
+    #[test]
+    fn t_certificate_check() {
+        let cert = make_certificate();
+        let steps = cert.check(&RuleSet::new()).unwrap();
+        assert_eq!(steps.len(), cert.steps().len());
+        assert!(steps.last().unwrap().tree.equivalent(&deftree!(k)));
+        // Missing templates.
+        let mut rules = RuleSet::new();
+        rules.remove("divide_by_self").unwrap();
+        assert!(matches!(
+            cert.check(&rules),
+            Err(CertificateError::UnknownRule { rule, .. }) if rule == "divide_by_self"
+        ));
+        // Without the assumptions, x + y could be zero.
+        let text = cert
+            .to_text()
+            .lines()
+            .filter(|line| !line.starts_with("assume"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(matches!(
+            Certificate::from_text(&text).unwrap().check(&RuleSet::new()),
+            Err(CertificateError::IllegalStep { rule, .. }) if rule == "divide_by_self"
+        ));
+        // Tampered result.
+        let text = cert.to_text().replace("result: k", "result: x");
+        assert!(matches!(
+            Certificate::from_text(&text)
+                .unwrap()
+                .check(&RuleSet::new()),
+            Err(CertificateError::ResultMismatch)
+        ));
+        // Wrong location.
+        let step = &cert.steps()[0];
+        let text = cert.to_text().replace(
+            &format!("step: {} {} ", step.rule, step.node_index),
+            &format!("step: {} {} ", step.rule, step.node_index + 1),
+        );
+        assert!(matches!(
+            Certificate::from_text(&text)
+                .unwrap()
+                .check(&RuleSet::new()),
+            Err(CertificateError::IllegalStep { step: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn t_certificate_regrouped() {
+        // The same node can be matched by several groupings of the
+        // operands of the sum, and the step has to replay the one
+        // that was recorded.
+        let mut rules = RuleSet::empty();
+        rules
+            .add_oneway(get_template_by_name("distribute_mul").unwrap().clone())
+            .unwrap();
+        let tree = deftree!(+ (+ (* k a) (* j 2)) (+ (* k b) (* j 3)));
+        let facts = Facts::new();
+        let steps = reduce_with_facts(tree.clone(), &rules, facts.clone(), 10).unwrap();
+        let cert = Certificate::new(tree, &facts, &steps);
+        assert!(cert.steps().len() > 1);
+        assert_eq!(cert.check(&rules).unwrap().len(), steps.len());
+        let parsed = Certificate::from_text(&cert.to_text()).unwrap();
+        assert_eq!(parsed.check(&rules).unwrap().len(), steps.len());
+        // Steps without bindings use the first grouping.
+        let text =
+            "start: (+ (* k a) (+ (* k b) c))\nstep: distribute_mul 7\nresult: (+ (* k (+ a b)) c)";
+        let parsed = Certificate::from_text(text).unwrap();
+        assert!(parsed.steps()[0].bindings.is_empty());
+        assert_eq!(parsed.check(&rules).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn t_certificate_errors() {
+        let check = |text: &str, line: usize| match Certificate::from_text(text) {
+            Err(CertificateError::Parse(ParseError::Syntax { line: l, .. })) => {
+                assert_eq!(l, line)
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|c| c.to_text())),
+        };
+        check("start: x\nresult x", 2);
+        check("start: x\nassume: x big\nresult: x", 2);
+        check("start: x\nassume: xy positive\nresult: x", 2);
+        check("start: x\nstep: rule\nresult: x", 2);
+        check("start: x\nstep: rule -1\nresult: x", 2);
+        check("start: x\nstep: rule 1 x\nresult: x", 2);
+        check("start: x\nstep: rule 1 xy=2\nresult: x", 2);
+        check("start: x\nfoo: bar\nresult: x", 2);
+        check("start: x\n# The result is missing.", 2);
+        check("start: (+ x\nresult: x", 1);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::tree::{BinaryOp::*, Node, Node::*, Tree, UnaryOp::*};
+
+/// Assigns costs to trees, so simplifiers can decide which of two
+/// equivalent trees is better. Lower costs are better.
+pub trait CostFunction {
+    /// Cost of the whole `tree`.
+    fn cost(&mut self, tree: &Tree) -> usize;
+
+    /// Cost of a tree with `node` as its root, given the costs of the
+    /// subtrees that are its inputs. This is used by simplifiers that
+    /// build trees one node at a time, such as `reduce::saturate`. By
+    /// default this is the number of nodes in the tree.
+    fn node_cost(&mut self, _node: &Node, input_costs: &[usize]) -> usize {
+        1 + input_costs.iter().sum::<usize>()
+    }
+}
+
+/// Cost of a tree is the number of nodes in it.
+#[derive(Default, Clone)]
+pub struct NodeCount;
+
+impl CostFunction for NodeCount {
+    fn cost(&mut self, tree: &Tree) -> usize {
+        tree.len()
+    }
+}
+
+/// Cost of a tree is its depth, i.e. the number of nodes along the
+/// longest path from the root to a leaf. Shallow trees have shorter
+/// dependency chains, and evaluate faster on parallel hardware.
+#[derive(Default, Clone)]
+pub struct Depth {
+    depths: Vec<usize>,
+}
+
+impl CostFunction for Depth {
+    fn cost(&mut self, tree: &Tree) -> usize {
+        // Nodes are topologically sorted, so the inputs are always
+        // visited before the nodes that use them.
+        self.depths.clear();
+        for node in tree.nodes() {
+            let depth = match node {
+                Constant(_) | Rational(_) | Named(_) | Symbol(_) => 1,
+                Unary(_, input) => 1 + self.depths[*input],
+                Binary(_, lhs, rhs) => 1 + usize::max(self.depths[*lhs], self.depths[*rhs]),
+            };
+            self.depths.push(depth);
+        }
+        self.depths[tree.root_index()]
+    }
+
+    fn node_cost(&mut self, _node: &Node, input_costs: &[usize]) -> usize {
+        1 + input_costs.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// Cost of a tree is a rough estimate of the number of floating
+/// point operations required to evaluate it. Operations like `pow`,
+/// `exp` and the trigonometric functions are much more expensive than
+/// additions and multiplications. Subtrees that are shared by more
+/// than one node are only counted once, because their values are
+/// only computed once.
+#[derive(Default, Clone)]
+pub struct Flops;
+
+impl Flops {
+    /// Relative cost of computing `node`, not including its inputs.
+    pub fn weight(node: &Node) -> usize {
+        match node {
+            Constant(_) | Rational(_) | Named(_) | Symbol(_) => 0,
+            Unary(op, _) => match op {
+                Negate | Abs => 1,
+                Sqrt => 4,
+                Log | Exp => 20,
+                Sin | Cos | Tan => 20,
+            },
+            Binary(op, _, _) => match op {
+                Add | Subtract | Multiply | Min | Max => 1,
+                Divide => 4,
+                Pow => 40,
+            },
+        }
+    }
+}
+
+impl CostFunction for Flops {
+    fn cost(&mut self, tree: &Tree) -> usize {
+        tree.nodes().iter().map(Flops::weight).sum()
+    }
+
+    fn node_cost(&mut self, node: &Node, input_costs: &[usize]) -> usize {
+        Flops::weight(node) + input_costs.iter().sum::<usize>()
+    }
+}
+
+/// The cost function `reduce` uses by default. The cost of a tree is
+/// the number of nodes, plus the cost of the diamond shaped loops in
+/// the tree, which is explained below.
+#[derive(Clone)]
+pub struct Heuristic {
+    stack: Vec<(usize, usize)>, // index, depth
+    last_visit: Vec<Option<usize>>,
+}
+
+/// If two nodes have the same node as their one of their inputs, it
+/// creates a diamond shaped loop. If this loop is small, i.e. if the
+/// lowest common ancestor of the two nodes is near by, It is more
+/// likely that a known template will match with the tree and be able
+/// to simplify it. The euler-walk heuristic penalizes diamond shaped
+/// loops based on their size, i.e. larger loops have a higher cost
+/// than smaller loops. While the heuristic tries to add up the
+/// lengths of all diamond shaped loops, the value might not always be
+/// exact, depending on the traversal order. But the value is
+/// guaranteed to have a positive correlation with the number of
+/// diamond shaped loops and their sizes.
+impl Heuristic {
+    pub fn new() -> Heuristic {
+        Heuristic {
+            stack: Vec::new(),
+            last_visit: Vec::new(),
+        }
+    }
+
+    /// In a typical depth first traversal, you just push the children
+    /// of the current node onto the stack. Instead, if you also push
+    /// the node itself, before every child, it results in an euler
+    /// walk. This is very useful because for any pair of nodes 'a'
+    /// and 'b', euler-walk necessarily contains a subpath that starts
+    /// at 'a' and ends at 'b' or starts at 'b' and ends at
+    /// 'a'. Furthermore, this subpath necessarily goes through the
+    /// lowest common ancestor of 'a' and 'b'. That means, if we're
+    /// visiting a node for the second (or more) time from a parent,
+    /// we've detected a diamond shaped loop, and the number of nodes
+    /// traversed since the last visit roughly correlates to the size
+    /// of the diamond shaped loop.
+    fn euler_walk_cost(&mut self, nodes: &[Node], root: usize) -> usize {
+        // Reset all buffers.
+        self.stack.clear();
+        self.stack.reserve(nodes.len());
+        self.last_visit.clear();
+        self.last_visit.resize(nodes.len(), None);
+        // Start the Euler walk.
+        self.stack.push((root, 0));
+        let mut prevdepth: usize = 0;
+        let mut counter: usize = 0;
+        let mut sum: usize = 0;
+        while let Some((i, depth)) = self.stack.pop() {
+            match self.last_visit[i] {
+                // Accumulate the size of the diamond shaped loop.
+                Some(last) if prevdepth < depth => sum += counter - last,
+                // Push children if visiting for the first time.
+                None => match &nodes[i] {
+                    Constant(_) | Rational(_) | Named(_) | Symbol(_) => {} // No children to push.
+                    Unary(_, input) => self
+                        .stack
+                        .extend_from_slice(&[(i, depth), (*input, depth + 1)]),
+                    Binary(_, lhs, rhs) => self.stack.extend_from_slice(&[
+                        (i, depth),
+                        (*rhs, depth + 1),
+                        (i, depth),
+                        (*lhs, depth + 1),
+                    ]),
+                },
+                _ => {} // Do nothing.
+            }
+            // Record visit, update counter and depth.
+            self.last_visit[i] = Some(counter);
+            counter += 1;
+            prevdepth = depth;
+        }
+        return sum;
+    }
+}
+
+impl Default for Heuristic {
+    fn default() -> Self {
+        Heuristic::new()
+    }
+}
+
+impl CostFunction for Heuristic {
+    fn cost(&mut self, tree: &Tree) -> usize {
+        tree.len() + self.euler_walk_cost(tree.nodes(), tree.root_index())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{dedup::Deduplicater, deftree, prune::Pruner};
+
+    #[test]
+    fn t_euler_walk_depth_1() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let mut h = Heuristic::new();
+        let tree = deftree!(+ x x)
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 2);
+    }
+
+    #[test]
+    fn t_euler_walk_depth_2() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let mut h = Heuristic::new();
+        let tree = deftree!(+ (* 2 x) (* 3 x))
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 6);
+    }
+
+    #[test]
+    fn t_euler_walk_multiple() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let mut h = Heuristic::new();
+        let tree = deftree!(+ (+ (* 2 x) (* 3 x)) (* 4 x))
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 13);
+        // Make sure the same heuristic instance can be reused on other trees.
+        let tree = deftree!(+ (+ (* 2 x) (* 3 x)) (+ (* 4 x) 2))
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 33);
+    }
+
+    #[test]
+    fn t_euler_walk_non_leaf() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let mut h = Heuristic::new();
+        let tree = deftree!(+ (* 2 (+ x y)) (* (+ x y) 3))
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 4);
+    }
+
+    #[test]
+    fn t_cost_functions() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let tree = deftree!(+ (* (sin x) (sin x)) (/ 1 (pow x 2)))
+            .deduplicate(&mut dedup)
+            .unwrap()
+            .prune(&mut pruner);
+        assert_eq!(NodeCount.cost(&tree), 8);
+        assert_eq!(Depth::default().cost(&tree), 4);
+        // sin is only computed once.
+        assert_eq!(Flops.cost(&tree), 1 + 20 + 1 + 4 + 40);
+        assert_eq!(
+            Heuristic::new().cost(&tree),
+            8 + Heuristic::new().euler_walk_cost(tree.nodes(), tree.root_index())
+        );
+        // Costs of single nodes.
+        let node = Binary(Add, 0, 1);
+        assert_eq!(NodeCount.node_cost(&node, &[2, 3]), 6);
+        assert_eq!(Depth::default().node_cost(&node, &[2, 3]), 4);
+        assert_eq!(Flops.node_cost(&node, &[2, 3]), 6);
+    }
+}
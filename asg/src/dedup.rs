@@ -1,10 +1,34 @@
 use crate::{
-    hash::hash_nodes,
     tree::{Node, Node::*, Tree, TreeError},
-    walk::{DepthWalker, NodeOrdering},
+    walk::DepthWalker,
 };
 use std::collections::HashMap;
 
+/// Key identifying a node, with its inputs replaced by the classes of
+/// equivalent subtrees they belong to. The classes of the inputs of
+/// commutative operations are sorted, so mirrored nodes have the same
+/// key.
+type ClassKey = (u8, u64, usize, usize);
+
+fn class_key(node: Node, class: impl Fn(usize) -> usize) -> ClassKey {
+    match node {
+        // Zero and negative zero are equal.
+        Constant(0.) => (0, 0, 0, 0),
+        Constant(val) => (0, val.to_bits(), 0, 0),
+        Rational(val) => (1, val.id() as u64, 0, 0),
+        Named(val) => (2, val.id() as u64, 0, 0),
+        Symbol(label) => (3, label as u64, 0, 0),
+        Unary(op, input) => (4, op.index() as u64, class(input), 0),
+        Binary(op, lhs, rhs) => {
+            let (lhs, rhs) = (class(lhs), class(rhs));
+            match op.is_commutative() && lhs > rhs {
+                true => (5, op.index() as u64, rhs, lhs),
+                false => (5, op.index() as u64, lhs, rhs),
+            }
+        }
+    }
+}
+
 /// Helper struct for deduplicating common subtrees.
 ///
 /// Deduplication requires allocations. Those buffers are owned by
@@ -12,10 +36,7 @@ use std::collections::HashMap;
 /// avoid unnecessary allocations.
 pub struct Deduplicater {
     indices: Vec<usize>,
-    hashes: Vec<u64>,
-    walker1: DepthWalker,
-    walker2: DepthWalker,
-    hash_to_index: HashMap<u64, usize>,
+    classes: HashMap<ClassKey, usize>,
 }
 
 impl Deduplicater {
@@ -23,10 +44,7 @@ impl Deduplicater {
     pub fn new() -> Self {
         Deduplicater {
             indices: vec![],
-            hashes: vec![],
-            walker1: DepthWalker::new(),
-            walker2: DepthWalker::new(),
-            hash_to_index: HashMap::new(),
+            classes: HashMap::new(),
         }
     }
 
@@ -39,34 +57,24 @@ impl Deduplicater {
     /// as its input will be rewired to the first subtree. That means,
     /// after deduplication, there can be `dead` nodes remaining, that
     /// are not connected to the root. Consider pruning the tree
-    /// afterwards.
-    pub fn run(&mut self, nodes: &mut Vec<Node>) {
-        // Compute unique indices after deduplication.
+    /// afterwards. Subtrees are deduplicated if they are `equivalent`.
+    pub fn run(&mut self, nodes: &mut [Node]) {
+        // Compute unique indices after deduplication. Each node is
+        // replaced with the first node in its class. The inputs come
+        // before the node, so their indices are already known.
         self.indices.clear();
-        self.indices.extend(0..nodes.len());
-        hash_nodes(&nodes, &mut self.hashes);
-        self.hash_to_index.clear();
-        for i in 0..self.hashes.len() {
-            let h = self.hashes[i];
-            let entry = self.hash_to_index.entry(h).or_insert(i);
-            if *entry != i
-                && equivalent(
-                    *entry,
-                    i,
-                    &nodes,
-                    &nodes,
-                    &mut self.walker1,
-                    &mut self.walker2,
-                )
-            {
-                // The i-th node should be replaced with entry-th node.
-                self.indices[i] = *entry;
-            }
+        self.classes.clear();
+        for (i, node) in nodes.iter().enumerate() {
+            let key = class_key(*node, |input| self.indices[input]);
+            let first = *self.classes.entry(key).or_insert(i);
+            self.indices.push(first);
         }
         // Update nodes.
         for node in nodes.iter_mut() {
             match node {
                 Constant(_) => {}
+                Rational(_) => {}
+                Named(_) => {}
                 Symbol(_) => {}
                 Unary(_, input) => {
                     *input = self.indices[*input];
@@ -80,6 +88,32 @@ impl Deduplicater {
     }
 }
 
+/// Assign a class to every node in the subtree rooted at `root`, so
+/// that two nodes are in the same class if and only if they are
+/// equivalent. The classes are looked up in and added to `classes`,
+/// so the subtrees of several trees can be classified together. The
+/// class of `root` is returned.
+fn classify(
+    root: usize,
+    nodes: &[Node],
+    walker: &mut DepthWalker,
+    classes: &mut HashMap<ClassKey, usize>,
+) -> usize {
+    let mut indices: Vec<usize> = walker
+        .walk_nodes(nodes, root, true)
+        .map(|(index, _parent)| index)
+        .collect();
+    // Topologically sorted, so the inputs are classified first.
+    indices.sort_unstable();
+    let mut found = vec![usize::MAX; root + 1];
+    for index in indices {
+        let key = class_key(nodes[index], |input| found[input]);
+        let next = classes.len();
+        found[index] = *classes.entry(key).or_insert(next);
+    }
+    found[root]
+}
+
 /// Check if the nodes at indices `left` and `right` are
 /// equivalent.
 ///
@@ -89,11 +123,12 @@ impl Deduplicater {
 /// holding the same value are equivalent. Two nodes of the same type
 /// with equivalent inputs are considered equivalent. For binary nodes
 /// with commutative operations, checking the equivalence of the
-/// inputs is done in an order agnostic way.
+/// inputs is done in an order agnostic way, at every depth.
 ///
-/// This implementation avoids recursion by using `walker1` and
-/// `walker2` are used to traverse the tree depth wise and perform the
-/// comparison.
+/// This implementation avoids recursion, by using `lwalker` and
+/// `rwalker` to find the nodes of both subtrees, and assigning them
+/// to classes of equivalent nodes, inputs first. Shared subtrees are
+/// only classified once.
 pub fn equivalent(
     left: usize,
     right: usize,
@@ -102,39 +137,11 @@ pub fn equivalent(
     lwalker: &mut DepthWalker,
     rwalker: &mut DepthWalker,
 ) -> bool {
-    {
-        // Zip the depth first iterators and compare.
-        let mut liter = lwalker.walk_nodes(&lnodes, left, false, NodeOrdering::Deterministic);
-        let mut riter = rwalker.walk_nodes(&rnodes, right, false, NodeOrdering::Deterministic);
-        loop {
-            match (liter.next(), riter.next()) {
-                (None, None) => {
-                    // Both iterators ended.
-                    return true;
-                }
-                (None, Some(_)) | (Some(_), None) => {
-                    // One of the iterators ended prematurely.
-                    return false;
-                }
-                (Some((li, _lp)), Some((ri, _rp))) => {
-                    if std::ptr::eq(lnodes, rnodes) && li == ri {
-                        liter.skip_children();
-                        riter.skip_children();
-                        continue;
-                    }
-                    if !match (lnodes[li], rnodes[ri]) {
-                        (Constant(v1), Constant(v2)) => v1 == v2,
-                        (Symbol(c1), Symbol(c2)) => c1 == c2,
-                        (Unary(op1, _input1), Unary(op2, _input2)) => op1 == op2,
-                        (Binary(op1, _lhs1, _rhs1), Binary(op2, _lhs2, _rhs2)) => op1 == op2,
-                        _ => false,
-                    } {
-                        return false;
-                    }
-                }
-            }
-        }
+    if std::ptr::eq(lnodes, rnodes) && left == right {
+        return true;
     }
+    let mut classes = HashMap::new();
+    classify(left, lnodes, lwalker, &mut classes) == classify(right, rnodes, rwalker, &mut classes)
 }
 
 impl Tree {
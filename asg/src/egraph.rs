@@ -0,0 +1,566 @@
+use crate::{
+    cost::CostFunction,
+    facts::{node_props, node_value, Facts, Props},
+    template::{RuleSet, Template},
+    tree::{Node, Node::*, Tree, TreeError},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/*
+An e-graph stores many equivalent trees at once. Nodes are grouped
+into equivalence classes, and the inputs of a node refer to classes,
+not to other nodes. Applying a template adds the pong to the graph
+and merges its class with the class of the matched subtree, instead
+of producing a new tree. Nothing is ever removed, so the order in
+which templates are applied doesn't matter. The graph is saturated
+when applying the templates doesn't change it anymore. Then the
+cheapest tree is extracted from the class of the root.
+
+Nodes in the graph are plain `Node`s, whose inputs are the ids of
+classes instead of indices of other nodes.
+*/
+
+/// Limits on the size of the e-graph and the time spent saturating
+/// it. Saturation stops when any of the limits is reached.
+#[derive(Debug, Clone)]
+pub struct SaturationLimits {
+    /// Maximum number of times all the templates are applied.
+    pub max_iter: usize,
+    /// Maximum number of nodes in the e-graph.
+    pub max_nodes: usize,
+    /// Maximum time spent saturating the e-graph.
+    pub timeout: Duration,
+}
+
+impl Default for SaturationLimits {
+    fn default() -> Self {
+        SaturationLimits {
+            max_iter: 16,
+            max_nodes: 10_000,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Wrapper for using nodes as keys in hash maps. Constants are
+/// compared by their bits.
+#[derive(Copy, Clone)]
+struct Key(Node);
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0, other.0) {
+            (Constant(a), Constant(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Constant(val) => (0u8, val.to_bits()).hash(state),
+            Rational(val) => (4u8, val).hash(state),
+            Named(val) => (5u8, val).hash(state),
+            Symbol(label) => (1u8, label).hash(state),
+            Unary(op, input) => (2u8, op, input).hash(state),
+            Binary(op, lhs, rhs) => (3u8, op, lhs, rhs).hash(state),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EClass {
+    nodes: Vec<Node>,
+    /// Value of the class, if it is a constant.
+    value: Option<f64>,
+    /// Properties that are known to hold for the class.
+    props: Props,
+}
+
+pub(crate) struct EGraph {
+    /// Union-find of class ids. Merged classes point to the class
+    /// they were merged into.
+    parents: Vec<usize>,
+    /// Classes indexed by their ids. Classes that were merged into
+    /// other classes are empty.
+    classes: Vec<EClass>,
+    /// Canonical nodes and the classes they belong to.
+    memo: HashMap<Key, usize>,
+    facts: Facts,
+}
+
+impl EGraph {
+    pub fn new(facts: Facts) -> EGraph {
+        EGraph {
+            parents: vec![],
+            classes: vec![],
+            memo: HashMap::new(),
+            facts,
+        }
+    }
+
+    /// Number of nodes in the graph.
+    pub fn len(&self) -> usize {
+        self.memo.len()
+    }
+
+    /// Find the canonical id of the class `id`.
+    pub fn find(&self, mut id: usize) -> usize {
+        while self.parents[id] != id {
+            id = self.parents[id];
+        }
+        id
+    }
+
+    fn canonical(&self, node: Node) -> Node {
+        match node {
+            Constant(_) | Rational(_) | Named(_) | Symbol(_) => node,
+            Unary(op, input) => Unary(op, self.find(input)),
+            Binary(op, lhs, rhs) => Binary(op, self.find(lhs), self.find(rhs)),
+        }
+    }
+
+    /// Add `node` to the graph, and return the id of its class.
+    pub fn add(&mut self, node: Node) -> usize {
+        let node = self.canonical(node);
+        if let Some(id) = self.memo.get(&Key(node)) {
+            return self.find(*id);
+        }
+        let id = self.classes.len();
+        self.parents.push(id);
+        self.classes.push(EClass {
+            nodes: vec![node],
+            ..Default::default()
+        });
+        self.memo.insert(Key(node), id);
+        id
+    }
+
+    /// Add all nodes of `tree` to the graph, and return the id of
+    /// the class of its root.
+    pub fn add_tree(&mut self, tree: &Tree) -> usize {
+        let mut ids: Vec<usize> = Vec::with_capacity(tree.len());
+        for node in tree.nodes() {
+            let id = self.add(match *node {
+                Constant(_) | Rational(_) | Named(_) | Symbol(_) => *node,
+                Unary(op, input) => Unary(op, ids[input]),
+                Binary(op, lhs, rhs) => Binary(op, ids[lhs], ids[rhs]),
+            });
+            ids.push(id);
+        }
+        ids[tree.root_index()]
+    }
+
+    /// Merge the classes `a` and `b`. Returns false if they are
+    /// already the same class. The graph must be rebuilt before it
+    /// is searched again.
+    pub fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        // Merge the smaller class into the larger one.
+        let (a, b) = if self.classes[a].nodes.len() < self.classes[b].nodes.len() {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        self.parents[b] = a;
+        let merged = std::mem::take(&mut self.classes[b]);
+        let class = &mut self.classes[a];
+        class.nodes.extend(merged.nodes);
+        class.value = class.value.or(merged.value);
+        class.props = class.props.union(merged.props);
+        true
+    }
+
+    /// Restore the invariants of the graph after merging classes:
+    /// nodes are canonical, and two nodes with the same operation
+    /// and inputs belong to the same class. The constant values and
+    /// the properties of the classes are updated too.
+    pub fn rebuild(&mut self) {
+        loop {
+            self.memo.clear();
+            let mut congruent: Vec<(usize, usize)> = Vec::new();
+            for id in 0..self.classes.len() {
+                if self.parents[id] != id {
+                    continue;
+                }
+                let mut nodes = std::mem::take(&mut self.classes[id].nodes);
+                for node in nodes.iter_mut() {
+                    *node = self.canonical(*node);
+                }
+                nodes.retain(|node| match self.memo.entry(Key(*node)) {
+                    Entry::Occupied(entry) => {
+                        // Keep the node if it is in a different class,
+                        // the classes are merged below.
+                        let other = *entry.get();
+                        if other != id {
+                            congruent.push((other, id));
+                        }
+                        other != id
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(id);
+                        true
+                    }
+                });
+                self.classes[id].nodes = nodes;
+            }
+            if !congruent.is_empty() {
+                for (a, b) in congruent {
+                    self.union(a, b);
+                }
+                continue;
+            }
+            if !self.analyze() {
+                break;
+            }
+        }
+    }
+
+    /// Update the constant values and the properties of the
+    /// classes. Constant classes get a constant node. Returns true if
+    /// nodes were added to the graph, in which case it must be
+    /// rebuilt.
+    fn analyze(&mut self) -> bool {
+        let mut values: Vec<Option<f64>> = self.classes.iter().map(|c| c.value).collect();
+        let mut props: Vec<Props> = self.classes.iter().map(|c| c.props).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in 0..self.classes.len() {
+                if self.parents[id] != id {
+                    continue;
+                }
+                for node in self.classes[id].nodes.iter() {
+                    let node = self.canonical(*node);
+                    if values[id].is_none() {
+                        if let Some(value) = node_value(&node, &values) {
+                            if !value.is_nan() {
+                                values[id] = Some(value);
+                                changed = true;
+                            }
+                        }
+                    }
+                    let p = props[id].union(node_props(
+                        &node,
+                        &self.facts,
+                        values[id],
+                        &values,
+                        &props,
+                    ));
+                    if p != props[id] {
+                        props[id] = p;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        let mut added = false;
+        for id in 0..self.classes.len() {
+            if self.parents[id] != id {
+                continue;
+            }
+            let class = &mut self.classes[id];
+            class.value = values[id];
+            class.props = props[id];
+            if let Some(value) = class.value {
+                if !class
+                    .nodes
+                    .iter()
+                    .any(|n| matches!(n, Constant(_) | Rational(_)))
+                {
+                    class.nodes.push(Constant(value));
+                    added = true;
+                }
+            }
+        }
+        added
+    }
+
+    /// Find all subtrees that match the ping of `template`, and
+    /// satisfy its guards. Each match consists of the id of the
+    /// matching class, and the classes bound to the symbols.
+    pub fn search(&self, template: &Template) -> Vec<(usize, Vec<(char, usize)>)> {
+        let ping = template.ping();
+        let mut matches = Vec::new();
+        for id in 0..self.classes.len() {
+            if self.parents[id] != id {
+                continue;
+            }
+            for bindings in self.match_class(template, ping.root_index(), id, vec![]) {
+                let satisfied = template.guards().iter().all(|(label, condition)| {
+                    match bindings.iter().find(|(l, _)| l == label) {
+                        Some((_, c)) => self.classes[*c].props.satisfies(*condition),
+                        None => false,
+                    }
+                });
+                if satisfied {
+                    matches.push((id, bindings));
+                }
+            }
+        }
+        matches
+    }
+
+    /// Match the `index`-th node of the ping of `template` with the
+    /// class `id`, and return all possible bindings.
+    fn match_class(
+        &self,
+        template: &Template,
+        index: usize,
+        id: usize,
+        bindings: Vec<(char, usize)>,
+    ) -> Vec<Vec<(char, usize)>> {
+        let id = self.find(id);
+        let class = &self.classes[id];
+        match template.ping().node(index) {
+            Constant(val) => match class.value {
+                Some(v) if v == *val => vec![bindings],
+                _ => vec![],
+            },
+            Rational(val) => match class.value {
+                Some(v) if v == val.to_f64() => vec![bindings],
+                _ => vec![],
+            },
+            Named(val) => match class.nodes.contains(&Named(*val)) {
+                true => vec![bindings],
+                false => vec![],
+            },
+            Symbol(label) => {
+                if template.constants().contains(label) && class.value.is_none() {
+                    return vec![];
+                }
+                match bindings.iter().find(|(l, _)| l == label) {
+                    Some((_, bound)) if *bound == id => vec![bindings],
+                    Some(_) => vec![],
+                    None => {
+                        let mut bindings = bindings;
+                        bindings.push((*label, id));
+                        vec![bindings]
+                    }
+                }
+            }
+            Unary(op, input) => {
+                let mut out = Vec::new();
+                for node in class.nodes.iter() {
+                    if let Unary(nop, ninput) = node {
+                        if nop == op {
+                            out.extend(self.match_class(
+                                template,
+                                *input,
+                                *ninput,
+                                bindings.clone(),
+                            ));
+                        }
+                    }
+                }
+                out
+            }
+            Binary(op, lhs, rhs) => {
+                let mut out = Vec::new();
+                for node in class.nodes.iter() {
+                    if let Binary(nop, nlhs, nrhs) = node {
+                        if nop != op {
+                            continue;
+                        }
+                        let mut orders = vec![(*nlhs, *nrhs)];
+                        if op.is_commutative() && nlhs != nrhs {
+                            orders.push((*nrhs, *nlhs));
+                        }
+                        for (a, b) in orders {
+                            for partial in self.match_class(template, *lhs, a, bindings.clone()) {
+                                out.extend(self.match_class(template, *rhs, b, partial));
+                            }
+                        }
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    /// Add the pong of `template` to the graph, with its symbols
+    /// substituted with the classes they're bound to, and return the
+    /// id of the class of its root.
+    pub fn instantiate(&mut self, template: &Template, bindings: &[(char, usize)]) -> usize {
+        let pong = template.pong();
+        let mut ids: Vec<usize> = Vec::with_capacity(pong.len());
+        for node in pong.nodes() {
+            let id = match *node {
+                Constant(_) | Rational(_) | Named(_) => self.add(*node),
+                Symbol(label) => match bindings.iter().find(|(l, _)| *l == label) {
+                    Some((_, id)) => *id,
+                    // Templates are checked when they're added to a
+                    // rule set, so this should never happen.
+                    None => unreachable!("Unbound symbol in template"),
+                },
+                Unary(op, input) => self.add(Unary(op, ids[input])),
+                Binary(op, lhs, rhs) => self.add(Binary(op, ids[lhs], ids[rhs])),
+            };
+            ids.push(id);
+        }
+        ids[pong.root_index()]
+    }
+
+    /// Apply all templates in `rules` to the graph until it is
+    /// saturated, or until one of the `limits` is reached.
+    pub fn saturate(&mut self, rules: &RuleSet, limits: &SaturationLimits) {
+        let start = Instant::now();
+        let exceeded =
+            |graph: &EGraph| graph.len() > limits.max_nodes || start.elapsed() > limits.timeout;
+        self.rebuild();
+        for _ in 0..limits.max_iter {
+            let before = self.len();
+            let mut changed = false;
+            for template in rules.templates() {
+                for (id, bindings) in self.search(template) {
+                    let new_id = self.instantiate(template, &bindings);
+                    changed |= self.union(id, new_id);
+                    if self.len() > limits.max_nodes {
+                        break;
+                    }
+                }
+                if exceeded(self) {
+                    break;
+                }
+            }
+            self.rebuild();
+            if (!changed && self.len() == before) || exceeded(self) {
+                break;
+            }
+        }
+    }
+
+    /// Extract the tree with the lowest `cost` from the class
+    /// `root`. The cost of each node is computed using
+    /// `CostFunction::node_cost`.
+    pub fn extract<C: CostFunction>(&self, root: usize, cost: &mut C) -> Result<Tree, TreeError> {
+        // Find the cheapest node of each class, until the costs
+        // don't change anymore.
+        let mut best: Vec<Option<(usize, Node)>> = vec![None; self.classes.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in 0..self.classes.len() {
+                if self.parents[id] != id {
+                    continue;
+                }
+                for node in self.classes[id].nodes.iter() {
+                    let node = self.canonical(*node);
+                    let node_cost = match node {
+                        Constant(_) | Rational(_) | Named(_) | Symbol(_) => {
+                            Some(cost.node_cost(&node, &[]))
+                        }
+                        Unary(_, input) => best[input].map(|(c, _)| cost.node_cost(&node, &[c])),
+                        Binary(_, lhs, rhs) => match (best[lhs], best[rhs]) {
+                            (Some((a, _)), Some((b, _))) => Some(cost.node_cost(&node, &[a, b])),
+                            _ => None,
+                        },
+                    };
+                    if let Some(cost) = node_cost {
+                        if best[id].is_none_or(|(c, _)| cost < c) {
+                            best[id] = Some((cost, node));
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+        // Gather the chosen nodes in topological order.
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut index_of: Vec<Option<usize>> = vec![None; self.classes.len()];
+        let mut stack: Vec<(usize, bool)> = vec![(self.find(root), false)];
+        while let Some((id, visited)) = stack.pop() {
+            if index_of[id].is_some() {
+                continue;
+            }
+            let node = match best[id] {
+                Some((_, node)) => node,
+                None => return Err(TreeError::EmptyTree),
+            };
+            if visited {
+                index_of[id] = Some(nodes.len());
+                nodes.push(match node {
+                    Constant(_) | Rational(_) | Named(_) | Symbol(_) => node,
+                    Unary(op, input) => Unary(op, index_of[input].unwrap()),
+                    Binary(op, lhs, rhs) => {
+                        Binary(op, index_of[lhs].unwrap(), index_of[rhs].unwrap())
+                    }
+                });
+                continue;
+            }
+            stack.push((id, true));
+            match node {
+                Constant(_) | Rational(_) | Named(_) | Symbol(_) => {}
+                Unary(_, input) => stack.push((input, false)),
+                Binary(_, lhs, rhs) => {
+                    stack.push((rhs, false));
+                    stack.push((lhs, false));
+                }
+            }
+        }
+        Tree::from_nodes(nodes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cost::NodeCount, deftree};
+
+    #[test]
+    fn t_congruence() {
+        let mut graph = EGraph::new(Facts::new());
+        let a = graph.add_tree(&deftree!(sin (+ x y)));
+        let b = graph.add_tree(&deftree!(sin (+ x z)));
+        assert_ne!(graph.find(a), graph.find(b));
+        let y = graph.add(Symbol('y'));
+        let z = graph.add(Symbol('z'));
+        graph.union(y, z);
+        graph.rebuild();
+        assert_eq!(graph.find(a), graph.find(b));
+    }
+
+    #[test]
+    fn t_constant_classes() {
+        let mut graph = EGraph::new(Facts::new());
+        let a = graph.add_tree(&deftree!(+ 2 (* 3 4)));
+        graph.rebuild();
+        let b = graph.add(Constant(14.));
+        assert_eq!(graph.find(a), graph.find(b));
+        assert_eq!(graph.extract(a, &mut NodeCount).unwrap(), deftree!(14));
+    }
+
+    #[test]
+    fn t_search() {
+        let rules = RuleSet::new();
+        let mut graph = EGraph::new(Facts::new());
+        let root = graph.add_tree(&deftree!(+ (* p x) (* y p)));
+        graph.rebuild();
+        // The operands of the addition can be matched in either
+        // order.
+        let found = graph.search(rules.get("distribute_mul").unwrap());
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|(id, _)| *id == graph.find(root)));
+        // Guards are checked against the facts.
+        let template = rules.get("divide_by_self").unwrap();
+        let mut graph = EGraph::new(Facts::new());
+        graph.add_tree(&deftree!(/ (+ x y) (+ x y)));
+        graph.rebuild();
+        assert!(graph.search(template).is_empty());
+        let mut graph = EGraph::new(Facts::new().with('x', crate::facts::Condition::Positive));
+        graph.add_tree(&deftree!(/ (exp x) (exp x)));
+        graph.add_tree(&deftree!(/ x x));
+        graph.rebuild();
+        assert_eq!(graph.search(template).len(), 2);
+    }
+}
@@ -65,6 +65,8 @@ impl<'a> Evaluator<'a> {
                 idx,
                 match &self.tree.node(idx) {
                     Constant(val) => *val,
+                    Rational(val) => val.to_f64(),
+                    Named(val) => val.value(),
                     Symbol(label) => match &self.regs[idx] {
                         None => return Err(EvaluationError::VariableNotFound(*label)),
                         Some(val) => *val,
@@ -81,8 +83,8 @@ impl<'a> Evaluator<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::deftree;
     use crate::test::util::{assert_float_eq, check_tree_eval, compare_trees};
+    use crate::{deftree, exact::Rational};
     use rand::rngs::StdRng;
     use rand::SeedableRng;
 
@@ -119,6 +121,22 @@ mod test {
         }
     }
 
+    #[test]
+    fn t_rational() {
+        let third = Tree::rational(Rational::from_fraction(1, 3).unwrap());
+        let tree = deftree!(+ x {third});
+        let mut eval = Evaluator::new(&tree);
+        eval.set_var('x', 1.);
+        assert_float_eq!(eval.run().unwrap(), 4. / 3.);
+    }
+
+    #[test]
+    fn t_named() {
+        let tree = deftree!(* (@ pi) (log (@ e)));
+        let mut eval = Evaluator::new(&tree);
+        assert_float_eq!(eval.run().unwrap(), std::f64::consts::PI);
+    }
+
     #[test]
     fn t_pythagoras() {
         const TRIPLETS: [(f64, f64, f64); 6] = [
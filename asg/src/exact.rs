@@ -0,0 +1,308 @@
+use crate::tree::{BinaryOp, UnaryOp};
+use lazy_static::lazy_static;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+use std::{cmp::Ordering, collections::HashMap, fmt, sync::RwLock};
+
+/*
+Exact rational constants are fractions of arbitrary precision
+integers. Nodes are `Copy`, so the fractions are stored in a global
+table, and nodes refer to them by their index in the table. Every
+value is stored only once, so two rationals are equal if and only if
+they have the same index, and they can be compared and hashed without
+looking at the table.
+
+Values are never removed from the table, so every distinct rational
+created during the life of the process stays in memory until it
+exits. Reference counting would need a hook when a node is dropped,
+which `Copy` nodes don't have. This is fine as long as the number of
+distinct values stays reasonable, but long running processes that
+create many different rationals, for example by folding random
+constants, will see the table grow without bound.
+
+Each rational also keeps its value rounded to the nearest `f64`, so
+trees can be evaluated without looking up the table.
+*/
+
+/// Limit on the magnitude of integer exponents when raising rationals
+/// to a power, to keep the numbers from getting out of hand.
+pub(crate) const MAX_EXACT_EXPONENT: u32 = 1024;
+
+#[derive(Default)]
+struct Table {
+    values: Vec<BigRational>,
+    ids: HashMap<BigRational, u32>,
+}
+
+lazy_static! {
+    static ref TABLE: RwLock<Table> = RwLock::new(Table::default());
+}
+
+/// An exact rational number. See the `exact` module for how the
+/// values are stored.
+#[derive(Copy, Clone)]
+pub struct Rational {
+    id: u32,
+    approx: f64,
+}
+
+impl Rational {
+    /// Create a rational with the value `value`. The value is added
+    /// to the global table if it isn't there already, and is never
+    /// removed.
+    pub fn new(value: BigRational) -> Rational {
+        if let Some(id) = TABLE.read().unwrap().ids.get(&value) {
+            return Rational {
+                id: *id,
+                approx: to_f64(&value),
+            };
+        }
+        let mut table = TABLE.write().unwrap();
+        let approx = to_f64(&value);
+        // Another thread may have inserted the value in the meantime.
+        if let Some(id) = table.ids.get(&value) {
+            return Rational { id: *id, approx };
+        }
+        let id = u32::try_from(table.values.len()).expect("Too many distinct rationals");
+        table.values.push(value.clone());
+        table.ids.insert(value, id);
+        Rational { id, approx }
+    }
+
+    /// Create a rational with the value `num / den`, or `None` if
+    /// `den` is zero.
+    pub fn from_fraction(num: i64, den: i64) -> Option<Rational> {
+        match den {
+            0 => None,
+            _ => Some(Rational::new(BigRational::new(num.into(), den.into()))),
+        }
+    }
+
+    pub fn from_integer(value: i64) -> Rational {
+        Rational::new(BigRational::from_integer(value.into()))
+    }
+
+    /// The exact value of `value`, or `None` if it is not finite. Note
+    /// that `0.1` is not exactly one tenth.
+    pub fn from_f64(value: f64) -> Option<Rational> {
+        BigRational::from_f64(value).map(Rational::new)
+    }
+
+    /// Whether this is exactly `value`. Note that `0.1` is not exactly
+    /// one tenth.
+    pub fn equals_f64(&self, value: f64) -> bool {
+        // The exact value rounds to `approx`, so the cheap check rules
+        // out almost everything.
+        self.approx == value && BigRational::from_f64(value).is_some_and(|v| v == self.value())
+    }
+
+    /// Index of the value in the table. Equal values have the same
+    /// index.
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The exact value.
+    pub fn value(&self) -> BigRational {
+        TABLE.read().unwrap().values[self.id as usize].clone()
+    }
+
+    pub fn numer(&self) -> BigInt {
+        self.value().numer().clone()
+    }
+
+    /// The denominator. This is always positive.
+    pub fn denom(&self) -> BigInt {
+        self.value().denom().clone()
+    }
+
+    /// The value rounded to the nearest `f64`.
+    pub fn to_f64(&self) -> f64 {
+        self.approx
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.value().is_integer()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.value().is_zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.value().is_negative()
+    }
+
+    /// The exact result of `op` applied to this value, if it is a
+    /// rational. See `apply_unary`.
+    pub fn apply_unary(&self, op: UnaryOp) -> Option<Rational> {
+        apply_unary(op, &self.value()).map(Rational::new)
+    }
+
+    /// The exact result of `op` applied to this value and `rhs`, if
+    /// it is a rational. See `apply_binary`.
+    pub fn apply_binary(&self, op: BinaryOp, rhs: &Rational) -> Option<Rational> {
+        apply_binary(op, &self.value(), &rhs.value()).map(Rational::new)
+    }
+}
+
+/// The exact result of `op` applied to `value`, if it is a rational.
+/// Only negation and absolute values are exact.
+pub(crate) fn apply_unary(op: UnaryOp, value: &BigRational) -> Option<BigRational> {
+    match op {
+        UnaryOp::Negate => Some(-value),
+        UnaryOp::Abs => Some(value.abs()),
+        _ => None,
+    }
+}
+
+/// The exact result of `op` applied to `a` and `b`, if it is a
+/// rational. Divisions by zero, non-integer powers and powers with
+/// large exponents are not exact.
+pub(crate) fn apply_binary(op: BinaryOp, a: &BigRational, b: &BigRational) -> Option<BigRational> {
+    Some(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide if !b.is_zero() => a / b,
+        BinaryOp::Pow if b.is_integer() => {
+            let exp = b.to_integer().to_i32()?;
+            if exp.unsigned_abs() > MAX_EXACT_EXPONENT || (exp < 0 && a.is_zero()) {
+                return None;
+            }
+            a.pow(exp)
+        }
+        BinaryOp::Min => std::cmp::min(a, b).clone(),
+        BinaryOp::Max => std::cmp::max(a, b).clone(),
+        BinaryOp::Divide | BinaryOp::Pow => return None,
+    })
+}
+
+/// The value of the shortest decimal that rounds to `value`, which is
+/// how `value` is printed, or `None` if `value` is not finite. Unlike
+/// `Rational::from_f64`, this reads `0.1` as exactly one tenth.
+pub(crate) fn decimal(value: f64) -> Option<BigRational> {
+    if !value.is_finite() {
+        return None;
+    }
+    let text = format!("{:e}", value);
+    let (mantissa, exponent) = text.split_once('e')?;
+    let exponent: i32 = exponent.parse().ok()?;
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: BigInt = format!("{}{}", int, frac).parse().ok()?;
+    let shift = exponent - frac.len() as i32;
+    let scale = BigInt::from(10).pow(shift.unsigned_abs());
+    Some(match shift >= 0 {
+        true => BigRational::from_integer(digits * scale),
+        false => BigRational::new(digits, scale),
+    })
+}
+
+fn to_f64(value: &BigRational) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+impl PartialEq for Rational {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Rational {}
+
+impl std::hash::Hash for Rational {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.id == other.id {
+            true => Ordering::Equal,
+            false => self.value().cmp(&other.value()),
+        }
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value())
+    }
+}
+
+impl fmt::Debug for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rational({})", self.value())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_rational_interning() {
+        let third = Rational::from_fraction(1, 3).unwrap();
+        assert_eq!(third, Rational::from_fraction(-2, -6).unwrap());
+        assert_ne!(third, Rational::from_fraction(1, 4).unwrap());
+        assert_eq!(third.to_string(), "1/3");
+        assert_eq!(Rational::from_integer(-5).to_string(), "-5");
+        assert_eq!(third.to_f64(), 1. / 3.);
+        assert!(Rational::from_fraction(1, 0).is_none());
+        assert_eq!(Rational::from_f64(0.25), Rational::from_fraction(1, 4));
+        assert!(Rational::from_f64(f64::INFINITY).is_none());
+        assert!(Rational::from_fraction(1, 4).unwrap() < third);
+        assert!(Rational::from_fraction(-1, 2).unwrap().is_negative());
+        assert!(Rational::from_integer(1).equals_f64(1.));
+        assert!(Rational::from_fraction(-1, 4).unwrap().equals_f64(-0.25));
+        assert!(!Rational::from_fraction(1, 10).unwrap().equals_f64(0.1));
+        assert!(!third.equals_f64(1. / 3.));
+    }
+
+    #[test]
+    fn t_rational_arithmetic() {
+        let r = |num, den| Rational::from_fraction(num, den).unwrap();
+        assert_eq!(r(1, 3).apply_binary(BinaryOp::Add, &r(1, 6)), Some(r(1, 2)));
+        assert_eq!(
+            r(1, 10).apply_binary(BinaryOp::Multiply, &r(10, 1)),
+            Some(r(1, 1))
+        );
+        assert_eq!(
+            r(2, 3).apply_binary(BinaryOp::Pow, &r(-2, 1)),
+            Some(r(9, 4))
+        );
+        assert_eq!(r(1, 3).apply_binary(BinaryOp::Divide, &r(0, 1)), None);
+        assert_eq!(r(2, 1).apply_binary(BinaryOp::Pow, &r(1, 2)), None);
+        assert_eq!(r(0, 1).apply_binary(BinaryOp::Pow, &r(-1, 1)), None);
+        assert_eq!(r(1, 3).apply_binary(BinaryOp::Min, &r(1, 4)), Some(r(1, 4)));
+        assert_eq!(r(-1, 3).apply_unary(UnaryOp::Abs), Some(r(1, 3)));
+        assert_eq!(r(1, 3).apply_unary(UnaryOp::Sqrt), None);
+        // Larger than any f64.
+        let big = r(10, 1).apply_binary(BinaryOp::Pow, &r(400, 1)).unwrap();
+        assert_eq!(big.to_f64(), f64::INFINITY);
+        let tiny = r(1, 1).apply_binary(BinaryOp::Divide, &big).unwrap();
+        assert_eq!(tiny.apply_binary(BinaryOp::Multiply, &big), Some(r(1, 1)));
+    }
+
+    #[test]
+    fn t_decimal() {
+        let r = |num: i128, den: i128| BigRational::new(num.into(), den.into());
+        assert_eq!(decimal(0.1), Some(r(1, 10)));
+        assert_eq!(decimal(-2.5e-7), Some(r(-1, 4_000_000)));
+        assert_eq!(decimal(1.5e20), Some(r(150_000_000_000_000_000_000, 1)));
+        assert_eq!(decimal(-0.), Some(r(0, 1)));
+        assert_eq!(decimal(f64::NAN), None);
+        assert_eq!(decimal(f64::NEG_INFINITY), None);
+        for value in [0.3, 1. / 3., 6.02214076e23, -1e-300, f64::MAX] {
+            assert_eq!(decimal(value).unwrap().to_f64(), Some(value));
+        }
+    }
+}
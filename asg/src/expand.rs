@@ -0,0 +1,369 @@
+use crate::{
+    canonical::CanonicalTree,
+    dedup::Deduplicater,
+    exact::{decimal, MAX_EXACT_EXPONENT},
+    poly::{as_exponent, coefficient, fold_binary, fold_unary, Polynomial},
+    prune::Pruner,
+    tree::{pow, BinaryOp, BinaryOp::*, Node::*, Tree, TreeError, UnaryOp::*},
+};
+use num_rational::BigRational;
+use num_traits::{One, Signed, Zero};
+use std::collections::HashMap;
+
+/*
+Expansion works bottom up. Every node of the tree is turned into a
+sum of terms, where each term is an exact rational coefficient times a
+product of atoms raised to positive integer powers. Atoms are the
+symbols, and the subtrees that are not sums, differences, products,
+negations, divisions by constants or non-negative integer powers, such
+as `(sin x)`. Powers with exponents above `MAX_EXACT_EXPONENT` are
+atoms too, because expanding them would take too long. The insides of
+atoms are expanded as well. The sums are
+`Polynomial`s whose variables are the indices of the atoms.
+
+The terms of the result are in graded lexicographic order: terms of
+higher total degree come first, and terms of the same degree are
+ordered by the powers of the atoms, with higher powers first. Symbols
+come before other atoms, in alphabetical order. Other atoms are
+ordered by their LISP notation. Coefficients of one are omitted, and
+negative coefficients are subtracted:
+
+    (* (+ x 1) (- x 1))  =>  (- (pow x 2) 1)
+    (pow (+ x y) 2)      =>  (+ (+ (pow x 2) (* 2 (* x y))) (pow y 2))
+*/
+
+/// Product of atoms raised to positive integer powers, as the index
+/// of each atom and its power.
+type Monomial = Vec<(usize, u32)>;
+
+/// The terms of `poly`, whose variables are the indices of atoms, with
+/// the atoms of each monomial in the order of their indices.
+fn monomials(poly: &Polynomial<usize>) -> impl Iterator<Item = (Monomial, BigRational)> + '_ {
+    poly.terms().map(|(exps, coeff)| {
+        let mono = poly
+            .vars()
+            .iter()
+            .zip(exps.iter())
+            .filter(|(_, exp)| **exp > 0)
+            .map(|(atom, exp)| (*atom, *exp))
+            .collect();
+        (mono, coeff.clone())
+    })
+}
+
+/// Expands trees, and keeps track of the atoms found in them.
+#[derive(Default)]
+struct Expander {
+    atoms: Vec<Tree>,
+    ids: HashMap<CanonicalTree, usize>,
+}
+
+impl Expander {
+    /// The polynomial of the atom `tree`, whose only variable is the
+    /// index of the atom.
+    fn atom(&mut self, tree: Tree) -> Result<Polynomial<usize>, TreeError> {
+        // Identified by their canonical form, but the terms of the
+        // expanded subtrees inside are kept in their order.
+        let key = CanonicalTree::new(tree.clone())?;
+        let next = self.atoms.len();
+        let index = *self.ids.entry(key).or_insert(next);
+        if index == next {
+            self.atoms.push(tree);
+        }
+        Ok(Polynomial::var(index))
+    }
+
+    /// Expand `tree`, returning the polynomial of its root.
+    fn expand(&mut self, tree: &Tree) -> Result<Polynomial<usize>, TreeError> {
+        let mut polys = Vec::<Polynomial<usize>>::with_capacity(tree.len());
+        for node in tree.nodes() {
+            let poly = match *node {
+                Constant(value) => self.folded(decimal(value).ok_or(value))?,
+                Rational(value) => Polynomial::constant(value.value()),
+                Named(value) => self.atom(Tree::named(value))?,
+                Symbol(label) => self.atom(Tree::symbol(label))?,
+                Unary(Negate, input) => -&polys[input],
+                Unary(op, input) => match polys[input].as_constant() {
+                    Some(value) => self.folded(fold_unary(op, &value))?,
+                    None => {
+                        let input = self.to_tree(&polys[input]);
+                        self.atom(input.unary_op(op))?
+                    }
+                },
+                Binary(op, lhs, rhs) => {
+                    let (l, r) = (&polys[lhs], &polys[rhs]);
+                    match (op, l.as_constant(), r.as_constant()) {
+                        (Add, ..) => l + r,
+                        (Subtract, ..) => l - r,
+                        // Products and powers whose exponents overflow,
+                        // or are too large to expand, are kept as atoms.
+                        (Multiply, ..) => match l.checked_mul(r) {
+                            Some(product) => product,
+                            None => self.binary_atom(op, l, r)?,
+                        },
+                        (_, Some(a), Some(b)) => self.folded(fold_binary(op, &a, &b))?,
+                        (Divide, _, Some(b)) if !b.is_zero() => l.scale(&b.recip()),
+                        (Pow, _, Some(b)) => match as_exponent(&b)
+                            .filter(|k| *k <= MAX_EXACT_EXPONENT)
+                            .and_then(|k| l.checked_pow(k))
+                        {
+                            Some(power) => power,
+                            None => self.binary_atom(op, l, r)?,
+                        },
+                        _ => self.binary_atom(op, l, r)?,
+                    }
+                }
+            };
+            polys.push(poly);
+        }
+        Ok(polys.pop().unwrap())
+    }
+
+    /// The result of folding constants. Values that are not finite
+    /// can't be coefficients, so they become atoms.
+    fn folded(&mut self, value: Result<BigRational, f64>) -> Result<Polynomial<usize>, TreeError> {
+        match value {
+            Ok(value) => Ok(Polynomial::constant(value)),
+            Err(value) => self.atom(Tree::constant(value)),
+        }
+    }
+
+    /// The polynomial of the atom `op` applied to `lhs` and `rhs`.
+    fn binary_atom(
+        &mut self,
+        op: BinaryOp,
+        lhs: &Polynomial<usize>,
+        rhs: &Polynomial<usize>,
+    ) -> Result<Polynomial<usize>, TreeError> {
+        let (lhs, rhs) = (self.to_tree(lhs), self.to_tree(rhs));
+        self.atom(lhs.binary_op(rhs, op))
+    }
+
+    /// Rank of each atom in the order described at the top of this
+    /// module.
+    fn ranks(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.atoms.len()).collect();
+        order.sort_by_cached_key(|i| match self.atoms[*i].root() {
+            Symbol(label) => (false, label.to_string()),
+            _ => (true, self.atoms[*i].to_lisp()),
+        });
+        let mut ranks = vec![0; order.len()];
+        for (rank, index) in order.into_iter().enumerate() {
+            ranks[index] = rank;
+        }
+        ranks
+    }
+
+    /// The terms of `poly` in graded lexicographic order. The atoms
+    /// of each monomial are sorted by rank.
+    fn sorted_terms(&self, poly: &Polynomial<usize>) -> Vec<(Monomial, BigRational)> {
+        let ranks = self.ranks();
+        let mut terms: Vec<(Monomial, BigRational)> = monomials(poly)
+            .map(|(mut mono, coeff)| {
+                mono.sort_by_key(|(atom, _)| ranks[*atom]);
+                (mono, coeff)
+            })
+            .collect();
+        let dense = |mono: &Monomial| {
+            let mut exps = vec![0u32; ranks.len()];
+            for (atom, exp) in mono.iter() {
+                exps[ranks[*atom]] = *exp;
+            }
+            exps
+        };
+        let degree = |mono: &Monomial| mono.iter().map(|(_, e)| *e as u64).sum::<u64>();
+        terms.sort_by(|(a, _), (b, _)| {
+            degree(b)
+                .cmp(&degree(a))
+                .then_with(|| dense(b).cmp(&dense(a)))
+        });
+        terms
+    }
+
+    fn monomial_tree(&self, mono: &Monomial) -> Option<Tree> {
+        mono.iter()
+            .map(|(atom, exp)| {
+                let atom = self.atoms[*atom].clone();
+                match exp {
+                    1 => atom,
+                    _ => pow(atom, Tree::constant(*exp as f64)),
+                }
+            })
+            .reduce(|acc, factor| acc * factor)
+    }
+
+    /// Convert `poly` to a tree, with its terms in graded
+    /// lexicographic order.
+    fn to_tree(&self, poly: &Polynomial<usize>) -> Tree {
+        sum_terms(
+            self.sorted_terms(poly)
+                .into_iter()
+                .map(|(mono, coeff)| (self.monomial_tree(&mono), coeff)),
+        )
+    }
+}
+
+/// Add up terms, each of which is a coefficient times an optional
+/// tree. Negative coefficients after the first term are subtracted.
+fn sum_terms<I: Iterator<Item = (Option<Tree>, BigRational)>>(terms: I) -> Tree {
+    let term = |tree: Option<Tree>, coeff: BigRational| match tree {
+        None => coefficient(&coeff),
+        Some(tree) if coeff.is_one() => tree,
+        Some(tree) if (-&coeff).is_one() => -tree,
+        Some(tree) => coefficient(&coeff) * tree,
+    };
+    let mut out: Option<Tree> = None;
+    for (tree, coeff) in terms {
+        out = Some(match out {
+            None => term(tree, coeff),
+            Some(acc) if coeff.is_negative() => acc - term(tree, -coeff),
+            Some(acc) => acc + term(tree, coeff),
+        });
+    }
+    out.unwrap_or_else(|| Tree::constant(0.))
+}
+
+/// Remove the duplicate subtrees introduced by building the result.
+fn compact(tree: Tree) -> Result<Tree, TreeError> {
+    Ok(tree
+        .validated()?
+        .deduplicate(&mut Deduplicater::new())?
+        .prune(&mut Pruner::new()))
+}
+
+impl Tree {
+    /// Distribute products over sums, and expand non-negative integer
+    /// powers of sums. The result is a sum of terms with combined
+    /// numeric coefficients, in the canonical order described at the
+    /// top of this module. The coefficients are exact, as in
+    /// `Tree::to_polynomial`. Constants that are not finite are kept
+    /// as atoms, and if the result contains NaN, the appropriate
+    /// `TreeError` is returned.
+    pub fn expand(self) -> Result<Tree, TreeError> {
+        let mut expander = Expander::default();
+        let poly = expander.expand(&self)?;
+        compact(expander.to_tree(&poly))
+    }
+
+    /// Expand this tree, and group its terms by the powers of the
+    /// symbol `x`. The result is a sum of the powers of `x`, from the
+    /// highest to the lowest, each multiplied by an expanded
+    /// coefficient that doesn't contain `x`. Atoms such as `(sin x)`
+    /// are not considered powers of `x`.
+    pub fn collect(self, x: char) -> Result<Tree, TreeError> {
+        let mut expander = Expander::default();
+        let poly = expander.expand(&self)?;
+        let x_atom = expander.atom(Tree::symbol(x))?.vars()[0];
+        // Coefficients of each power of x.
+        let coeffs = poly.coefficients_of(x_atom);
+        let terms = coeffs.iter().rev().map(|(power, coeff)| {
+            let xk = match power {
+                0 => None,
+                1 => Some(Tree::symbol(x)),
+                _ => Some(pow(Tree::symbol(x), Tree::constant(*power as f64))),
+            };
+            // Coefficients with a single term keep their sign, so they
+            // can be subtracted.
+            let (tree, value) = match coeff.terms().count() {
+                1 => {
+                    let (mono, value) = monomials(coeff).next().unwrap();
+                    (expander.monomial_tree(&mono), value)
+                }
+                _ => (Some(expander.to_tree(coeff)), BigRational::one()),
+            };
+            match (tree, xk) {
+                (Some(tree), Some(xk)) => (Some(tree * xk), value),
+                (tree, xk) => (tree.or(xk), value),
+            }
+        });
+        compact(sum_terms(terms))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{deftree, verify::numerically_equivalent};
+
+    fn check_expand(tree: crate::tree::Tree, expected: &str) {
+        let expanded = tree.clone().expand().unwrap();
+        assert_eq!(expanded.to_lisp(), expected);
+        let domains: Vec<_> = tree.symbols().iter().map(|c| (*c, -3., 3.)).collect();
+        numerically_equivalent(&tree, &expanded, &domains, 100, 1e-9).unwrap();
+    }
+
+    #[test]
+    fn t_expand() {
+        check_expand(deftree!(* (+ x 1) (- x 1)), "(- (pow x 2) 1)");
+        check_expand(
+            deftree!(pow (+ x y) 2),
+            "(+ (+ (pow x 2) (* 2 (* x y))) (pow y 2))",
+        );
+        check_expand(deftree!(- (* 2 (+ y x)) (/ (* 4 x) 2)), "(* 2 y)");
+        check_expand(deftree!(- (* x y) (* y x)), "0");
+        check_expand(
+            deftree!(* (- 3 x) (+ x (pow x 2))),
+            "(+ (+ (- (pow x 3)) (* 2 (pow x 2))) (* 3 x))",
+        );
+        // The insides of atoms are expanded too.
+        check_expand(
+            deftree!(* (sin (* 2 (+ x 1))) (+ x 1)),
+            "(+ (* x (sin (+ (* 2 x) 2))) (sin (+ (* 2 x) 2)))",
+        );
+        // Coefficients are exact.
+        check_expand(
+            deftree!(- (* (- x 0.1) (- x 0.2)) (* x x)),
+            "(+ (* -0.3 x) 0.02)",
+        );
+        check_expand(deftree!(/ (+ x 1) 3), "(+ (* 1/3 x) 1/3)");
+        // Other powers are atoms.
+        check_expand(
+            deftree!(* (pow (+ x 1) 0.5) (+ x 1)),
+            "(+ (* x (pow (+ x 1) 0.5)) (pow (+ x 1) 0.5))",
+        );
+    }
+
+    #[test]
+    fn t_expand_overflow() {
+        // Powers with exponents that don't fit in a u32 are kept as
+        // atoms.
+        assert_eq!(
+            deftree!(* (pow x 3000000000.) (pow x 3000000000.))
+                .expand()
+                .unwrap()
+                .to_lisp(),
+            "(pow (pow x 3000000000) 2)"
+        );
+        // So are powers with exponents too large to expand.
+        assert_eq!(
+            deftree!(pow (+ (+ x y) z) 100000)
+                .expand()
+                .unwrap()
+                .to_lisp(),
+            "(pow (+ (+ x y) z) 100000)"
+        );
+    }
+
+    #[test]
+    fn t_expand_deterministic() {
+        let a = deftree!(* (+ (sin y) x) (+ b a)).expand().unwrap();
+        let b = deftree!(* (+ a b) (+ x (sin y))).expand().unwrap();
+        assert_eq!(a.to_lisp(), b.to_lisp());
+    }
+
+    #[test]
+    fn t_collect() {
+        let tree = deftree!(+ (+ (* a x) (* (pow x 2) (+ b 1))) (- (* x b) c));
+        let collected = tree.clone().collect('x').unwrap();
+        assert_eq!(
+            collected.to_lisp(),
+            "(- (+ (* (+ b 1) (pow x 2)) (* (+ a b) x)) c)"
+        );
+        let domains: Vec<_> = tree.symbols().iter().map(|c| (*c, -3., 3.)).collect();
+        numerically_equivalent(&tree, &collected, &domains, 100, 1e-9).unwrap();
+        assert_eq!(
+            deftree!(* 2 (+ x (* 3 x))).collect('x').unwrap().to_lisp(),
+            "(* 8 x)"
+        );
+        assert_eq!(deftree!(+ y 1).collect('x').unwrap().to_lisp(), "(+ y 1)");
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{
+    poly::{coefficient, gcd_integers, Polynomial, PolynomialError},
+    tree::{pow, Tree},
+};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+/*
+Polynomials are factored in stages:
+
+1. The numeric content, i.e. the greatest common divisor of the
+   numerators of the coefficients over the least common multiple of
+   their denominators, is pulled out. The rest is primitive, with
+   integer coefficients that have no common factor.
+2. Powers of variables that divide every term are pulled out.
+3. The content with respect to the first variable, which is a
+   polynomial in the remaining variables, is factored recursively.
+4. What is left is split into square-free factors using Yun's
+   algorithm, which finds the factors of each multiplicity using
+   greatest common divisors with the derivative.
+5. Square-free factors in a single variable with integer coefficients
+   are split further by finding their rational roots. A root `p / q`
+   corresponds to the linear factor `q x - p`, where `p` divides the
+   constant coefficient and `q` divides the leading coefficient.
+
+This finds all linear factors of univariate polynomials, and factors
+such as `k (x + y)` or `(x + y)^2` of multivariate polynomials. It
+doesn't find irreducible factors of degree two or more, such as
+`x^2 + 1`, or factors like `x - y` of `x^2 - y^2`.
+*/
+
+/// Polynomials whose leading or constant coefficient is larger than
+/// this in magnitude are not searched for rational roots, because the
+/// divisors of those coefficients are found by trial division.
+const MAX_DIVISOR_SEARCH: u64 = 1 << 40;
+
+impl Polynomial {
+    /// Factor this polynomial over the rationals, as far as the
+    /// stages described in the `factor` module are able to. Returns a
+    /// constant factor, and the other factors with their
+    /// multiplicities. If the coefficients of this polynomial are all
+    /// integers, the factors are primitive, with integer coefficients
+    /// and positive leading coefficients. Otherwise their leading
+    /// coefficients are one. The factors are sorted by their number of
+    /// terms, then their degree. The product of the constant and the
+    /// factors raised to their multiplicities is this polynomial.
+    pub fn factor(&self) -> (BigRational, Vec<(Polynomial, u32)>) {
+        if self.is_zero() {
+            return (BigRational::zero(), vec![]);
+        }
+        let (constant, primitive) = numeric_content(self);
+        let mut factors = Vec::<(Polynomial, u32)>::new();
+        factor_primitive(&primitive, 1, &mut factors);
+        let integer = self.terms().all(|(_, coeff)| coeff.is_integer());
+        // Merge identical factors.
+        let mut merged = Vec::<(Polynomial, u32)>::with_capacity(factors.len());
+        for (factor, count) in factors {
+            let factor = match (integer, factor.leading_term()) {
+                (false, Some((_, lead))) => factor.scale(&lead.recip()),
+                _ => factor,
+            };
+            match merged.iter_mut().find(|(f, _)| *f == factor) {
+                Some((_, total)) => *total += count,
+                None => merged.push((factor, count)),
+            }
+        }
+        merged.sort_by_cached_key(|(f, _)| {
+            (f.terms().count(), f.total_degree(), f.to_tree().to_lisp())
+        });
+        // The factors are only determined up to a constant.
+        let product = merged
+            .iter()
+            .fold(Polynomial::constant(BigRational::one()), |acc, (f, k)| {
+                &acc * &f.pow(*k)
+            });
+        let (quot, rem) = self.div_rem(&product);
+        match (quot.as_constant(), rem.is_zero()) {
+            (Some(scale), true) => (scale, merged),
+            // Never return factors whose product isn't this
+            // polynomial.
+            _ => (constant, vec![(primitive, 1)]),
+        }
+    }
+}
+
+/// Split `poly` into its numeric content, with the sign of the
+/// leading coefficient, and the rest. The content is the greatest
+/// common divisor of the numerators of the coefficients over the
+/// least common multiple of their denominators, so the rest has
+/// integer coefficients without a common factor.
+fn numeric_content(poly: &Polynomial) -> (BigRational, Polynomial) {
+    let (num, den) =
+        poly.terms()
+            .fold((BigInt::zero(), BigInt::one()), |(num, den), (_, coeff)| {
+                let lcm = &den * coeff.denom() / gcd_integers(den.clone(), coeff.denom().clone());
+                (gcd_integers(num, coeff.numer().clone()), lcm)
+            });
+    let content = match poly.leading_term() {
+        None => BigRational::one(),
+        Some((_, lead)) if lead.is_negative() => BigRational::new(-num, den),
+        Some(_) => BigRational::new(num, den),
+    };
+    let primitive = poly.scale(&content.recip());
+    (content, primitive)
+}
+
+/// Push the factors of the primitive polynomial `poly`, each with
+/// multiplicity `count` times its multiplicity in `poly`.
+fn factor_primitive(poly: &Polynomial, count: u32, out: &mut Vec<(Polynomial, u32)>) {
+    if poly.as_constant().is_some() {
+        return;
+    }
+    let mut monomial = Polynomial::constant(BigRational::one());
+    for (i, label) in poly.vars().iter().enumerate() {
+        let power = poly.terms().map(|(exps, _)| exps[i]).min().unwrap_or(0);
+        if power > 0 {
+            out.push((Polynomial::var(*label), power * count));
+            monomial = &monomial * &Polynomial::var(*label).pow(power);
+        }
+    }
+    let mut poly = poly.div_rem(&monomial).0;
+    let label = match poly.vars().first() {
+        Some(label) => *label,
+        None => return,
+    };
+    let content = poly.content(label);
+    if content.as_constant().is_none() {
+        poly = poly.div_rem(&content).0;
+        factor_primitive(&numeric_content(&content).1, count, out);
+    }
+    for (factor, k) in square_free(&poly, label) {
+        let factor = numeric_content(&factor).1;
+        for linear in rational_roots(factor, label) {
+            out.push((linear, k * count));
+        }
+    }
+}
+
+/// Square-free decomposition of `poly` with respect to `label`, using
+/// Yun's algorithm. Returns the factors with their multiplicities.
+fn square_free(poly: &Polynomial, label: char) -> Vec<(Polynomial, u32)> {
+    let mut out = Vec::new();
+    let derivative = poly.derivative(label);
+    let gcd = poly.gcd(&derivative);
+    let mut b = poly.div_rem(&gcd).0;
+    let c = derivative.div_rem(&gcd).0;
+    let mut d = &c - &b.derivative(label);
+    let mut multiplicity = 1;
+    while b.as_constant().is_none() {
+        let a = b.gcd(&d);
+        b = b.div_rem(&a).0;
+        let c = d.div_rem(&a).0;
+        d = &c - &b.derivative(label);
+        if a.as_constant().is_none() {
+            out.push((a, multiplicity));
+        }
+        multiplicity += 1;
+    }
+    out
+}
+
+/// Divisors of `n`, in ascending order.
+fn divisors(n: u64) -> Vec<u64> {
+    let (mut small, mut large) = (vec![], vec![]);
+    let mut i = 1;
+    while i * i <= n {
+        if n.is_multiple_of(i) {
+            small.push(i);
+            if i * i != n {
+                large.push(n / i);
+            }
+        }
+        i += 1;
+    }
+    small.extend(large.into_iter().rev());
+    small
+}
+
+/// Split the primitive, square-free polynomial `poly` into linear
+/// factors `q x - p` for each of its rational roots `p / q`, and the
+/// rest. Polynomials in more than one variable, or with coefficients
+/// that aren't integers, are not split.
+fn rational_roots(mut poly: Polynomial, label: char) -> Vec<Polynomial> {
+    let mut out = Vec::new();
+    'outer: while poly.vars() == [label] && poly.degree(label) > 1 {
+        let coeffs = poly.coefficients_of(label);
+        let lead = coeffs.values().next_back().and_then(|c| c.as_constant());
+        let trail = coeffs.get(&0).and_then(|c| c.as_constant());
+        let integer = |c: Option<BigRational>| match c {
+            Some(c) if c.is_integer() => c.to_integer().abs().to_u64(),
+            _ => None,
+        };
+        let (lead, trail) = match (integer(lead), integer(trail)) {
+            (Some(lead), Some(trail)) if lead.max(trail) <= MAX_DIVISOR_SEARCH => (lead, trail),
+            _ => break,
+        };
+        for q in divisors(lead) {
+            for p in divisors(trail) {
+                if !gcd_integers(p.into(), q.into()).is_one() {
+                    continue;
+                }
+                for sign in [1, -1] {
+                    let linear = &Polynomial::var(label)
+                        .scale(&BigRational::from_integer(q.into()))
+                        - &Polynomial::constant(BigRational::from_integer(
+                            (sign * p as i64).into(),
+                        ));
+                    let (quot, rem) = poly.div_rem(&linear);
+                    if rem.is_zero() {
+                        out.push(linear);
+                        poly = quot;
+                        continue 'outer;
+                    }
+                }
+            }
+        }
+        break;
+    }
+    out.push(poly);
+    out
+}
+
+impl Tree {
+    /// Factor this tree, if it is a polynomial, using
+    /// `Polynomial::factor`. The result is the product of the
+    /// constant factor and the other factors, converted to trees
+    /// using Horner's scheme, and raised to their multiplicities.
+    pub fn factor(self) -> Result<Tree, PolynomialError> {
+        let (constant, factors) = self.to_polynomial()?.factor();
+        let product = factors
+            .into_iter()
+            .map(|(factor, k)| match k {
+                1 => factor.to_tree(),
+                _ => pow(factor.to_tree(), Tree::constant(k as f64)),
+            })
+            .reduce(|acc, factor| acc * factor);
+        Ok(match product {
+            None => coefficient(&constant),
+            Some(product) if constant.is_one() => product,
+            Some(product) if (-&constant).is_one() => -product,
+            Some(product) => coefficient(&constant) * product,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deftree, verify::numerically_equivalent};
+
+    fn check_factor(tree: Tree, expected: &str) {
+        let factored = tree.clone().factor().unwrap();
+        assert_eq!(factored.to_lisp(), expected);
+        let domains: Vec<_> = tree.symbols().iter().map(|c| (*c, -3., 3.)).collect();
+        numerically_equivalent(&tree, &factored, &domains, 100, 1e-9).unwrap();
+    }
+
+    #[test]
+    fn t_factor_univariate() {
+        check_factor(deftree!(- (pow x 2) 1), "(* (+ x 1) (- x 1))");
+        check_factor(
+            deftree!(+ (- (* 2 (pow x 3)) (* 2 (pow x 2))) (- (* 4 x) 4)),
+            "(* 2 (* (- x 1) (+ (pow x 2) 2)))",
+        );
+        check_factor(
+            deftree!(* (pow (- (* 2 x) 3) 2) (- (pow x 2) x)),
+            "(* (* x (pow (- (* 2 x) 3) 2)) (- x 1))",
+        );
+        check_factor(
+            deftree!(- 6 (* 6 (pow x 3))),
+            "(* -6 (* (- x 1) (+ (* (+ x 1) x) 1)))",
+        );
+        check_factor(deftree!(+ (pow x 2) 1), "(+ (pow x 2) 1)");
+        // Rational coefficients are factored exactly.
+        check_factor(
+            deftree!(* (pow (+ x 0.1) 2) (+ x 0.3)),
+            "(* (pow (+ x 0.1) 2) (+ x 0.3))",
+        );
+        check_factor(
+            deftree!(+ (+ (+ (pow x 3) (* 0.5 (pow x 2))) (* 0.07 x)) 0.003),
+            "(* (pow (+ x 0.1) 2) (+ x 0.3))",
+        );
+        check_factor(
+            deftree!(- (/ (pow x 2) 2) 0.5),
+            "(* 0.5 (* (+ x 1) (- x 1)))",
+        );
+        check_factor(deftree!(* 3 4), "12");
+    }
+
+    #[test]
+    fn t_factor_multivariate() {
+        check_factor(deftree!(+ (* k x) (* k y)), "(* k (+ x y))");
+        check_factor(
+            deftree!(+ (+ (pow x 2) (* 2 (* x y))) (pow y 2)),
+            "(pow (+ x y) 2)",
+        );
+        check_factor(
+            deftree!(* (+ (* x y) y) (+ (* x y) (* 3 x))),
+            "(* (* (* x y) (+ x 1)) (+ y 3))",
+        );
+        assert!(matches!(
+            deftree!(+ x (sin x)).factor(),
+            Err(PolynomialError::NotPolynomial(_))
+        ));
+        assert!(matches!(
+            deftree!(* (sqrt (- 1)) x).factor(),
+            Err(PolynomialError::NotFinite(_))
+        ));
+    }
+
+    #[test]
+    fn t_polynomial_factor() {
+        let poly = deftree!(* 4 (pow (+ x 1) 3)).to_polynomial().unwrap();
+        let (constant, factors) = poly.factor();
+        assert_eq!(constant, BigRational::from_integer(4.into()));
+        assert_eq!(factors.len(), 1);
+        assert_eq!(factors[0].1, 3);
+        assert_eq!(factors[0].0, deftree!(+ x 1).to_polynomial().unwrap());
+        assert_eq!(Polynomial::zero().factor(), (BigRational::zero(), vec![]));
+        assert_eq!(divisors(12), vec![1, 2, 3, 4, 6, 12]);
+        assert_eq!(divisors(9), vec![1, 3, 9]);
+    }
+}
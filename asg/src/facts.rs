@@ -0,0 +1,458 @@
+use crate::tree::{BinaryOp::*, Node, Node::*, UnaryOp::*};
+
+/// A property of a value, that can be required of the subtree bound
+/// to a symbol of a template, or assumed about a symbol in a tree.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Condition {
+    /// The value is greater than zero.
+    Positive,
+    /// The value is less than zero.
+    Negative,
+    /// The value is greater than or equal to zero.
+    NonNegative,
+    /// The value is less than or equal to zero.
+    NonPositive,
+    /// The value is not zero.
+    NonZero,
+    /// The value is a whole number.
+    Integer,
+    /// The value does not depend on any symbols.
+    ConstantOnly,
+}
+
+use Condition::*;
+
+impl Condition {
+    const ALL: [Condition; 7] = [
+        Positive,
+        Negative,
+        NonNegative,
+        NonPositive,
+        NonZero,
+        Integer,
+        ConstantOnly,
+    ];
+
+    /// Name of the condition, used in text formats.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Positive => "positive",
+            Negative => "negative",
+            NonNegative => "non_negative",
+            NonPositive => "non_positive",
+            NonZero => "non_zero",
+            Integer => "integer",
+            ConstantOnly => "constant_only",
+        }
+    }
+
+    /// Find the condition with the given `name`.
+    pub fn from_name(name: &str) -> Option<Condition> {
+        Condition::ALL.into_iter().find(|c| c.name() == name)
+    }
+}
+
+/// Known facts about the symbols in a tree.
+///
+/// Facts about symbols are combined with what can be inferred from
+/// the structure of the tree, e.g. `exp(x)` is always positive, to
+/// decide whether the guards of a template are satisfied.
+#[derive(Debug, Clone, Default)]
+pub struct Facts {
+    known: Vec<(char, Condition)>,
+}
+
+impl Facts {
+    /// Create an empty set of facts. Nothing is assumed about any
+    /// symbol.
+    pub fn new() -> Facts {
+        Facts { known: vec![] }
+    }
+
+    /// Assume `condition` holds for the symbol with `label`.
+    pub fn assume(&mut self, label: char, condition: Condition) -> &mut Facts {
+        if !self.known.contains(&(label, condition)) {
+            self.known.push((label, condition));
+        }
+        self
+    }
+
+    /// Same as `assume` but consumes and returns `self`, for
+    /// chaining.
+    pub fn with(mut self, label: char, condition: Condition) -> Facts {
+        self.assume(label, condition);
+        self
+    }
+
+    /// All the facts, in the order they were assumed.
+    pub fn known(&self) -> &[(char, Condition)] {
+        &self.known
+    }
+
+    /// Check if `condition` holds for the symbol with `label`.
+    pub fn holds(&self, label: char, condition: Condition) -> bool {
+        self.symbol_props(label).satisfies(condition)
+    }
+
+    fn symbol_props(&self, label: char) -> Props {
+        self.known
+            .iter()
+            .filter(|(l, _)| *l == label)
+            .fold(Props::default(), |props, (_, c)| {
+                props.union(Props::from(*c))
+            })
+            .complete()
+    }
+}
+
+/// Properties known about the value of a node, stored as bit flags.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub(crate) struct Props(u8);
+
+impl Props {
+    const POS: u8 = 1 << 0;
+    const NEG: u8 = 1 << 1;
+    const NONNEG: u8 = 1 << 2;
+    const NONPOS: u8 = 1 << 3;
+    const NONZERO: u8 = 1 << 4;
+    const INTEGER: u8 = 1 << 5;
+    const CONST: u8 = 1 << 6;
+
+    fn has(&self, flags: u8) -> bool {
+        self.0 & flags == flags
+    }
+
+    pub(crate) fn union(self, other: Props) -> Props {
+        Props(self.0 | other.0)
+    }
+
+    fn with(self, flags: u8, cond: bool) -> Props {
+        if cond {
+            Props(self.0 | flags)
+        } else {
+            self
+        }
+    }
+
+    /// Add the properties that follow from the ones already known.
+    fn complete(self) -> Props {
+        let mut out = self;
+        out = out.with(Self::NONNEG | Self::NONZERO, self.has(Self::POS));
+        out = out.with(Self::NONPOS | Self::NONZERO, self.has(Self::NEG));
+        out = out.with(Self::POS, out.has(Self::NONNEG | Self::NONZERO));
+        out = out.with(Self::NEG, out.has(Self::NONPOS | Self::NONZERO));
+        out
+    }
+
+    /// Properties of a known `value`.
+    pub(crate) fn of_value(value: f64) -> Props {
+        Props(Self::CONST)
+            .with(Self::POS, value > 0.)
+            .with(Self::NEG, value < 0.)
+            .with(Self::NONNEG, value >= 0.)
+            .with(Self::NONPOS, value <= 0.)
+            .with(Self::INTEGER, value.is_finite() && value.fract() == 0.)
+            .complete()
+    }
+
+    pub(crate) fn satisfies(&self, condition: Condition) -> bool {
+        match condition {
+            Positive => self.has(Self::POS),
+            Negative => self.has(Self::NEG),
+            NonNegative => self.has(Self::NONNEG),
+            NonPositive => self.has(Self::NONPOS),
+            NonZero => self.has(Self::NONZERO),
+            Integer => self.has(Self::INTEGER),
+            ConstantOnly => self.has(Self::CONST),
+        }
+    }
+
+    /// Swap the positive and negative properties.
+    fn negated(self) -> Props {
+        Props(self.0 & (Self::NONZERO | Self::INTEGER | Self::CONST))
+            .with(Self::POS, self.has(Self::NEG))
+            .with(Self::NEG, self.has(Self::POS))
+            .with(Self::NONNEG, self.has(Self::NONPOS))
+            .with(Self::NONPOS, self.has(Self::NONNEG))
+    }
+
+    /// Sign properties of the sum of two values with properties `a`
+    /// and `b`.
+    fn sum(a: Props, b: Props) -> Props {
+        Props::default()
+            .with(
+                Self::POS,
+                (a.has(Self::POS) && b.has(Self::NONNEG))
+                    || (a.has(Self::NONNEG) && b.has(Self::POS)),
+            )
+            .with(
+                Self::NEG,
+                (a.has(Self::NEG) && b.has(Self::NONPOS))
+                    || (a.has(Self::NONPOS) && b.has(Self::NEG)),
+            )
+            .with(Self::NONNEG, a.has(Self::NONNEG) && b.has(Self::NONNEG))
+            .with(Self::NONPOS, a.has(Self::NONPOS) && b.has(Self::NONPOS))
+    }
+
+    /// Sign properties of the product, or the quotient, of two values
+    /// with properties `a` and `b`.
+    fn product(a: Props, b: Props) -> Props {
+        Props::default()
+            .with(
+                Self::POS,
+                (a.has(Self::POS) && b.has(Self::POS)) || (a.has(Self::NEG) && b.has(Self::NEG)),
+            )
+            .with(
+                Self::NEG,
+                (a.has(Self::POS) && b.has(Self::NEG)) || (a.has(Self::NEG) && b.has(Self::POS)),
+            )
+            .with(
+                Self::NONNEG,
+                (a.has(Self::NONNEG) && b.has(Self::NONNEG))
+                    || (a.has(Self::NONPOS) && b.has(Self::NONPOS)),
+            )
+            .with(
+                Self::NONPOS,
+                (a.has(Self::NONNEG) && b.has(Self::NONPOS))
+                    || (a.has(Self::NONPOS) && b.has(Self::NONNEG)),
+            )
+            .with(Self::NONZERO, a.has(Self::NONZERO) && b.has(Self::NONZERO))
+    }
+}
+
+impl From<Condition> for Props {
+    fn from(condition: Condition) -> Self {
+        Props(match condition {
+            Positive => Props::POS,
+            Negative => Props::NEG,
+            NonNegative => Props::NONNEG,
+            NonPositive => Props::NONPOS,
+            NonZero => Props::NONZERO,
+            Integer => Props::INTEGER,
+            ConstantOnly => Props::CONST,
+        })
+    }
+}
+
+/// Infer the properties of the first `count` nodes in `nodes`, using
+/// `facts` about the symbols. The nodes are expected to be
+/// topologically sorted, so the properties are computed in a single
+/// pass, without recursion. Both buffers are resized as needed, and
+/// can be reused across calls to avoid allocations.
+pub(crate) fn infer_props(
+    nodes: &[Node],
+    count: usize,
+    facts: &Facts,
+    values: &mut Vec<Option<f64>>,
+    props: &mut Vec<Props>,
+) {
+    values.clear();
+    values.resize(count, None);
+    props.clear();
+    props.resize(count, Props::default());
+    for index in 0..count {
+        values[index] = node_value(&nodes[index], values);
+        props[index] = node_props(&nodes[index], facts, values[index], values, props);
+    }
+}
+
+/// Value of `node` if it is a constant, or an operation on inputs
+/// with known `values`. The inputs of `node` are indices into
+/// `values`.
+pub(crate) fn node_value(node: &Node, values: &[Option<f64>]) -> Option<f64> {
+    match node {
+        Constant(val) => Some(*val),
+        Rational(val) => Some(val.to_f64()),
+        // Named constants are not folded into their values.
+        Named(_) | Symbol(_) => None,
+        Unary(op, input) => values[*input].map(|v| op.apply(v)),
+        Binary(op, lhs, rhs) => match (values[*lhs], values[*rhs]) {
+            (Some(a), Some(b)) => Some(op.apply(a, b)),
+            _ => None,
+        },
+    }
+}
+
+/// Properties of `node` with the given `value`, if known. The inputs
+/// of `node` are indices into `values` and `props`, which hold the
+/// known values and properties of the inputs.
+pub(crate) fn node_props(
+    node: &Node,
+    facts: &Facts,
+    value: Option<f64>,
+    values: &[Option<f64>],
+    props: &[Props],
+) -> Props {
+    if let Some(value) = value {
+        // Constant subtrees are folded and their exact values are
+        // used. Constant subtrees that evaluate to NaN have no
+        // properties at all.
+        return if value.is_nan() {
+            Props::default()
+        } else {
+            Props::of_value(value)
+        };
+    }
+    match node {
+        Constant(val) => Props::of_value(*val),
+        Rational(val) => Props::of_value(val.to_f64()),
+        Named(val) => Props::of_value(val.value()),
+        Symbol(label) => facts.symbol_props(*label),
+        Unary(op, input) => {
+            let a = props[*input];
+            match op {
+                Negate => a.negated(),
+                Sqrt => Props(Props::NONNEG).with(Props::POS, a.has(Props::POS)),
+                Abs => Props(Props::NONNEG)
+                    .with(Props::NONZERO, a.has(Props::NONZERO))
+                    .with(Props::INTEGER, a.has(Props::INTEGER)),
+                Exp => Props(Props::POS),
+                Sin | Cos | Tan | Log => Props::default(),
+            }
+        }
+        Binary(op, lhs, rhs) => {
+            let (a, b) = (props[*lhs], props[*rhs]);
+            let both_int = a.has(Props::INTEGER) && b.has(Props::INTEGER);
+            match op {
+                Add => Props::sum(a, b).with(Props::INTEGER, both_int),
+                Subtract => Props::sum(a, b.negated()).with(Props::INTEGER, both_int),
+                Multiply => Props::product(a, b).with(Props::INTEGER, both_int),
+                Divide => Props::product(a, b),
+                Pow => {
+                    let exponent = values[*rhs];
+                    let even = matches!(exponent, Some(k) if k % 2. == 0.);
+                    let int = matches!(exponent, Some(k) if k.fract() == 0.);
+                    Props::default()
+                        .with(Props::POS, a.has(Props::POS))
+                        .with(Props::NONNEG, a.has(Props::NONNEG) || even)
+                        .with(Props::NONZERO, a.has(Props::NONZERO) && int)
+                        .with(
+                            Props::INTEGER,
+                            a.has(Props::INTEGER)
+                                && matches!(exponent, Some(k) if k >= 0. && k.fract() == 0.),
+                        )
+                }
+                Min => Props::default()
+                    .with(Props::POS, a.has(Props::POS) && b.has(Props::POS))
+                    .with(Props::NONNEG, a.has(Props::NONNEG) && b.has(Props::NONNEG))
+                    .with(Props::NEG, a.has(Props::NEG) || b.has(Props::NEG))
+                    .with(Props::NONPOS, a.has(Props::NONPOS) || b.has(Props::NONPOS))
+                    .with(Props::INTEGER, both_int),
+                Max => Props::default()
+                    .with(Props::POS, a.has(Props::POS) || b.has(Props::POS))
+                    .with(Props::NONNEG, a.has(Props::NONNEG) || b.has(Props::NONNEG))
+                    .with(Props::NEG, a.has(Props::NEG) && b.has(Props::NEG))
+                    .with(Props::NONPOS, a.has(Props::NONPOS) && b.has(Props::NONPOS))
+                    .with(Props::INTEGER, both_int),
+            }
+        }
+    }
+    .complete()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deftree, tree::Tree};
+
+    fn check(tree: Tree, facts: &Facts, expected: &[Condition], unexpected: &[Condition]) {
+        let mut values = Vec::new();
+        let mut props = Vec::new();
+        infer_props(tree.nodes(), tree.len(), facts, &mut values, &mut props);
+        let root = props[tree.root_index()];
+        for c in expected {
+            assert!(root.satisfies(*c), "Expected {:?} for tree:{}", c, tree);
+        }
+        for c in unexpected {
+            assert!(!root.satisfies(*c), "Unexpected {:?} for tree:{}", c, tree);
+        }
+    }
+
+    #[test]
+    fn t_facts_constants() {
+        let facts = Facts::new();
+        check(
+            deftree!(2.),
+            &facts,
+            &[Positive, NonNegative, NonZero, Integer, ConstantOnly],
+            &[Negative, NonPositive],
+        );
+        check(
+            deftree!(-0.5),
+            &facts,
+            &[Negative, NonPositive, NonZero, ConstantOnly],
+            &[Positive, Integer],
+        );
+        check(
+            deftree!(0.),
+            &facts,
+            &[NonNegative, NonPositive, Integer],
+            &[NonZero],
+        );
+        // ConstantOnly subtrees are evaluated.
+        check(deftree!(- 2 (* 1.5 2)), &facts, &[Negative, Integer], &[]);
+        check(
+            deftree!(sqrt(-1)),
+            &facts,
+            &[],
+            &[ConstantOnly, NonNegative],
+        );
+    }
+
+    #[test]
+    fn t_facts_symbols() {
+        let facts = Facts::new().with('x', Positive).with('n', Integer);
+        assert!(facts.holds('x', NonZero));
+        assert!(facts.holds('x', NonNegative));
+        assert!(!facts.holds('y', NonZero));
+        check(
+            deftree!(x),
+            &facts,
+            &[Positive, NonZero],
+            &[ConstantOnly, Integer],
+        );
+        check(
+            deftree!(y),
+            &facts,
+            &[],
+            &[NonZero, NonNegative, ConstantOnly],
+        );
+        check(deftree!(-x), &facts, &[Negative, NonZero], &[]);
+        check(deftree!(+ x (pow y 2)), &facts, &[Positive], &[]);
+        check(deftree!(- x (pow y 2)), &facts, &[], &[Positive, Negative]);
+        check(deftree!(*(-x)(-x)), &facts, &[Positive], &[]);
+        check(deftree!(/ y x), &facts, &[], &[NonZero]);
+        check(deftree!(+ (* n 2) n), &facts, &[Integer], &[NonZero]);
+        check(deftree!(pow n 3), &facts, &[Integer], &[]);
+        check(deftree!(pow n 0.5), &facts, &[], &[Integer]);
+        check(deftree!(pow n (-1)), &facts, &[], &[Integer]);
+        check(
+            deftree!(abs n),
+            &facts,
+            &[NonNegative, Integer],
+            &[Positive],
+        );
+        let facts = Facts::new().with('n', Integer).with('n', NonZero);
+        check(deftree!(abs n), &facts, &[Positive, Integer], &[]);
+    }
+
+    #[test]
+    fn t_facts_functions() {
+        let facts = Facts::new();
+        check(deftree!(exp x), &facts, &[Positive], &[ConstantOnly]);
+        check(deftree!(sqrt x), &facts, &[NonNegative], &[Positive]);
+        check(deftree!(abs x), &facts, &[NonNegative], &[NonZero]);
+        check(deftree!(pow x 2), &facts, &[NonNegative], &[NonZero]);
+        check(deftree!(pow x 3), &facts, &[], &[NonNegative]);
+        check(deftree!(pow (exp x) y), &facts, &[Positive], &[]);
+        check(deftree!(sin x), &facts, &[], &[NonNegative, NonPositive]);
+        check(
+            deftree!(min (exp x) (abs y)),
+            &facts,
+            &[NonNegative],
+            &[Positive],
+        );
+        check(deftree!(max (exp x) y), &facts, &[Positive], &[]);
+        check(deftree!(min (- (exp x)) y), &facts, &[Negative], &[]);
+    }
+}
@@ -1,35 +1,64 @@
-use crate::tree::{BinaryOp::*, Node, Node::*, Tree, TreeError};
+use crate::{
+    exact::Rational,
+    tree::{BinaryOp::*, Node, Node::*, Tree, TreeError},
+};
+
+/// Check if `node` is a constant with the given `value`.
+fn has_value(node: &Node, value: i64) -> bool {
+    match node {
+        Constant(val) => *val == value as f64,
+        Rational(val) => *val == Rational::from_integer(value),
+        Named(_) | Symbol(_) | Unary(..) | Binary(..) => false,
+    }
+}
 
 /// Compute the results of operations on constants and fold those into
-/// constant nodes. The unused nodes after folding are not
+/// constant nodes. Operations on exact rational constants are folded
+/// into rational constants, as long as the result is exact. Otherwise
+/// the result is a floating point constant. Named constants are kept
+/// as they are, like symbols. The unused nodes after folding are not
 /// pruned. Use a pruner for that.
 pub fn fold_nodes(nodes: &mut Vec<Node>) {
     for index in 0..nodes.len() {
         let folded = match nodes[index] {
             Constant(_) => None,
+            Rational(_) => None,
+            Named(_) => None,
             Symbol(_) => None,
-            Unary(op, input) => {
-                if let Constant(value) = nodes[input] {
-                    Some(Constant(op.apply(value)))
-                } else {
-                    None
-                }
-            }
+            Unary(op, input) => match nodes[input] {
+                Constant(value) => Some(Constant(op.apply(value))),
+                Rational(value) => Some(match value.apply_unary(op) {
+                    Some(exact) => Rational(exact),
+                    None => Constant(op.apply(value.to_f64())),
+                }),
+                _ => None,
+            },
             Binary(op, lhs, rhs) => match (op, &nodes[lhs], &nodes[rhs]) {
                 // Constant folding.
                 (op, Constant(a), Constant(b)) => Some(Constant(op.apply(*a, *b))),
+                (op, Rational(a), Rational(b)) => Some(match a.apply_binary(op, b) {
+                    Some(exact) => Rational(exact),
+                    None => Constant(op.apply(a.to_f64(), b.to_f64())),
+                }),
+                (op, Rational(a), Constant(b)) => Some(Constant(op.apply(a.to_f64(), *b))),
+                (op, Constant(a), Rational(b)) => Some(Constant(op.apply(*a, b.to_f64()))),
                 // Identity ops.
-                (Add, lhs, Constant(val)) if *val == 0. => Some(*lhs),
-                (Add, Constant(val), rhs) if *val == 0. => Some(*rhs),
-                (Subtract, lhs, Constant(val)) if *val == 0. => Some(*lhs),
-                (Multiply, lhs, Constant(val)) if *val == 1. => Some(*lhs),
-                (Multiply, Constant(val), rhs) if *val == 1. => Some(*rhs),
-                (Pow, base, Constant(val)) if *val == 1. => Some(*base),
-                (Divide, numerator, Constant(val)) if *val == 1. => Some(*numerator),
+                (Add, lhs, zero) if has_value(zero, 0) => Some(*lhs),
+                (Add, zero, rhs) if has_value(zero, 0) => Some(*rhs),
+                (Subtract, lhs, zero) if has_value(zero, 0) => Some(*lhs),
+                (Multiply, lhs, one) if has_value(one, 1) => Some(*lhs),
+                (Multiply, one, rhs) if has_value(one, 1) => Some(*rhs),
+                (Pow, base, one) if has_value(one, 1) => Some(*base),
+                (Divide, numerator, one) if has_value(one, 1) => Some(*numerator),
                 // Other ops.
+                (Pow, _base, Rational(zero)) if zero.is_zero() => {
+                    Some(Rational(Rational::from_integer(1)))
+                }
                 (Pow, _base, Constant(val)) if *val == 0. => Some(Constant(1.)),
                 (Multiply, _lhs, Constant(val)) if *val == 0. => Some(Constant(0.)),
                 (Multiply, Constant(val), _rhs) if *val == 0. => Some(Constant(0.)),
+                (Multiply, _lhs, Rational(zero)) if zero.is_zero() => Some(Rational(*zero)),
+                (Multiply, Rational(zero), _rhs) if zero.is_zero() => Some(Rational(*zero)),
                 _ => None,
             },
         };
@@ -80,6 +109,60 @@ mod test {
         compare_trees(&tree, &expected, &[('x', 0.1, 10.)], 100, 0.);
     }
 
+    #[test]
+    fn t_rational_folding() {
+        let mut pruner = Pruner::new();
+        let r = |num, den| Tree::rational(Rational::from_fraction(num, den).unwrap());
+        let fold = |tree: Tree, pruner: &mut Pruner| tree.fold().unwrap().prune(pruner);
+        // Exact inputs give exact results.
+        assert_eq!(fold(deftree!(+ {r(1, 3)} {r(1, 6)}), &mut pruner), r(1, 2));
+        let tenth = r(1, 10);
+        let sum = (1..10).fold(tenth.clone(), |acc, _| acc + tenth.clone());
+        assert_eq!(fold(sum, &mut pruner), r(1, 1));
+        assert_eq!(
+            fold(deftree!(pow (- {r(2, 3)}) {r(-2, 1)}), &mut pruner).to_lisp(),
+            "9/4"
+        );
+        // Anything else falls back to floating point.
+        assert_eq!(
+            fold(deftree!(sqrt {r(1, 4)}), &mut pruner),
+            Tree::constant(0.5)
+        );
+        assert_eq!(
+            fold(deftree!(* {r(1, 2)} 3), &mut pruner),
+            Tree::constant(1.5)
+        );
+        assert_eq!(
+            fold(deftree!(/ {r(1, 2)} {r(0, 1)}), &mut pruner),
+            Tree::constant(f64::INFINITY)
+        );
+        // Identity ops.
+        assert_eq!(
+            fold(deftree!(+ (* x {r(1, 1)}) {r(0, 1)}), &mut pruner),
+            deftree!(x)
+        );
+        assert_eq!(fold(deftree!(pow x {r(0, 1)}), &mut pruner), r(1, 1));
+    }
+
+    #[test]
+    fn t_named_folding() {
+        let mut pruner = Pruner::new();
+        assert_eq!(
+            deftree!(+ (* (+ 1 1) (@ pi)) (* (@ e) 1))
+                .fold()
+                .unwrap()
+                .prune(&mut pruner),
+            deftree!(+ (* 2 (@ pi)) (@ e))
+        );
+        assert_eq!(
+            deftree!(* (sin (@ pi)) 0)
+                .fold()
+                .unwrap()
+                .prune(&mut pruner),
+            deftree!(0)
+        );
+    }
+
     #[test]
     fn t_add_zero() {
         let mut pruner = Pruner::new();
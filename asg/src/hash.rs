@@ -1,25 +1,71 @@
 use crate::tree::{Node, Node::*, Tree};
+use num_traits::Signed;
+
+/*
+Trees are hashed bottom up. The hash of a node is computed from a
+sequence of 64 bit words: a tag identifying the kind of node, followed
+by its value, label or operator, and the hashes of its inputs:
+
+    Constant:  0, bits of the value, with -0 hashed as 0
+    Rational:  4, 1 if negative else 0, number of words in the
+               numerator, the words of the absolute values of the
+               numerator and the denominator, least significant first
+    Named:     5, number of bytes in the name, the bytes of the name
+    Symbol:    1, label as a unicode scalar value
+    Unary:     2, index of the operator, hash of the input
+    Binary:    3, index of the operator, hashes of the inputs
+
+The index of an operator is the one given by `UnaryOp::index` or
+`BinaryOp::index`.
+The inputs of commutative operators are hashed in ascending order of
+their hashes, so that mirrored trees have the same hash. The words
+are hashed with 64 bit FNV-1a, one little endian byte at a time.
+
+Unlike the hashers in the standard library, this is guaranteed to
+produce the same hashes on every platform and toolchain, so anything
+that depends on the order of hashes is reproducible. Hashes only
+change if the format described above changes.
+*/
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(words: &[u64]) -> u64 {
+    words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
 
 pub fn hash_nodes(nodes: &[Node], hashbuf: &mut Vec<u64>) {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
     // Using a boxed slice to avoid accidental resizing later.
     hashbuf.clear();
     hashbuf.resize(nodes.len(), 0);
     for index in 0..nodes.len() {
         let hash: u64 = match nodes[index] {
-            Constant(value) => value.to_bits().into(),
-            Symbol(label) => {
-                let mut s: DefaultHasher = Default::default();
-                label.hash(&mut s);
-                s.finish()
+            Constant(value) => {
+                // -0 and 0 are equivalent, so they must have the same hash.
+                let value = if value == 0. { 0. } else { value };
+                fnv1a(&[0, value.to_bits()])
             }
-            Unary(op, input) => {
-                let mut s: DefaultHasher = Default::default();
-                op.hash(&mut s);
-                hashbuf[input].hash(&mut s);
-                s.finish()
+            Rational(value) => {
+                let value = value.value();
+                let num = value.numer().magnitude().to_u64_digits();
+                let mut words = vec![4, value.is_negative() as u64, num.len() as u64];
+                words.extend(num);
+                words.extend(value.denom().magnitude().to_u64_digits());
+                fnv1a(&words)
             }
+            Named(value) => {
+                let name = value.name();
+                let mut words = vec![5, name.len() as u64];
+                words.extend(name.bytes().map(u64::from));
+                fnv1a(&words)
+            }
+            Symbol(label) => fnv1a(&[1, label as u64]),
+            Unary(op, input) => fnv1a(&[2, op.index() as u64, hashbuf[input]]),
             Binary(op, lhs, rhs) => {
                 let (hash1, hash2) = {
                     let mut hash1 = hashbuf[lhs];
@@ -29,11 +75,7 @@ pub fn hash_nodes(nodes: &[Node], hashbuf: &mut Vec<u64>) {
                     }
                     (hash1, hash2)
                 };
-                let mut s: DefaultHasher = Default::default();
-                op.hash(&mut s);
-                hash1.hash(&mut s);
-                hash2.hash(&mut s);
-                s.finish()
+                fnv1a(&[3, op.index() as u64, hash1, hash2])
             }
         };
         hashbuf[index] = hash;
@@ -41,8 +83,41 @@ pub fn hash_nodes(nodes: &[Node], hashbuf: &mut Vec<u64>) {
 }
 
 impl Tree {
+    /// Stable hash of this tree. See the top of this module for how
+    /// it is computed. Equivalent trees have the same hash, but trees
+    /// with the same hash are not necessarily equivalent.
     pub fn hash(&self, hashbuf: &mut Vec<u64>) -> u64 {
         hash_nodes(self.nodes(), hashbuf);
         return hashbuf[self.root_index()];
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{deftree, tree::Tree};
+
+    #[test]
+    fn t_stable_hash() {
+        let mut hashbuf = Vec::new();
+        let tree = deftree!(+ (sin x) (* 2 y));
+        let mirrored = deftree!(+ (* y 2) (sin x));
+        assert_eq!(tree.hash(&mut hashbuf), mirrored.hash(&mut hashbuf));
+        assert_ne!(
+            tree.hash(&mut hashbuf),
+            deftree!(- (sin x) (* 2 y)).hash(&mut hashbuf)
+        );
+        // These values must never change, or hashes will not be
+        // reproducible.
+        assert_eq!(deftree!(x).hash(&mut hashbuf), 18446062289296720732);
+        assert_eq!(tree.hash(&mut hashbuf), 3839640067279183325);
+    }
+
+    #[test]
+    fn t_hash_negative_zero() {
+        let mut hashbuf = Vec::new();
+        let zero = Tree::symbol('x') + Tree::constant(0.);
+        let negzero = Tree::symbol('x') + Tree::constant(-0.);
+        assert!(zero.equivalent(&negzero));
+        assert_eq!(zero.hash(&mut hashbuf), negzero.hash(&mut hashbuf));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     tree::{Node, Node::*, Tree},
-    walk::{DepthWalker, NodeOrdering},
+    walk::DepthWalker,
 };
 
 impl std::fmt::Display for Tree {
@@ -21,7 +21,7 @@ impl std::fmt::Display for Tree {
                 let mut tokens: Vec<Token> = Vec::with_capacity(self.len()); // Likely need more memory.
                 let mut walker = DepthWalker::new();
                 let mut node_depths: Box<[usize]> = vec![0; self.len()].into_boxed_slice();
-                for (index, parent) in walker.walk_tree(self, false, NodeOrdering::Original) {
+                for (index, parent) in walker.walk_tree(self, false) {
                     if let Some(pi) = parent {
                         node_depths[index] = node_depths[pi] + 1;
                     }
@@ -81,6 +81,8 @@ impl std::fmt::Display for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Constant(value) => write!(f, "Constant({})", value),
+            Rational(value) => write!(f, "Rational({})", value),
+            Named(value) => write!(f, "Named({})", value),
             Symbol(label) => write!(f, "Symbol({})", label),
             Unary(op, input) => write!(f, "{:?}({})", op, input),
             Binary(op, lhs, rhs) => write!(f, "{:?}({}, {})", op, lhs, rhs),
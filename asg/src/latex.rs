@@ -1,4 +1,7 @@
-use crate::tree::{BinaryOp, BinaryOp::*, Node, Node::*, Tree, UnaryOp::*};
+use crate::{
+    reduce::Step,
+    tree::{BinaryOp, BinaryOp::*, Node, Node::*, Tree, UnaryOp::*},
+};
 
 impl Tree {
     pub fn to_latex(&self) -> String {
@@ -6,9 +9,29 @@ impl Tree {
     }
 }
 
+impl Step {
+    /// The tree after this step, annotated with the name of the
+    /// template that was applied.
+    pub fn to_latex(&self) -> String {
+        format!(
+            "{} \\qquad \\text{{({})}}",
+            self.tree.to_latex(),
+            self.rule.replace('_', "\\_")
+        )
+    }
+}
+
 fn to_latex(node: &Node, nodes: &[Node]) -> String {
     match node {
         Constant(val) => val.to_string(),
+        Rational(val) if val.is_integer() => val.numer().to_string(),
+        Rational(val) => format!(
+            "{}\\dfrac{{{}}}{{{}}}",
+            if val.is_negative() { "-" } else { "" },
+            val.numer().magnitude(),
+            val.denom()
+        ),
+        Named(val) => val.latex().to_string(),
         Symbol(label) => label.to_string(),
         Unary(op, i) => {
             let inode = &nodes[*i];
@@ -18,7 +41,8 @@ fn to_latex(node: &Node, nodes: &[Node]) -> String {
                     match inode {
                         // Special cases that require braces.
                         Binary(Add, ..) | Binary(Subtract, ..) => with_parens(ix),
-                        Constant(_) | Symbol(_) | Unary(..) | Binary(..) => ix,
+                        Constant(_) | Rational(_) | Named(_) | Symbol(_) | Unary(..)
+                        | Binary(..) => ix,
                     }
                 }),
                 Sqrt => format!("\\sqrt{{{}}}", ix),
@@ -29,9 +53,13 @@ fn to_latex(node: &Node, nodes: &[Node]) -> String {
                 Log => format!("\\ln\\left({{{}}}\\right)", ix),
                 Exp => format!("e^{{{}}}", {
                     match inode {
-                        Constant(_) | Symbol(_) | Unary(..) | Binary(Min, ..) | Binary(Max, ..) => {
-                            ix
-                        }
+                        Constant(_)
+                        | Rational(_)
+                        | Named(_)
+                        | Symbol(_)
+                        | Unary(..)
+                        | Binary(Min, ..)
+                        | Binary(Max, ..) => ix,
                         Binary(..) => with_parens(ix),
                     }
                 }),
@@ -82,14 +110,19 @@ fn parens_binary(
                     | Unary(Log, _)
                     | Unary(Exp, _)
                     | Binary(..) => with_parens(lx),
-                    Constant(_) if lx.len() > 1 => with_parens(lx),
-                    Constant(_) | Symbol(_) | Unary(_, _) => lx,
+                    Constant(_) | Rational(_) if lx.len() > 1 => with_parens(lx),
+                    Constant(_) | Rational(_) | Named(_) | Symbol(_) | Unary(_, _) => lx,
                 }
             },
             {
                 match rnode {
                     Binary(Add, ..) | Binary(Subtract, ..) => with_parens(rx),
-                    Constant(_) | Symbol(_) | Unary(_, _) | Binary(_, _, _) => rx,
+                    Constant(_)
+                    | Rational(_)
+                    | Named(_)
+                    | Symbol(_)
+                    | Unary(_, _)
+                    | Binary(_, _, _) => rx,
                 }
             },
         ),
@@ -109,14 +142,14 @@ fn parens_mul(node: &Node, latex: String) -> String {
         Binary(Add, ..) | Binary(Subtract, ..) | Binary(Multiply, ..) | Unary(Negate, ..) => {
             with_parens(latex)
         }
-        Binary(..) | Unary(..) | Symbol(_) | Constant(_) => latex,
+        Binary(..) | Unary(..) | Symbol(_) | Constant(_) | Rational(_) | Named(_) => latex,
     }
 }
 
 fn parens_add_sub(node: &Node, latex: String) -> String {
     match node {
         Binary(Add, ..) | Binary(Subtract, ..) | Unary(Negate, _) => with_parens(latex),
-        Binary(..) | Constant(_) | Symbol(_) | Unary(..) => latex,
+        Binary(..) | Constant(_) | Rational(_) | Named(_) | Symbol(_) | Unary(..) => latex,
     }
 }
 
@@ -129,8 +162,12 @@ mod test {
     use crate::{
         dedup::Deduplicater,
         deftree,
+        exact::Rational,
         mutate::{Mutations, TemplateCapture},
         prune::Pruner,
+        reduce::Step,
+        template::RuleSet,
+        tree::Tree,
     };
 
     #[test]
@@ -239,6 +276,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn t_rational() {
+        let rational = |num, den| Tree::rational(Rational::from_fraction(num, den).unwrap());
+        assert_eq!("\\dfrac{1}{3}", rational(1, 3).to_latex());
+        assert_eq!("-\\dfrac{2}{5}", rational(-2, 5).to_latex());
+        assert_eq!("3", rational(6, 2).to_latex());
+        assert_eq!(
+            "{\\left(\\dfrac{1}{2}\\right)}^{x}",
+            deftree!(pow {rational(1, 2)} x).to_latex()
+        );
+    }
+
+    #[test]
+    fn t_named() {
+        assert_eq!("\\pi", deftree!(@ pi).to_latex());
+        assert_eq!(
+            "\\sin\\left({{2}.{\\pi}}\\right)",
+            deftree!(sin (* 2 (@ pi))).to_latex()
+        );
+        assert_eq!("{e}^{x}", deftree!(pow (@ e) x).to_latex());
+    }
+
     #[test]
     fn t_mutations_latex() {
         let mut dedup = Deduplicater::new();
@@ -248,7 +307,8 @@ mod test {
             .unwrap()
             .prune(&mut pruner);
         let mut capture = TemplateCapture::new();
-        for m in Mutations::of(&tree, &mut capture) {
+        let rules = RuleSet::new();
+        for m in Mutations::of(&tree, &rules, &mut capture) {
             match m {
                 Ok(mutated) => {
                     assert_ne!(mutated, tree);
@@ -257,4 +317,18 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn t_step_latex() {
+        let step = Step {
+            tree: deftree!(* k x),
+            rule: "distribute_mul".to_string(),
+            node_index: 0,
+            bindings: vec![],
+        };
+        assert_eq!(
+            step.to_latex(),
+            "{k}.{x} \\qquad \\text{(distribute\\_mul)}"
+        );
+    }
 }
@@ -1,8 +1,24 @@
+pub mod binary;
+pub mod canonical;
+pub mod certificate;
+pub mod cost;
 pub mod eval;
+pub mod exact;
+pub mod facts;
+pub mod named;
+pub mod nary;
+pub mod parse;
+pub mod poly;
+pub mod rational;
 pub mod reduce;
+pub mod template;
 pub mod tree;
+pub mod verify;
 
 mod dedup;
+mod egraph;
+mod expand;
+mod factor;
 mod fold;
 mod hash;
 mod io;
@@ -10,8 +26,8 @@ mod latex;
 mod macros;
 mod mutate;
 mod prune;
+mod search;
 mod sort;
-mod template;
 mod walk;
 
 #[cfg(test)]
@@ -33,6 +33,14 @@ macro_rules! deftree {
     ($op:tt $lhs:tt $rhs:tt) => {
         $crate::deftree!($lhs) $op $crate::deftree!($rhs)
     };
+    // Named constants. They must be defined before the tree is
+    // constructed.
+    (@ $name:ident) => {
+        $crate::tree::Tree::named(
+            $crate::named::NamedConstant::get(stringify!($name))
+                .expect("Undefined named constant."),
+        )
+    };
     // Symbols.
     ($a:ident) => {{
         const LABEL: &str = stringify!($a);
@@ -69,6 +77,24 @@ mod test {
         assert_eq!(tree.root(), &Constant(2.));
     }
 
+    #[test]
+    fn t_named_deftree() {
+        use crate::named::NamedConstant;
+        let tree = deftree!(@ pi);
+        assert_eq!(tree.root(), &Named(NamedConstant::PI));
+        let tree = deftree!(* 2 (pow (@ e) x));
+        assert_eq!(
+            tree.nodes(),
+            &vec![
+                Constant(2.),
+                Named(NamedConstant::E),
+                Symbol('x'),
+                Binary(Pow, 1, 2),
+                Binary(Multiply, 0, 3)
+            ]
+        );
+    }
+
     #[test]
     fn t_negate_deftree() {
         let tree = deftree!(-x);
@@ -1,10 +1,13 @@
+use std::collections::HashSet;
+
 use crate::{
     dedup::Deduplicater,
+    facts::{infer_props, Facts, Props},
     fold::fold_nodes,
     prune::Pruner,
     sort::{TopoSorter, TopologicalError},
-    template::{get_templates, Template},
-    tree::{Node, Tree, TreeError},
+    template::{RuleSet, Template},
+    tree::{BinaryOp, Node, Tree, TreeError},
 };
 
 #[derive(Debug)]
@@ -15,27 +18,54 @@ pub enum MutationError {
     TreeCreationError(TreeError),
 }
 
-pub struct Mutations<'a> {
+/// A template that matched a tree, the index of the node of the tree
+/// that matched its ping, and the indices of the nodes its symbols
+/// were bound to.
+#[derive(Clone)]
+pub struct Match<'r> {
+    pub template: &'r Template,
+    pub node_index: usize,
+    pub bindings: Vec<(char, usize)>,
+}
+
+pub struct Mutations<'a, 'r> {
     tree: &'a Tree,
+    rules: &'r RuleSet,
     capture: &'a mut TemplateCapture,
     template_index: usize,
 }
 
-impl<'a> Mutations<'a> {
-    pub fn of(tree: &'a Tree, capture: &'a mut TemplateCapture) -> Mutations<'a> {
+impl<'a, 'r> Mutations<'a, 'r> {
+    /// Get an iterator over all the trees that can be produced by
+    /// applying one template from `rules` to `tree`.
+    pub fn of(
+        tree: &'a Tree,
+        rules: &'r RuleSet,
+        capture: &'a mut TemplateCapture,
+    ) -> Mutations<'a, 'r> {
         Mutations {
             tree,
+            rules,
             capture,
             template_index: 0,
         }
     }
+
+    /// The match that produced the last mutation.
+    pub fn last_match(&self) -> Option<Match<'r>> {
+        Some(Match {
+            template: self.rules.templates().get(self.template_index)?,
+            node_index: self.capture.node_index?,
+            bindings: self.capture.bindings().clone(),
+        })
+    }
 }
 
-impl<'a> Iterator for Mutations<'a> {
+impl Iterator for Mutations<'_, '_> {
     type Item = Result<Tree, MutationError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let templates = get_templates();
+        let templates = self.rules.templates();
         while self.template_index < templates.len() {
             let template = &templates[self.template_index];
             while self.capture.next_match(template, self.tree) {
@@ -50,42 +80,105 @@ impl<'a> Iterator for Mutations<'a> {
 pub struct TemplateCapture {
     node_index: Option<usize>,
     bindings: Vec<(char, usize)>,
+    constants: Vec<char>,
     node_map: Vec<usize>,
     topo_sorter: TopoSorter,
     pruner: Pruner,
     deduper: Deduplicater,
+    facts: Facts,
+    values: Vec<Option<f64>>,
+    props: Vec<Props>,
+    /// Operator of the last match, if it was matched regardless of
+    /// how the operands were nested. See `match_template`.
+    ac_op: Option<BinaryOp>,
+    /// Operands of the ping and the tree, for AC matching.
+    pattern_operands: Vec<usize>,
+    operands: Vec<usize>,
+    used: Vec<bool>,
+    /// Operands of the tree matched with each operand of the ping by
+    /// the last AC match, with the number of bindings before each
+    /// operand was matched, so the search can resume from there.
+    choices: Vec<(usize, usize)>,
+    /// Operands of the tree used by each AC match found at the
+    /// current node, as bitsets.
+    groups: HashSet<Vec<u64>>,
+    /// Maximum number of AC matches to find at each node.
+    max_groupings: Option<usize>,
+    /// Operands of the tree not matched by the last AC match.
+    rest: Vec<usize>,
+    /// Whether each node of the tree is the top of a chain of the
+    /// same operation, i.e. not an operand of the same operation. The
+    /// operands are only regrouped at the top of each chain, which
+    /// finds every grouping found at the inner nodes too.
+    chain_tops: Vec<bool>,
 }
 
 impl TemplateCapture {
     pub fn new() -> TemplateCapture {
+        TemplateCapture::with_facts(Facts::new())
+    }
+
+    /// Create a new capture, that checks the guards of templates
+    /// against the given `facts`.
+    pub fn with_facts(facts: Facts) -> TemplateCapture {
         TemplateCapture {
             node_index: None,
             bindings: vec![],
+            constants: vec![],
             node_map: vec![],
             topo_sorter: TopoSorter::new(),
             pruner: Pruner::new(),
             deduper: Deduplicater::new(),
+            facts,
+            values: vec![],
+            props: vec![],
+            ac_op: None,
+            pattern_operands: vec![],
+            operands: vec![],
+            used: vec![],
+            choices: vec![],
+            groups: HashSet::new(),
+            max_groupings: None,
+            rest: vec![],
+            chain_tops: vec![],
         }
     }
 
+    /// Find at most `max` ways of grouping the operands of each sum or
+    /// product. `None` means there is no limit.
+    pub fn set_max_groupings(&mut self, max: Option<usize>) {
+        self.max_groupings = max;
+    }
+
     pub fn bindings(&self) -> &Vec<(char, usize)> {
         &self.bindings
     }
 
+    /// Find the next match of `template` in `tree`. If the last match
+    /// regrouped the operands of a sum or a product, the other ways
+    /// of grouping the operands of the same node are found first,
+    /// before moving on to the next node.
     pub fn next_match(&mut self, template: &Template, tree: &Tree) -> bool {
         // Set self.node_index to None and choose the starting index
         // based on what was in self.node_index before setting it to None.
         let start: usize = match std::mem::replace(&mut self.node_index, None) {
+            Some(i) if self.ac_op.is_some() && self.match_operands(template, tree, true) => {
+                self.node_index = Some(i);
+                return true;
+            }
             Some(i) => i + 1,
             None => 0,
         };
         if start >= tree.len() {
             return false;
         }
+        self.constants.clear();
+        self.constants.extend_from_slice(template.constants());
+        if start == 0 || self.chain_tops.len() != tree.len() {
+            find_chain_tops(tree.nodes(), &mut self.chain_tops);
+        }
         for i in start..tree.len() {
-            // Clear any previous bindings to start over fresh.
-            self.bindings.clear();
-            if self.match_node(template.ping().root_index(), template.ping(), i, tree) {
+            if self.match_template(template, tree, i, self.chain_tops[i]) {
                 self.node_index = Some(i);
                 return true;
             }
@@ -93,6 +186,181 @@ impl TemplateCapture {
         return false;
     }
 
+    /// Find the next match of `template` at the same node as the last
+    /// match, i.e. the next way of grouping the operands of the sum or
+    /// product at that node. Returns false if there are no other
+    /// groupings.
+    pub fn next_grouping(&mut self, template: &Template, tree: &Tree) -> bool {
+        if self.node_index.is_some()
+            && self.ac_op.is_some()
+            && self.match_operands(template, tree, true)
+        {
+            return true;
+        }
+        self.node_index = None;
+        false
+    }
+
+    /// Match `template` with the subtree rooted at the node with
+    /// `index` in `tree`, and no other node. Returns true if the ping
+    /// matches, and the guards of the template are satisfied.
+    pub fn match_at(&mut self, template: &Template, tree: &Tree, index: usize) -> bool {
+        self.node_index = None;
+        self.bindings.clear();
+        if index >= tree.len() {
+            return false;
+        }
+        self.constants.clear();
+        self.constants.extend_from_slice(template.constants());
+        if self.match_template(template, tree, index, true) {
+            self.node_index = Some(index);
+            return true;
+        }
+        false
+    }
+
+    /// Match `template` with the subtree rooted at the node with
+    /// `index` in `tree`, and check its guards.
+    ///
+    /// If that fails, `regroup` is true, and the roots of the ping and the subtree are
+    /// the same associative and commutative operation, such as a sum,
+    /// the operands of the ping are matched with the operands of the
+    /// subtree regardless of how they are nested. For example, `(+ (*
+    /// k a) (* k b))` matches `(+ (+ (* k x) c) (* k y))`. Operands of
+    /// the subtree that are not matched are kept, and combined with
+    /// the pong when the template is applied.
+    fn match_template(
+        &mut self,
+        template: &Template,
+        tree: &Tree,
+        index: usize,
+        regroup: bool,
+    ) -> bool {
+        // Clear any previous bindings to start over fresh.
+        self.bindings.clear();
+        self.ac_op = None;
+        let ping = template.ping();
+        if self.match_node(ping.root_index(), ping, index, tree)
+            && self.check_guards(template, tree)
+        {
+            return true;
+        }
+        if !regroup {
+            return false;
+        }
+        let op = match (ping.root(), tree.node(index)) {
+            (Node::Binary(lop, ..), Node::Binary(rop, ..))
+                if lop == rop && lop.is_associative() && lop.is_commutative() =>
+            {
+                *lop
+            }
+            _ => return false,
+        };
+        flatten(
+            op,
+            ping.root_index(),
+            ping.nodes(),
+            &mut self.pattern_operands,
+        );
+        flatten(op, index, tree.nodes(), &mut self.operands);
+        if self.operands.len() < 3 || self.pattern_operands.len() > self.operands.len() {
+            // Regrouping can't make a difference.
+            return false;
+        }
+        self.bindings.clear();
+        self.used.clear();
+        self.used.resize(self.operands.len(), false);
+        self.choices.clear();
+        self.groups.clear();
+        if !self.match_operands(template, tree, false) {
+            return false;
+        }
+        self.ac_op = Some(op);
+        true
+    }
+
+    /// Match each operand of the ping with a different operand of the
+    /// tree. The operands are assigned by backtracking, in order, so
+    /// every grouping of the operands of the tree is found exactly
+    /// once. Like commuted operands in `match_node`, only the first
+    /// assignment that matches a group of operands is used. If
+    /// `resume` is true, the search continues after the last
+    /// assignment that was found. Returns false when there are no
+    /// more groupings, or `max_groupings` were found.
+    fn match_operands(&mut self, template: &Template, tree: &Tree, resume: bool) -> bool {
+        if self
+            .max_groupings
+            .is_some_and(|max| self.groups.len() >= max)
+        {
+            return false;
+        }
+        // Operand of the tree to try next for the current operand of
+        // the ping.
+        let mut k = 0;
+        if resume {
+            match self.unchoose() {
+                Some(last) => k = last + 1,
+                None => return false,
+            }
+        }
+        loop {
+            let pi = self.choices.len();
+            if pi == self.pattern_operands.len() {
+                let mut group = vec![0u64; self.used.len().div_ceil(64)];
+                for (k, used) in self.used.iter().enumerate() {
+                    if *used {
+                        group[k / 64] |= 1 << (k % 64);
+                    }
+                }
+                if !self.groups.contains(&group) && self.check_guards(template, tree) {
+                    self.groups.insert(group);
+                    self.rest.clear();
+                    self.rest.extend(
+                        self.operands
+                            .iter()
+                            .zip(self.used.iter())
+                            .filter_map(|(i, used)| if *used { None } else { Some(*i) }),
+                    );
+                    return true;
+                }
+            } else {
+                let pattern = self.pattern_operands[pi];
+                let mut found = None;
+                while k < self.operands.len() {
+                    if !self.used[k] {
+                        let cpt = self.checkpoint();
+                        if self.match_node(pattern, template.ping(), self.operands[k], tree) {
+                            found = Some(cpt);
+                            break;
+                        }
+                        self.restore(cpt);
+                    }
+                    k += 1;
+                }
+                if let Some(cpt) = found {
+                    self.used[k] = true;
+                    self.choices.push((k, cpt));
+                    k = 0;
+                    continue;
+                }
+            }
+            // Backtrack.
+            match self.unchoose() {
+                Some(last) => k = last + 1,
+                None => return false,
+            }
+        }
+    }
+
+    /// Undo the last choice made by `match_operands`, and return the
+    /// operand of the tree that was chosen.
+    fn unchoose(&mut self) -> Option<usize> {
+        let (k, cpt) = self.choices.pop()?;
+        self.used[k] = false;
+        self.restore(cpt);
+        Some(k)
+    }
+
     pub fn make_compact_tree(
         &mut self,
         mut tree: Tree,
@@ -114,6 +382,33 @@ impl TemplateCapture {
             .map_err(|e| MutationError::TreeCreationError(e));
     }
 
+    /// Check if the subtrees bound to the symbols of `template`
+    /// satisfy all its guards.
+    fn check_guards(&mut self, template: &Template, tree: &Tree) -> bool {
+        if template.guards().is_empty() {
+            return true;
+        }
+        // Nodes are topologically sorted, so only the nodes up to the
+        // last bound node need to be analyzed.
+        let count = match self.bindings.iter().map(|(_, i)| *i).max() {
+            Some(i) => i + 1,
+            None => return true,
+        };
+        infer_props(
+            tree.nodes(),
+            count,
+            &self.facts,
+            &mut self.values,
+            &mut self.props,
+        );
+        template.guards().iter().all(|(label, condition)| {
+            match self.bindings.iter().find(|(l, _)| l == label) {
+                Some((_, i)) => self.props[*i].satisfies(*condition),
+                None => false,
+            }
+        })
+    }
+
     fn bind(&mut self, label: char, index: usize) -> bool {
         for (l, i) in self.bindings.iter() {
             if *l == label {
@@ -137,7 +432,8 @@ impl TemplateCapture {
         self.bindings.truncate(state);
     }
 
-    fn apply(&mut self, template: &Template, tree: &Tree) -> Result<Tree, MutationError> {
+    /// Apply `template` to `tree` at the last match.
+    pub fn apply(&mut self, template: &Template, tree: &Tree) -> Result<Tree, MutationError> {
         use crate::tree::Node::*;
         let mut tree = tree.clone();
         let root_index = tree.root_index();
@@ -153,6 +449,8 @@ impl TemplateCapture {
         for ni in 0..pong.len() {
             match pong.node(ni) {
                 Constant(val) => self.add_node(tree.nodes_mut(), ni, Constant(*val)),
+                Rational(val) => self.add_node(tree.nodes_mut(), ni, Rational(*val)),
+                Named(val) => self.add_node(tree.nodes_mut(), ni, Named(*val)),
                 Symbol(label) => match self.bindings.iter().find(|(ch, _i)| *ch == *label) {
                     Some((_ch, i)) => self.node_map[ni] = *i,
                     None => return Err(MutationError::UnboundSymbol),
@@ -170,6 +468,14 @@ impl TemplateCapture {
                 newroot = self.node_map[ni];
             }
         }
+        if let Some(op) = self.ac_op {
+            // Keep the operands that were not matched.
+            for &i in self.rest.iter() {
+                let node = Binary(op, newroot, i);
+                newroot = tree.len();
+                tree.nodes_mut().push(node);
+            }
+        }
         // Rewire old pattern root to the new pattern root. Only
         // iterate over the preexisting nodes, not the ones we just
         // added.
@@ -177,7 +483,7 @@ impl TemplateCapture {
             match tree.nodes_mut().get_mut(i) {
                 Some(node) => {
                     match node {
-                        Constant(_) | Symbol(_) => {} // Do nothing.
+                        Constant(_) | Rational(_) | Named(_) | Symbol(_) => {} // Do nothing.
                         Unary(_, input) => {
                             if *input == oldroot {
                                 *input = newroot;
@@ -196,7 +502,8 @@ impl TemplateCapture {
                 None => {}
             }
         }
-        // Clean up and make a tree.
+        // Clean up and make a tree. Operations on constants,
+        // i.e. computed constants of the pong, are folded here.
         return self.make_compact_tree(
             tree,
             Some(if oldroot == root_index {
@@ -231,7 +538,17 @@ impl TemplateCapture {
     ) -> (bool, bool) {
         match (ltree.node(li), rtree.node(ri)) {
             (Node::Constant(v1), Node::Constant(v2)) => (v1 == v2, false),
+            (Node::Constant(v1), Node::Rational(v2)) => (v2.equals_f64(*v1), false),
             (Node::Constant(_), _) => return (false, false),
+            (Node::Rational(v1), Node::Rational(v2)) => (v1 == v2, false),
+            (Node::Rational(v1), Node::Constant(v2)) => (v1.equals_f64(*v2), false),
+            (Node::Rational(_), _) => (false, false),
+            (Node::Named(v1), Node::Named(v2)) => (v1 == v2, false),
+            (Node::Named(_), _) => (false, false),
+            (Node::Symbol(label), Node::Constant(_) | Node::Rational(_)) => {
+                (self.bind(*label, ri), false)
+            }
+            (Node::Symbol(label), _) if self.constants.contains(label) => (false, false),
             (Node::Symbol(label), _) => return (self.bind(*label, ri), false),
             (Node::Unary(lop, input1), Node::Unary(rop, input2)) => {
                 if lop != rop {
@@ -272,13 +589,74 @@ impl TemplateCapture {
     }
 }
 
+/// Set `tops[i]` to false for each node that is an associative and
+/// commutative operation whose parents are all the same operation,
+/// and true for every other node.
+fn find_chain_tops(nodes: &[Node], tops: &mut Vec<bool>) {
+    tops.clear();
+    tops.resize(nodes.len(), false);
+    let mut has_parent = vec![false; nodes.len()];
+    for node in nodes {
+        match node {
+            Node::Unary(_, input) => {
+                has_parent[*input] = true;
+                tops[*input] = true;
+            }
+            Node::Binary(op, lhs, rhs) => {
+                for &i in [*lhs, *rhs].iter() {
+                    has_parent[i] = true;
+                    let same = match nodes[i] {
+                        Node::Binary(iop, ..) => iop == *op,
+                        _ => false,
+                    };
+                    if !same || !op.is_associative() || !op.is_commutative() {
+                        tops[i] = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for (top, has_parent) in tops.iter_mut().zip(has_parent) {
+        *top |= !has_parent;
+    }
+}
+
+/// Collect the operands of the chain of `op` nodes rooted at `index`,
+/// from left to right, in `out`.
+fn flatten(op: BinaryOp, index: usize, nodes: &[Node], out: &mut Vec<usize>) {
+    out.clear();
+    let mut stack = vec![index];
+    while let Some(i) = stack.pop() {
+        match nodes[i] {
+            Node::Binary(iop, lhs, rhs) if iop == op => {
+                stack.push(rhs);
+                stack.push(lhs);
+            }
+            _ => out.push(i),
+        }
+    }
+}
+
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
     use crate::{
-        dedup::equivalent, deftree, template::test::get_template_by_name, walk::DepthWalker,
+        dedup::equivalent, deftree, exact::Rational, facts::Condition,
+        template::test::get_template_by_name, walk::DepthWalker,
     };
 
+    /// Assume all symbols with the given `labels` are positive. This
+    /// keeps the guards of the templates out of the way, in tests
+    /// that are about matching and mutating trees.
+    pub fn assume_positive(labels: &[char]) -> Facts {
+        let mut facts = Facts::new();
+        for label in labels {
+            facts.assume(*label, Condition::Positive);
+        }
+        facts
+    }
+
     fn t_check_bindings(capture: &TemplateCapture, template: &Template, tree: &Tree) {
         let left: Vec<_> = {
             let mut chars: Vec<_> = capture.bindings.iter().map(|(c, _i)| *c).collect();
@@ -346,7 +724,7 @@ mod test {
         let mut dedup = Deduplicater::new();
         let mut pruner = Pruner::new();
         let tree = tree.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
-        let mut capture = TemplateCapture::new();
+        let mut capture = TemplateCapture::with_facts(assume_positive(&tree.symbols()));
         capture.node_index = None;
         capture.bindings.clear();
         let template = get_template_by_name(name).unwrap();
@@ -422,6 +800,17 @@ mod test {
         t_check_template("square_abs", deftree!(log (+ 1 (exp (pow (abs 2) 2.)))), 3);
     }
 
+    #[test]
+    fn t_match_rational_constants() {
+        let r = |num, den| Tree::rational(Rational::from_fraction(num, den).unwrap());
+        let (one, two) = (r(1, 1), r(2, 1));
+        t_check_template("pythagoras_sin", deftree!(- {one} (pow (cos x) {two})), 5);
+        // The template has the constant 2, not 2.5.
+        let template = get_template_by_name("square_abs").unwrap();
+        let mut capture = TemplateCapture::new();
+        assert!(!capture.next_match(&template, &deftree!(pow (abs x) {r(5, 2)})));
+    }
+
     #[test]
     fn t_match_mul_exponents() {
         t_check_template(
@@ -435,7 +824,7 @@ mod test {
     fn t_match_add_exponents() {
         t_check_template(
             "add_exponents",
-            deftree!(log (+ 1 (exp (* (pow (log x) 2) (pow (log x) 3))))),
+            deftree!(log (+ 1 (exp (* (pow (exp x) 2) (pow (exp x) 3))))),
             7,
         );
     }
@@ -478,6 +867,34 @@ mod test {
         t_check_template(NAME, deftree!(min (+ x z) (+ y x)), 5);
     }
 
+    #[test]
+    fn t_match_pythagoras() {
+        t_check_template(
+            "pythagoras",
+            deftree!(* 3 (+ (pow (cos y) 2) (pow (sin y) 2))),
+            7,
+        );
+    }
+
+    #[test]
+    fn t_match_double_angle_sin() {
+        t_check_template("double_angle_sin", deftree!(+ 1 (sin (* 2 (+ x y)))), 6);
+    }
+
+    #[test]
+    fn t_match_sum_to_product_cos() {
+        t_check_template(
+            "sum_to_product_cos",
+            deftree!(/ (+ (cos x) (cos (* 2 x))) y),
+            5,
+        );
+    }
+
+    #[test]
+    fn t_match_sin_odd() {
+        t_check_template("sin_odd", deftree!(pow (sin (- x)) 2), 2);
+    }
+
     #[test]
     fn t_mutate_multiple_trees() {
         fn assert_one_match(tree: Tree, expected: Tree, capture: &mut TemplateCapture) {
@@ -487,7 +904,7 @@ mod test {
             let expected = expected.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
             assert_eq!(
                 1,
-                Mutations::of(&tree, capture)
+                Mutations::of(&tree, &RuleSet::new(), capture)
                     .filter_map(|result| if result.unwrap().equivalent(&expected) {
                         Some(())
                     } else {
@@ -496,7 +913,8 @@ mod test {
                     .count()
             );
         }
-        let mut capture = TemplateCapture::new();
+        let mut capture =
+            TemplateCapture::with_facts(assume_positive(&['a', 'b', 'c', 'p', 'x', 'y']));
         // Ensure the same template capture can be used to mutate
         // multiple trees without having to reallocate.
         assert_one_match(
@@ -530,10 +948,11 @@ mod test {
             .prune(&mut pruner);
         let mut lwalker = DepthWalker::new();
         let mut rwalker = DepthWalker::new();
-        let mut capture = TemplateCapture::new();
+        let mut capture = TemplateCapture::with_facts(assume_positive(&before.symbols()));
+        let rules = RuleSet::new();
         assert_eq!(
             1,
-            Mutations::of(&before, &mut capture)
+            Mutations::of(&before, &rules, &mut capture)
                 .filter_map(|t| {
                     let tree = t.unwrap();
                     if equivalent(
@@ -649,4 +1068,158 @@ mod test {
             deftree!(exp (+ 1 (log (pow p (+ (+ 2 m) (/ q r)))))),
         );
     }
+
+    #[test]
+    fn t_template_guards() {
+        let mut dedup = Deduplicater::new();
+        let mut pruner = Pruner::new();
+        let rules = RuleSet::new();
+        let template = rules.get("divide_by_self").unwrap();
+        let mut check = |tree: Tree, facts: Facts| -> bool {
+            let tree = tree.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
+            TemplateCapture::with_facts(facts).next_match(template, &tree)
+        };
+        // Nothing is known about p, so p / p can't be simplified.
+        assert!(!check(deftree!(/ p p), Facts::new()));
+        assert!(check(deftree!(/ p p), assume_positive(&['p'])));
+        assert!(check(
+            deftree!(/ p p),
+            Facts::new().with('p', Condition::NonZero)
+        ));
+        // Guards are inferred from the tree where possible.
+        assert!(check(deftree!(/ (exp p) (exp p)), Facts::new()));
+        assert!(check(deftree!(/ (+ 2 (abs p)) (+ 2 (abs p))), Facts::new()));
+        assert!(!check(
+            deftree!(/ (- 2 (abs p)) (- 2 (abs p))),
+            Facts::new()
+        ));
+        assert!(check(deftree!(+ 1 (/ 2.5 2.5)), Facts::new()));
+        // Guards are checked for the mirrored templates too.
+        let template = rules.get("rev_mul_exponents").unwrap();
+        assert_eq!(template.guards(), &[('a', Condition::Positive)]);
+        assert!(!TemplateCapture::new().next_match(template, &deftree!(pow (sin x) (* 2 3))));
+        assert!(TemplateCapture::new().next_match(template, &deftree!(pow (exp x) (* 2 3))));
+    }
+
+    #[test]
+    fn t_combine_constants() {
+        check_mutations(deftree!(* 2 (* 3 (sin x))), deftree!(* 6 (sin x)));
+        check_mutations(deftree!(+ (+ x 1.5) 2), deftree!(+ 3.5 x));
+        // Constant symbols don't match anything other than constants.
+        let rules = RuleSet::new();
+        let template = rules.get("combine_mul_constants").unwrap();
+        let mut capture = TemplateCapture::new();
+        assert!(!capture.next_match(template, &deftree!(* x (* 3 y))));
+        assert!(!capture.next_match(template, &deftree!(* (+ 1 2) (* 3 y))));
+        assert!(capture.next_match(template, &deftree!(* 2 (* y 3))));
+    }
+
+    #[test]
+    fn t_ac_matching() {
+        // The operands of sums, products, min and max are matched
+        // regardless of how they are nested.
+        check_mutations(
+            deftree!(+ (+ (* k a) c) (* k b)),
+            deftree!(+ (* k (+ a b)) c),
+        );
+        check_mutations(deftree!(* (* 2 x) (* 3 y)), deftree!(* (* 6 x) y));
+        check_mutations(
+            deftree!(min (min (sqrt x) y) (sqrt z)),
+            deftree!(min (sqrt (min x z)) y),
+        );
+        // Operands of other operations are not regrouped.
+        let rules = RuleSet::new();
+        let template = rules.get("distribute_mul").unwrap();
+        let mut capture = TemplateCapture::new();
+        assert!(!capture.next_match(template, &deftree!(- (- (* k a) c) (* k b))));
+        // Only the node whose operands are regrouped matches.
+        let tree = capture
+            .make_compact_tree(deftree!(+ (+ (* k a) c) (* k b)), None)
+            .unwrap();
+        assert!(capture.match_at(template, &tree, tree.root_index()));
+        let bound: Vec<_> = capture
+            .bindings()
+            .iter()
+            .map(|(label, i)| (*label, tree.node(*i).clone()))
+            .collect();
+        assert!(bound.contains(&('a', Node::Symbol('a'))));
+        assert!(bound.contains(&('b', Node::Symbol('b'))));
+        assert!(!capture.match_at(template, &tree, tree.root_index() - 1));
+    }
+
+    #[test]
+    fn t_ac_matching_all_groupings() {
+        // Every grouping of the operands is a separate match.
+        let rules = RuleSet::new();
+        let template = rules.get("distribute_mul").unwrap();
+        let mut capture = TemplateCapture::new();
+        let tree = capture
+            .make_compact_tree(deftree!(+ (+ (* k a) (* j c)) (+ (* k b) (* j d))), None)
+            .unwrap();
+        let mut results = Vec::new();
+        while capture.next_match(template, &tree) {
+            results.push(capture.apply(template, &tree).unwrap());
+        }
+        assert_eq!(results.len(), 2);
+        for expected in [
+            deftree!(+ (+ (* k (+ a b)) (* j c)) (* j d)),
+            deftree!(+ (+ (* j (+ c d)) (* k a)) (* k b)),
+        ] {
+            let expected = capture.make_compact_tree(expected, None).unwrap();
+            assert!(results.iter().any(|t| t.equivalent(&expected)));
+        }
+    }
+
+    #[test]
+    fn t_ac_matching_chain_tops() {
+        // Operands are only regrouped at the top of each chain, which
+        // finds the groupings of the inner nodes too.
+        let rules = RuleSet::new();
+        let template = rules.get("distribute_mul").unwrap();
+        let mut capture = TemplateCapture::new();
+        let tree = capture
+            .make_compact_tree(deftree!(+ (+ (+ (* k a) c) (* k b)) d), None)
+            .unwrap();
+        let mut indices = Vec::new();
+        while capture.next_match(template, &tree) {
+            indices.push(capture.node_index.unwrap());
+        }
+        assert_eq!(indices, vec![tree.root_index()]);
+        // Matching at an inner node still regroups its operands.
+        let inner = match tree.root() {
+            Node::Binary(_, lhs, rhs) => match tree.node(*lhs) {
+                Node::Binary(..) => *lhs,
+                _ => *rhs,
+            },
+            _ => unreachable!(),
+        };
+        assert!(capture.match_at(template, &tree, inner));
+        // A sum of n products has n (n - 1) / 2 pairs to distribute.
+        let mut tree = Tree::symbol('k') * Tree::constant(2.);
+        for i in 3..=81 {
+            tree = tree + Tree::symbol('k') * Tree::constant(i as f64);
+        }
+        let tree = capture.make_compact_tree(tree, None).unwrap();
+        let mut count = 0;
+        while capture.next_match(template, &tree) {
+            count += 1;
+        }
+        assert_eq!(count, 80 * 79 / 2);
+    }
+
+    #[test]
+    fn t_ac_matching_max_groupings() {
+        let rules = RuleSet::new();
+        let template = rules.get("distribute_mul").unwrap();
+        let mut capture = TemplateCapture::new();
+        capture.set_max_groupings(Some(1));
+        let tree = capture
+            .make_compact_tree(deftree!(+ (+ (* k a) (* j c)) (+ (* k b) (* j d))), None)
+            .unwrap();
+        let mut count = 0;
+        while capture.next_match(template, &tree) {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
 }
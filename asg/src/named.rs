@@ -0,0 +1,225 @@
+use lazy_static::lazy_static;
+use std::{cmp::Ordering, collections::HashMap, fmt, sync::RwLock};
+
+/*
+Named constants are numbers like π and e, that are kept as symbols
+instead of being replaced with their floating point values. Nodes are
+`Copy`, so each constant is stored once, in an entry that is never
+freed, and nodes refer to their entry. The entry holds the name, LaTeX
+and value of the constant, so they can be read without locking or
+allocating. A global table maps names to entries, and is only used to
+define and look up constants by name.
+
+π and e are always defined, with the names "pi" and "e". Other
+constants can be defined with `NamedConstant::define`. Once defined, a
+constant can't be redefined with a different value. Names are ASCII
+identifiers, so they can be written in lisp notation as `@pi` and in
+`deftree!` as `(@ pi)`.
+*/
+
+/// Errors that can occur when defining named constants.
+#[derive(Debug, PartialEq)]
+pub enum NamedConstantError {
+    /// The name is not an ASCII identifier.
+    InvalidName(String),
+    /// The value is NaN.
+    InvalidValue(f64),
+    /// A constant with the same name but a different value or LaTeX
+    /// representation already exists.
+    Redefined(String),
+}
+
+struct Entry {
+    /// Position of the constant in the order of definition.
+    id: u32,
+    name: &'static str,
+    latex: &'static str,
+    value: f64,
+}
+
+struct Table {
+    entries: HashMap<&'static str, &'static Entry>,
+}
+
+impl Table {
+    fn builtin() -> Table {
+        let mut table = Table {
+            entries: HashMap::new(),
+        };
+        for constant in [NamedConstant::PI, NamedConstant::E] {
+            debug_assert_eq!(constant.id() as usize, table.entries.len());
+            table.entries.insert(constant.name(), constant.entry);
+        }
+        table
+    }
+}
+
+lazy_static! {
+    static ref TABLE: RwLock<Table> = RwLock::new(Table::builtin());
+}
+
+/// A constant that is represented by its name. See the `named` module
+/// for how the names are stored.
+#[derive(Copy, Clone)]
+pub struct NamedConstant {
+    entry: &'static Entry,
+}
+
+impl NamedConstant {
+    /// The ratio of the circumference of a circle to its diameter.
+    pub const PI: NamedConstant = NamedConstant {
+        entry: &Entry {
+            id: 0,
+            name: "pi",
+            latex: "\\pi",
+            value: std::f64::consts::PI,
+        },
+    };
+
+    /// Euler's number, the base of the natural logarithm.
+    pub const E: NamedConstant = NamedConstant {
+        entry: &Entry {
+            id: 1,
+            name: "e",
+            latex: "e",
+            value: std::f64::consts::E,
+        },
+    };
+
+    /// Define a constant called `name`, written as `latex` in LaTeX,
+    /// with the value `value`. Defining a constant that already
+    /// exists with the same value and LaTeX is allowed, and returns
+    /// the existing constant.
+    pub fn define(
+        name: &str,
+        latex: &str,
+        value: f64,
+    ) -> Result<NamedConstant, NamedConstantError> {
+        let mut chars = name.chars();
+        if !chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(NamedConstantError::InvalidName(name.to_string()));
+        }
+        if value.is_nan() {
+            return Err(NamedConstantError::InvalidValue(value));
+        }
+        let mut table = TABLE.write().unwrap();
+        if let Some(&entry) = table.entries.get(name) {
+            return match entry.value.to_bits() == value.to_bits() && entry.latex == latex {
+                true => Ok(NamedConstant { entry }),
+                false => Err(NamedConstantError::Redefined(name.to_string())),
+            };
+        }
+        let id = u32::try_from(table.entries.len()).expect("Too many named constants");
+        let entry: &'static Entry = Box::leak(Box::new(Entry {
+            id,
+            name: Box::leak(name.into()),
+            latex: Box::leak(latex.into()),
+            value,
+        }));
+        table.entries.insert(entry.name, entry);
+        Ok(NamedConstant { entry })
+    }
+
+    /// Find the constant called `name`, if it is defined.
+    pub fn get(name: &str) -> Option<NamedConstant> {
+        let entry = *TABLE.read().unwrap().entries.get(name)?;
+        Some(NamedConstant { entry })
+    }
+
+    /// Position of the constant in the order in which constants were
+    /// defined. Equal constants have the same id.
+    pub(crate) fn id(&self) -> u32 {
+        self.entry.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.entry.name
+    }
+
+    pub fn latex(&self) -> &'static str {
+        self.entry.latex
+    }
+
+    pub fn value(&self) -> f64 {
+        self.entry.value
+    }
+}
+
+impl PartialEq for NamedConstant {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for NamedConstant {}
+
+impl std::hash::Hash for NamedConstant {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id().hash(state);
+    }
+}
+
+impl PartialOrd for NamedConstant {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Constants are ordered by their names, so the order doesn't depend
+/// on the order in which they were defined.
+impl Ord for NamedConstant {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name().cmp(other.name())
+    }
+}
+
+impl fmt::Display for NamedConstant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Debug for NamedConstant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NamedConstant({})", self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_named_constants() {
+        assert_eq!(NamedConstant::get("pi"), Some(NamedConstant::PI));
+        assert_eq!(NamedConstant::get("e"), Some(NamedConstant::E));
+        assert_eq!(NamedConstant::PI.latex(), "\\pi");
+        assert_eq!(NamedConstant::E.value(), std::f64::consts::E);
+        assert!(NamedConstant::get("t_undefined").is_none());
+        let phi = NamedConstant::define("t_phi", "\\phi", 1.618).unwrap();
+        assert_eq!(phi.name(), "t_phi");
+        assert_eq!(phi.value(), 1.618);
+        assert_eq!(NamedConstant::get("t_phi"), Some(phi));
+        assert_eq!(NamedConstant::define("t_phi", "\\phi", 1.618), Ok(phi));
+        assert_eq!(
+            NamedConstant::define("t_phi", "\\phi", 1.6),
+            Err(NamedConstantError::Redefined("t_phi".into()))
+        );
+        assert_eq!(
+            NamedConstant::define("pi", "\\pi", 3.14),
+            Err(NamedConstantError::Redefined("pi".into()))
+        );
+        assert!(matches!(
+            NamedConstant::define("2x", "", 1.),
+            Err(NamedConstantError::InvalidName(_))
+        ));
+        assert!(matches!(
+            NamedConstant::define("t_nan", "", f64::NAN),
+            Err(NamedConstantError::InvalidValue(_))
+        ));
+        assert!(NamedConstant::E < NamedConstant::PI);
+        assert_eq!(format!("{:?}", NamedConstant::PI), "NamedConstant(pi)");
+    }
+}
@@ -0,0 +1,439 @@
+use crate::{
+    exact::Rational,
+    named::NamedConstant,
+    tree::{BinaryOp, BinaryOp::*, Node, Tree, UnaryOp},
+};
+use std::{cmp::Ordering, collections::HashMap};
+
+/// A node in an `NaryTree`. Unlike `Node`, sums and products can
+/// have any number of operands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NaryNode {
+    Constant(f64),
+    Rational(Rational),
+    Named(NamedConstant),
+    Symbol(char),
+    Unary(UnaryOp, usize),
+    /// Binary operation other than addition and multiplication. The
+    /// operands of commutative operations are in canonical order.
+    Binary(BinaryOp, usize, usize),
+    /// Sum of two or more operands, in canonical order.
+    Sum(Vec<usize>),
+    /// Product of two or more operands, in canonical order.
+    Product(Vec<usize>),
+}
+
+impl NaryNode {
+    /// The node without its inputs, as a `Node`, for comparison.
+    fn head(&self) -> Node {
+        match self {
+            NaryNode::Constant(val) => Node::Constant(*val),
+            NaryNode::Rational(val) => Node::Rational(*val),
+            NaryNode::Named(val) => Node::Named(*val),
+            NaryNode::Symbol(label) => Node::Symbol(*label),
+            NaryNode::Unary(op, _) => Node::Unary(*op, 0),
+            NaryNode::Binary(op, ..) => Node::Binary(*op, 0, 0),
+            NaryNode::Sum(_) => Node::Binary(Add, 0, 0),
+            NaryNode::Product(_) => Node::Binary(Multiply, 0, 0),
+        }
+    }
+
+    /// Indices of the inputs of this node, from left to right.
+    pub fn inputs(&self) -> Vec<usize> {
+        match self {
+            NaryNode::Constant(_)
+            | NaryNode::Rational(_)
+            | NaryNode::Named(_)
+            | NaryNode::Symbol(_) => vec![],
+            NaryNode::Unary(_, input) => vec![*input],
+            NaryNode::Binary(_, lhs, rhs) => vec![*lhs, *rhs],
+            NaryNode::Sum(operands) | NaryNode::Product(operands) => operands.clone(),
+        }
+    }
+}
+
+/// A tree in which chains of additions and multiplications are
+/// flattened into sums and products with any number of operands. The
+/// operands are sorted in a canonical order, and identical subtrees
+/// are shared, so two trees that only differ in how their sums and
+/// products are nested and ordered have the same `NaryTree`.
+///
+/// The canonical order is the order of `Node`s, i.e. constants,
+/// rationals, named constants, symbols, unary and then binary
+/// operations, with ties broken by comparing the inputs from left to
+/// right.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NaryTree {
+    nodes: Vec<NaryNode>,
+}
+
+impl NaryTree {
+    /// The root of the tree. This is the last node.
+    pub fn root(&self) -> &NaryNode {
+        self.nodes.last().unwrap()
+    }
+
+    /// Index of the root node.
+    pub fn root_index(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    /// Get a reference to the node at `index`.
+    pub fn node(&self, index: usize) -> &NaryNode {
+        &self.nodes[index]
+    }
+
+    /// Reference to the nodes of this tree. They are topologically
+    /// sorted, with the root as the last node.
+    pub fn nodes(&self) -> &[NaryNode] {
+        &self.nodes
+    }
+
+    /// Convert this tree back to a `Tree` with only binary sums and
+    /// products, for evaluation, LaTeX and so on. The operands of a
+    /// sum or product are added or multiplied from left to right, in
+    /// canonical order.
+    pub fn to_tree(&self) -> Tree {
+        let mut nodes = Vec::<Node>::with_capacity(self.nodes.len());
+        let mut map = Vec::<usize>::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            match node {
+                NaryNode::Constant(val) => nodes.push(Node::Constant(*val)),
+                NaryNode::Rational(val) => nodes.push(Node::Rational(*val)),
+                NaryNode::Named(val) => nodes.push(Node::Named(*val)),
+                NaryNode::Symbol(label) => nodes.push(Node::Symbol(*label)),
+                NaryNode::Unary(op, input) => nodes.push(Node::Unary(*op, map[*input])),
+                NaryNode::Binary(op, lhs, rhs) => {
+                    nodes.push(Node::Binary(*op, map[*lhs], map[*rhs]))
+                }
+                NaryNode::Sum(operands) | NaryNode::Product(operands) => {
+                    let op = if let NaryNode::Sum(_) = node {
+                        Add
+                    } else {
+                        Multiply
+                    };
+                    let mut acc = map[operands[0]];
+                    for operand in &operands[1..] {
+                        nodes.push(Node::Binary(op, acc, map[*operand]));
+                        acc = nodes.len() - 1;
+                    }
+                }
+            }
+            map.push(nodes.len() - 1);
+        }
+        // The nodes are topologically sorted, and don't contain NaN,
+        // because they came from a valid tree.
+        Tree::from_nodes(nodes).unwrap()
+    }
+}
+
+impl Tree {
+    /// Convert this tree to an `NaryTree`, by flattening chains of
+    /// additions and multiplications, and sorting their operands.
+    pub fn to_nary(&self) -> NaryTree {
+        let nodes = self.nodes();
+        // Additions and multiplications that are only used by the
+        // same operation are absorbed into the chains they belong to,
+        // so only the tops of the chains are flattened.
+        let mut absorbed: Vec<bool> = nodes
+            .iter()
+            .map(|node| matches!(node, Node::Binary(Add | Multiply, ..)))
+            .collect();
+        absorbed[self.root_index()] = false;
+        for node in nodes {
+            let (op, inputs) = match node {
+                Node::Unary(_, input) => (None, vec![*input]),
+                Node::Binary(op, lhs, rhs) => (Some(*op), vec![*lhs, *rhs]),
+                _ => continue,
+            };
+            for input in inputs {
+                if !matches!(nodes[input], Node::Binary(iop, ..) if Some(iop) == op) {
+                    absorbed[input] = false;
+                }
+            }
+        }
+        let mut flat = Vec::<NaryNode>::with_capacity(self.len());
+        let mut classes = Vec::<usize>::with_capacity(self.len());
+        let mut unique = HashMap::<(u8, u64, Vec<usize>), usize>::new();
+        let mut stack = Vec::<usize>::new();
+        for (index, node) in nodes.iter().enumerate() {
+            if absorbed[index] {
+                // Never an operand of anything, so it is never
+                // compared or emitted.
+                flat.push(NaryNode::Sum(Vec::new()));
+                classes.push(usize::MAX);
+                continue;
+            }
+            let flat_node = match node {
+                Node::Constant(val) => NaryNode::Constant(*val),
+                Node::Rational(val) => NaryNode::Rational(*val),
+                Node::Named(val) => NaryNode::Named(*val),
+                Node::Symbol(label) => NaryNode::Symbol(*label),
+                Node::Unary(op, input) => NaryNode::Unary(*op, *input),
+                Node::Binary(op @ (Add | Multiply), ..) => {
+                    let mut operands = Vec::new();
+                    stack.push(index);
+                    while let Some(i) = stack.pop() {
+                        match nodes[i] {
+                            Node::Binary(iop, lhs, rhs) if iop == *op => {
+                                stack.push(rhs);
+                                stack.push(lhs);
+                            }
+                            _ => operands.push(i),
+                        }
+                    }
+                    operands.sort_by(|a, b| compare(&flat, &classes, *a, *b));
+                    if *op == Add {
+                        NaryNode::Sum(operands)
+                    } else {
+                        NaryNode::Product(operands)
+                    }
+                }
+                Node::Binary(op, lhs, rhs) => {
+                    if op.is_commutative()
+                        && compare(&flat, &classes, *lhs, *rhs) == Ordering::Greater
+                    {
+                        NaryNode::Binary(*op, *rhs, *lhs)
+                    } else {
+                        NaryNode::Binary(*op, *lhs, *rhs)
+                    }
+                }
+            };
+            let key = class_key(&flat_node, &classes);
+            let next = unique.len();
+            classes.push(*unique.entry(key).or_insert(next));
+            flat.push(flat_node);
+        }
+        NaryTree {
+            nodes: emit(&flat, flat.len() - 1),
+        }
+    }
+}
+
+/// Key that two nodes share if and only if the subtrees rooted at
+/// them compare equal, given the `classes` of their inputs.
+fn class_key(node: &NaryNode, classes: &[usize]) -> (u8, u64, Vec<usize>) {
+    let inputs = node.inputs().iter().map(|i| classes[*i]).collect();
+    match node {
+        // Zero and negative zero compare equal.
+        NaryNode::Constant(val) if *val == 0. => (0, 0, inputs),
+        NaryNode::Constant(val) => (0, val.to_bits(), inputs),
+        NaryNode::Rational(val) => (1, val.id() as u64, inputs),
+        NaryNode::Named(val) => (2, val.id() as u64, inputs),
+        NaryNode::Symbol(label) => (3, *label as u64, inputs),
+        NaryNode::Unary(op, _) => (4, op.index() as u64, inputs),
+        NaryNode::Binary(op, ..) => (5, op.index() as u64, inputs),
+        NaryNode::Sum(_) => (6, 0, inputs),
+        NaryNode::Product(_) => (7, 0, inputs),
+    }
+}
+
+/// Compare the subtrees rooted at `a` and `b` in canonical order.
+/// Subtrees in the same class are equal, so only the first pair of
+/// inputs in different classes decides the order. That means the
+/// comparison follows a single path down the trees, without
+/// recursion, no matter how many subtrees are shared.
+fn compare(nodes: &[NaryNode], classes: &[usize], mut a: usize, mut b: usize) -> Ordering {
+    loop {
+        if classes[a] == classes[b] {
+            return Ordering::Equal;
+        }
+        let (na, nb) = (&nodes[a], &nodes[b]);
+        // Trees can't contain NaN, so this is never None.
+        let ord = na.head().partial_cmp(&nb.head()).unwrap_or(Ordering::Equal);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        let (ia, ib) = (na.inputs(), nb.inputs());
+        if ia.len() != ib.len() {
+            return ia.len().cmp(&ib.len());
+        }
+        match ia
+            .into_iter()
+            .zip(ib)
+            .find(|(x, y)| classes[*x] != classes[*y])
+        {
+            Some((x, y)) => (a, b) = (x, y),
+            // Not reachable, because the nodes would be in the same
+            // class.
+            None => return Ordering::Equal,
+        }
+    }
+}
+
+/// Copy the subtree rooted at `root` in depth first order, sharing
+/// identical subtrees.
+fn emit(nodes: &[NaryNode], root: usize) -> Vec<NaryNode> {
+    let mut out = Vec::<NaryNode>::new();
+    let mut map = vec![usize::MAX; nodes.len()];
+    let mut unique = HashMap::<(u8, u64, Vec<usize>), usize>::new();
+    let mut stack = vec![(root, false)];
+    while let Some((index, ready)) = stack.pop() {
+        if map[index] != usize::MAX {
+            continue;
+        }
+        let node = &nodes[index];
+        if !ready {
+            stack.push((index, true));
+            // Reversed, so the inputs are emitted from left to right.
+            for input in node.inputs().into_iter().rev() {
+                stack.push((input, false));
+            }
+            continue;
+        }
+        let node = match node {
+            NaryNode::Constant(val) => NaryNode::Constant(*val),
+            NaryNode::Rational(val) => NaryNode::Rational(*val),
+            NaryNode::Named(val) => NaryNode::Named(*val),
+            NaryNode::Symbol(label) => NaryNode::Symbol(*label),
+            NaryNode::Unary(op, input) => NaryNode::Unary(*op, map[*input]),
+            NaryNode::Binary(op, lhs, rhs) => NaryNode::Binary(*op, map[*lhs], map[*rhs]),
+            NaryNode::Sum(operands) => NaryNode::Sum(operands.iter().map(|i| map[*i]).collect()),
+            NaryNode::Product(operands) => {
+                NaryNode::Product(operands.iter().map(|i| map[*i]).collect())
+            }
+        };
+        let key = match &node {
+            NaryNode::Constant(val) => (0, val.to_bits(), vec![]),
+            NaryNode::Symbol(label) => (1, *label as u64, vec![]),
+            NaryNode::Unary(op, input) => (2, op.index() as u64, vec![*input]),
+            NaryNode::Binary(op, lhs, rhs) => (3, op.index() as u64, vec![*lhs, *rhs]),
+            NaryNode::Sum(operands) => (4, 0, operands.clone()),
+            NaryNode::Product(operands) => (5, 0, operands.clone()),
+            NaryNode::Rational(val) => (6, val.id() as u64, vec![]),
+            NaryNode::Named(val) => (7, val.id() as u64, vec![]),
+        };
+        map[index] = *unique.entry(key).or_insert_with(|| {
+            out.push(node);
+            out.len() - 1
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deftree, verify::numerically_equivalent};
+
+    #[test]
+    fn t_nary_flatten() {
+        let nary = deftree!(+ (+ b a) (+ c (* y (* x 2)))).to_nary();
+        let product = match nary.root() {
+            NaryNode::Sum(operands) => {
+                let labels: Vec<_> = operands[..3].iter().map(|i| nary.node(*i)).collect();
+                assert_eq!(
+                    labels,
+                    vec![
+                        &NaryNode::Symbol('a'),
+                        &NaryNode::Symbol('b'),
+                        &NaryNode::Symbol('c')
+                    ]
+                );
+                assert_eq!(operands.len(), 4);
+                nary.node(operands[3])
+            }
+            other => panic!("Expected a sum, found {:?}", other),
+        };
+        match product {
+            NaryNode::Product(operands) => {
+                assert_eq!(nary.node(operands[0]), &NaryNode::Constant(2.));
+                assert_eq!(nary.node(operands[1]), &NaryNode::Symbol('x'));
+                assert_eq!(nary.node(operands[2]), &NaryNode::Symbol('y'));
+            }
+            other => panic!("Expected a product, found {:?}", other),
+        }
+        // Subtraction is not flattened.
+        assert!(matches!(
+            deftree!(- (- a b) c).to_nary().root(),
+            NaryNode::Binary(BinaryOp::Subtract, ..)
+        ));
+    }
+
+    #[test]
+    fn t_nary_canonical() {
+        let a = deftree!(+ (* (sin x) (+ y 1)) (min (exp z) 2));
+        let b = deftree!(+ (min 2 (exp z)) (* (+ 1 y) (sin x)));
+        let c = deftree!(+ (min (exp z) 2) (* (+ 1 y) (sin x)));
+        assert_eq!(a.to_nary(), b.to_nary());
+        assert_eq!(a.to_nary(), c.to_nary());
+        assert_ne!(
+            a.to_nary(),
+            deftree!(+ (* (sin x) (+ y 1)) (max (exp z) 2)).to_nary()
+        );
+        // Identical subtrees are shared.
+        let nary = deftree!(* (+ x y) (+ y x)).to_nary();
+        assert_eq!(nary.nodes().len(), 4);
+        // Sums used by other operations are kept, even if they are
+        // also part of a longer chain.
+        let mut dedup = crate::dedup::Deduplicater::new();
+        let tree = deftree!(+ (+ a b) (sin (+ b a)));
+        let shared = deftree!(+ (+ a b) (sin (+ a b)))
+            .deduplicate(&mut dedup)
+            .unwrap();
+        assert_eq!(tree.to_nary(), shared.to_nary());
+        assert_eq!(tree.to_nary().nodes().len(), 5);
+        assert!(matches!(tree.to_nary().root(), NaryNode::Sum(operands) if operands.len() == 3));
+    }
+
+    #[test]
+    fn t_nary_shared() {
+        // Two identical, but separate copies of a deep DAG, where
+        // every node uses the previous node twice. Expanding the
+        // shared subtrees would take 2^60 steps.
+        let mut nodes = Vec::new();
+        let mut roots = Vec::new();
+        for _ in 0..2 {
+            nodes.push(Node::Symbol('x'));
+            for _ in 0..60 {
+                let last = nodes.len() - 1;
+                nodes.push(Node::Binary(Pow, last, last));
+            }
+            roots.push(nodes.len() - 1);
+        }
+        nodes.push(Node::Binary(Add, roots[0], roots[1]));
+        let nary = Tree::from_nodes(nodes).unwrap().to_nary();
+        assert_eq!(nary.nodes().len(), 62);
+        assert_eq!(nary.root(), &NaryNode::Sum(vec![60, 60]));
+    }
+
+    #[test]
+    fn t_nary_long_chain() {
+        // A left-deep sum of many terms is flattened once, at the top.
+        let mut nodes = vec![Node::Symbol('x')];
+        for i in 0..20_000 {
+            nodes.push(Node::Constant(i as f64));
+            nodes.push(Node::Binary(Add, nodes.len() - 2, nodes.len() - 1));
+        }
+        let nary = Tree::from_nodes(nodes).unwrap().to_nary();
+        match nary.root() {
+            NaryNode::Sum(operands) => assert_eq!(operands.len(), 20_001),
+            other => panic!("Expected a sum, found {:?}", other),
+        }
+        assert_eq!(nary.nodes().len(), 20_002);
+    }
+
+    #[test]
+    fn t_nary_to_tree() {
+        let tree = deftree!(+ (+ (* k a) c) (- (* b (* k 3)) (+ a (+ b c))));
+        let binary = tree.to_nary().to_tree();
+        numerically_equivalent(
+            &tree,
+            &binary,
+            &[
+                ('a', -5., 5.),
+                ('b', -5., 5.),
+                ('c', -5., 5.),
+                ('k', -5., 5.),
+            ],
+            100,
+            1e-9,
+        )
+        .unwrap();
+        assert_eq!(
+            deftree!(+ c (+ b a)).to_nary().to_tree().to_latex(),
+            "{\\left({a} + {b}\\right)} + {c}"
+        );
+        assert_eq!(binary.to_nary(), tree.to_nary());
+    }
+}
@@ -0,0 +1,813 @@
+use crate::{
+    exact::Rational,
+    named::NamedConstant,
+    prune::Pruner,
+    template::{RuleSet, Template, TemplateError},
+    tree::{BinaryOp, BinaryOp::*, Node, Node::*, Tree, UnaryOp, UnaryOp::*},
+};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::path::Path;
+
+/*
+Trees are written in the same lisp-like notation used by `deftree!`:
+
+    (/ (+ (* k x) (* k y)) (+ x y))
+
+Symbols are single characters, constants are floating point numbers,
+exact rational constants are written as fractions of integers without
+spaces, such as `1/3` or `-2/5`, named constants are written as their
+names prefixed with '@', such as `@pi`, and redundant parentheses are
+allowed.
+
+A rule file has one rule per line, mirroring `deftemplate!`:
+
+    # Comments start with a hash.
+    distribute_mul: (+ (* k a) (* k b)) <=> (* k (+ a b))
+    divide_by_self: (/ a a) => 1
+
+A rule with '=>' is only applied from left to right. A rule with the
+bidirectional marker '<=>' is mirrored, so it is also applied from
+right to left, exactly like the built-in templates.
+*/
+
+/// Errors that can occur when parsing trees and rule files.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The text on the given line (starting from 1) is not valid.
+    Syntax { line: usize, message: String },
+    /// The rule on the given line does not make a valid template.
+    InvalidTemplate { line: usize, error: TemplateError },
+    /// The rule file could not be read.
+    Io(std::io::Error),
+}
+
+impl ParseError {
+    fn syntax(line: usize, message: impl Into<String>) -> ParseError {
+        ParseError::Syntax {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Atom(&'a str),
+}
+
+/// Split `text` into tokens, each paired with the number of the line
+/// it was found on. `first_line` is the number of the first line of
+/// `text`.
+fn tokenize(text: &str, first_line: usize) -> Vec<(Token<'_>, usize)> {
+    let mut tokens = Vec::new();
+    for (offset, line) in text.lines().enumerate() {
+        let lineno = first_line + offset;
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            let mut chars = rest.chars();
+            match chars.next() {
+                None => break,
+                Some('(') => tokens.push((Token::Open, lineno)),
+                Some(')') => tokens.push((Token::Close, lineno)),
+                Some(_) => {
+                    let end = rest
+                        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                        .unwrap_or(rest.len());
+                    tokens.push((Token::Atom(&rest[..end]), lineno));
+                    rest = &rest[end..];
+                    continue;
+                }
+            }
+            rest = chars.as_str();
+        }
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token<'a>, usize)>,
+    pos: usize,
+    last_line: usize,
+    nodes: Vec<Node>,
+    /// Roots of the expressions parsed so far, that can be referred
+    /// to as `$0`, `$1` and so on, if `shared` is true.
+    roots: Vec<usize>,
+    shared: bool,
+}
+
+/// An expression whose closing parenthesis hasn't been reached yet.
+struct Frame<'a> {
+    /// The operator, or `None` for a redundant pair of parentheses.
+    op: Option<&'a str>,
+    line: usize,
+    args: Vec<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str, first_line: usize) -> Parser<'a> {
+        Parser {
+            tokens: tokenize(text, first_line),
+            pos: 0,
+            last_line: first_line + text.lines().count().saturating_sub(1),
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            shared: false,
+        }
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, line)) => *line,
+            None => self.last_line,
+        }
+    }
+
+    fn next(&mut self) -> Result<&Token<'a>, ParseError> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => Err(ParseError::syntax(
+                self.last_line,
+                "Unexpected end of input.",
+            )),
+        }
+    }
+
+    /// Parse the whole input as a single tree.
+    fn parse_all(mut self) -> Result<Tree, ParseError> {
+        self.parse_expr()?;
+        if self.pos < self.tokens.len() {
+            return Err(ParseError::syntax(
+                self.line(),
+                "Unexpected input after the end of the expression.",
+            ));
+        }
+        self.finish(false)
+    }
+
+    /// Parse the whole input as a sequence of expressions, each of
+    /// which can refer to the ones before it. The last one is the
+    /// root of the tree.
+    fn parse_shared(mut self) -> Result<Tree, ParseError> {
+        self.shared = true;
+        loop {
+            let root = self.parse_expr()?;
+            self.roots.push(root);
+            if self.pos == self.tokens.len() {
+                return self.finish(true);
+            }
+        }
+    }
+
+    /// Make a tree of the parsed nodes, rooted at the last one. If
+    /// `prune` is true, expressions that aren't used by the root are
+    /// removed.
+    fn finish(self, prune: bool) -> Result<Tree, ParseError> {
+        let line = self.last_line;
+        let tree = Tree::from_nodes(self.nodes)
+            .map_err(|e| ParseError::syntax(line, format!("Invalid tree: {:?}.", e)))?;
+        Ok(match prune {
+            true => tree.prune(&mut Pruner::new()),
+            false => tree,
+        })
+    }
+
+    /// Parse a single expression, and return the index of its root
+    /// node. An explicit stack is used instead of recursion, so deep
+    /// trees don't overflow the call stack.
+    fn parse_expr(&mut self) -> Result<usize, ParseError> {
+        let mut stack = Vec::<Frame<'a>>::new();
+        loop {
+            let line = self.line();
+            let mut value = match *self.next()? {
+                Token::Close => return Err(ParseError::syntax(line, "Unexpected ')'.")),
+                Token::Atom(atom) => Some(self.push_atom(atom, line)?),
+                Token::Open => {
+                    // Either an operation, or a redundant pair of
+                    // parentheses around an expression.
+                    let op = match self.tokens.get(self.pos) {
+                        Some((Token::Atom(atom), _)) if is_operator(atom) => {
+                            self.pos += 1;
+                            Some(*atom)
+                        }
+                        Some((Token::Close, _)) => {
+                            return Err(ParseError::syntax(line, "Empty expression."))
+                        }
+                        _ => None,
+                    };
+                    stack.push(Frame {
+                        op,
+                        line,
+                        args: Vec::with_capacity(2),
+                    });
+                    None
+                }
+            };
+            // Close all the expressions that are complete.
+            loop {
+                let frame = match stack.last_mut() {
+                    Some(frame) => frame,
+                    // The value is only `None` right after pushing a
+                    // frame, so the stack can't be empty.
+                    None => return Ok(value.unwrap()),
+                };
+                frame.args.extend(value.take());
+                match frame.op {
+                    None if frame.args.is_empty() => break,
+                    None => {
+                        let line = frame.line;
+                        let inner = frame.args[0];
+                        match self.next()? {
+                            Token::Close => {}
+                            _ => {
+                                return Err(ParseError::syntax(
+                                    line,
+                                    "Expected an operator at the start of an expression.",
+                                ))
+                            }
+                        }
+                        stack.pop();
+                        value = Some(inner);
+                    }
+                    Some(_) => {
+                        if !matches!(self.tokens.get(self.pos), Some((Token::Close, _)) | None) {
+                            break;
+                        }
+                        self.next()?; // Closing paren.
+                        let frame = stack.pop().unwrap();
+                        let node = make_op(frame.op.unwrap(), &frame.args, frame.line)?;
+                        self.nodes.push(node);
+                        value = Some(self.nodes.len() - 1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add the node of `atom`, and return its index. References to
+    /// earlier expressions don't add a node.
+    fn push_atom(&mut self, atom: &str, line: usize) -> Result<usize, ParseError> {
+        if self.shared {
+            if let Some(index) = atom.strip_prefix('$') {
+                return match index.parse::<usize>().ok().and_then(|i| self.roots.get(i)) {
+                    Some(root) => Ok(*root),
+                    None => Err(ParseError::syntax(
+                        line,
+                        format!("Invalid reference '{}'.", atom),
+                    )),
+                };
+            }
+        }
+        self.nodes.push(parse_atom(atom, line)?);
+        Ok(self.nodes.len() - 1)
+    }
+}
+
+fn is_operator(atom: &str) -> bool {
+    matches!(
+        atom,
+        "+" | "-"
+            | "*"
+            | "/"
+            | "pow"
+            | "min"
+            | "max"
+            | "sqrt"
+            | "abs"
+            | "sin"
+            | "cos"
+            | "tan"
+            | "log"
+            | "exp"
+    )
+}
+
+/// The node of the operation `op` with the inputs `args`.
+fn make_op(op: &str, args: &[usize], line: usize) -> Result<Node, ParseError> {
+    match *args {
+        [x] => Ok(Unary(
+            match op {
+                "-" => Negate,
+                "sqrt" => Sqrt,
+                "abs" => Abs,
+                "sin" => Sin,
+                "cos" => Cos,
+                "tan" => Tan,
+                "log" => Log,
+                "exp" => Exp,
+                _ => {
+                    return Err(ParseError::syntax(
+                        line,
+                        format!("'{}' expects 2 arguments, found 1.", op),
+                    ))
+                }
+            },
+            x,
+        )),
+        [lhs, rhs] => Ok(Binary(
+            match op {
+                "+" => Add,
+                "-" => Subtract,
+                "*" => Multiply,
+                "/" => Divide,
+                "pow" => Pow,
+                "min" => Min,
+                "max" => Max,
+                _ => {
+                    return Err(ParseError::syntax(
+                        line,
+                        format!("'{}' expects 1 argument, found 2.", op),
+                    ))
+                }
+            },
+            lhs,
+            rhs,
+        )),
+        _ => Err(ParseError::syntax(
+            line,
+            format!("Wrong number of arguments for '{}': {}.", op, args.len()),
+        )),
+    }
+}
+
+fn parse_atom(atom: &str, line: usize) -> Result<Node, ParseError> {
+    if let Some(name) = atom.strip_prefix('@') {
+        return match NamedConstant::get(name) {
+            Some(value) => Ok(Named(value)),
+            None => Err(ParseError::syntax(
+                line,
+                format!("Undefined named constant '{}'.", atom),
+            )),
+        };
+    }
+    if let Some((num, den)) = atom.split_once('/') {
+        if let (Ok(num), Ok(den)) = (num.parse::<BigInt>(), den.parse::<BigInt>()) {
+            if den == BigInt::from(0) {
+                return Err(ParseError::syntax(
+                    line,
+                    format!("Invalid rational '{}'. The denominator is zero.", atom),
+                ));
+            }
+            return Ok(Rational(Rational::new(BigRational::new(num, den))));
+        }
+    }
+    if let Ok(value) = atom.parse::<f64>() {
+        if value.is_nan() {
+            return Err(ParseError::syntax(line, "Constants cannot be NaN."));
+        }
+        return Ok(Constant(value));
+    }
+    let mut chars = atom.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_alphabetic() => Ok(Symbol(c)),
+        _ => Err(ParseError::syntax(
+            line,
+            format!("Invalid symbol '{}'. Symbols must be single letters.", atom),
+        )),
+    }
+}
+
+/// Parse a single tree from `text`, with the first line of `text`
+/// numbered `first_line`.
+pub(crate) fn parse_tree(text: &str, first_line: usize) -> Result<Tree, ParseError> {
+    Parser::new(text, first_line).parse_all()
+}
+
+/// Parse a sequence of expressions from `text`, where `$i` refers to
+/// the `i`-th expression, starting from 0. The last expression is the
+/// root of the tree. See `Tree::to_shared_lisp`.
+pub(crate) fn parse_shared_tree(text: &str, first_line: usize) -> Result<Tree, ParseError> {
+    Parser::new(text, first_line).parse_shared()
+}
+
+impl Tree {
+    /// Write this tree in the lisp-like notation that can be parsed
+    /// back into a tree.
+    pub fn to_lisp(&self) -> String {
+        let mut out = String::new();
+        write_lisp(self.nodes(), self.root_index(), &mut out);
+        out
+    }
+
+    /// Write this tree as a sequence of expressions, one for each
+    /// node, in which the inputs of operations are references to
+    /// earlier expressions, such as `x (sin $0) (+ $1 $1)`. Unlike
+    /// `to_lisp`, shared subtrees are only written once, so the
+    /// length of the text is proportional to the number of nodes.
+    pub(crate) fn to_shared_lisp(&self) -> String {
+        let mut out = String::new();
+        for (index, node) in self.nodes().iter().enumerate() {
+            if index > 0 {
+                out.push(' ');
+            }
+            match node {
+                Unary(op, input) => {
+                    out.push_str(&format!("({} ${})", unary_name(*op), input));
+                }
+                Binary(op, lhs, rhs) => {
+                    out.push_str(&format!("({} ${} ${})", binary_name(*op), lhs, rhs));
+                }
+                _ => write_atom(node, &mut out),
+            }
+        }
+        out
+    }
+}
+
+fn unary_name(op: UnaryOp) -> &'static str {
+    match op {
+        Negate => "-",
+        Sqrt => "sqrt",
+        Abs => "abs",
+        Sin => "sin",
+        Cos => "cos",
+        Tan => "tan",
+        Log => "log",
+        Exp => "exp",
+    }
+}
+
+fn binary_name(op: BinaryOp) -> &'static str {
+    match op {
+        Add => "+",
+        Subtract => "-",
+        Multiply => "*",
+        Divide => "/",
+        Pow => "pow",
+        Min => "min",
+        Max => "max",
+    }
+}
+
+/// Write `node`, which must not be an operation, to `out`.
+fn write_atom(node: &Node, out: &mut String) {
+    match node {
+        Constant(val) => out.push_str(&val.to_string()),
+        // Integers keep the denominator, to tell them apart from
+        // floating point constants.
+        Rational(val) => out.push_str(&format!("{}/{}", val.numer(), val.denom())),
+        Named(val) => {
+            out.push('@');
+            out.push_str(val.name());
+        }
+        Symbol(label) => out.push(*label),
+        Unary(..) | Binary(..) => unreachable!("Operations are not atoms."),
+    }
+}
+
+/// Write the subtree rooted at `root` to `out`. Shared subtrees are
+/// written out once for every use, because the notation has no way to
+/// refer to them. An explicit stack is used, so deep trees don't
+/// overflow the call stack.
+fn write_lisp(nodes: &[Node], root: usize, out: &mut String) {
+    let mut stack = vec![(root, false)];
+    let mut first = true;
+    while let Some((index, ready)) = stack.pop() {
+        if ready {
+            out.push(')');
+            continue;
+        }
+        if !first {
+            out.push(' ');
+        }
+        first = false;
+        let op = match nodes[index] {
+            Unary(op, input) => {
+                stack.push((index, true));
+                stack.push((input, false));
+                unary_name(op)
+            }
+            Binary(op, lhs, rhs) => {
+                // Reversed, so the inputs are written from left to
+                // right.
+                stack.push((index, true));
+                stack.push((rhs, false));
+                stack.push((lhs, false));
+                binary_name(op)
+            }
+            _ => {
+                write_atom(&nodes[index], out);
+                continue;
+            }
+        };
+        out.push('(');
+        out.push_str(op);
+    }
+}
+
+impl std::str::FromStr for Tree {
+    type Err = ParseError;
+
+    /// Parse a tree from lisp-like notation, the same notation
+    /// accepted by `deftree!`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_tree(s, 1)
+    }
+}
+
+/// A rule read from a rule file.
+pub struct Rule {
+    /// Line the rule was found on.
+    pub line: usize,
+    pub template: Template,
+    /// Whether the rule should also be applied from right to left.
+    pub bidirectional: bool,
+}
+
+/// Parse all the rules in `text`, without adding them to any rule
+/// set. See the top of this module for a description of the format.
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, ParseError> {
+    let mut rules = Vec::new();
+    for (offset, line) in text.lines().enumerate() {
+        let lineno = offset + 1;
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, body) = line
+            .split_once(':')
+            .ok_or_else(|| ParseError::syntax(lineno, "Expected 'name: ping => pong'."))?;
+        let name = name.trim();
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(ParseError::syntax(
+                lineno,
+                format!("Invalid rule name '{}'.", name),
+            ));
+        }
+        let (ping, pong, bidirectional) = match body.split_once("<=>") {
+            Some((ping, pong)) => (ping, pong, true),
+            None => match body.split_once("=>") {
+                Some((ping, pong)) => (ping, pong, false),
+                None => {
+                    return Err(ParseError::syntax(
+                        lineno,
+                        "Expected '=>' or '<=>' between the ping and the pong.",
+                    ))
+                }
+            },
+        };
+        rules.push(Rule {
+            line: lineno,
+            template: Template::from(name, parse_tree(ping, lineno)?, parse_tree(pong, lineno)?),
+            bidirectional,
+        });
+    }
+    Ok(rules)
+}
+
+impl RuleSet {
+    /// Parse the rules in `text` and add them to this set. Either all
+    /// the rules are added, or none of them are and an error is
+    /// returned.
+    pub fn add_rules(&mut self, text: &str) -> Result<(), ParseError> {
+        let mut added: Vec<String> = Vec::new();
+        for rule in parse_rules(text)? {
+            let line = rule.line;
+            let name = rule.template.name().to_string();
+            let result = if rule.bidirectional {
+                self.add(rule.template)
+            } else {
+                self.add_oneway(rule.template)
+            };
+            if let Err(error) = result {
+                // Undo, in reverse, so the templates are in the same
+                // order as before.
+                for name in added.iter().rev() {
+                    self.remove(name);
+                }
+                return Err(ParseError::InvalidTemplate { line, error });
+            }
+            added.push(name);
+        }
+        Ok(())
+    }
+
+    /// Read the rule file at `path` and add its rules to this set.
+    /// Either all the rules are added, or none of them are and an
+    /// error is returned.
+    pub fn load_rules<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ParseError> {
+        let text = std::fs::read_to_string(path).map_err(ParseError::Io)?;
+        self.add_rules(&text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deftree;
+
+    fn check_parse(text: &str, expected: Tree) {
+        assert_eq!(text.parse::<Tree>().unwrap(), expected);
+    }
+
+    fn check_syntax_error(text: &str, line: usize) {
+        match text.parse::<Tree>() {
+            Err(ParseError::Syntax { line: l, .. }) => assert_eq!(l, line),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(tree) => panic!("Expected an error, parsed:{}", tree),
+        }
+    }
+
+    #[test]
+    fn t_parse_tree() {
+        check_parse("x", deftree!(x));
+        check_parse("2.5", deftree!(2.5));
+        check_parse("(-2.5)", Tree::constant(-2.5));
+        check_parse("((x))", deftree!(x));
+        check_parse("(- x)", deftree!(-x));
+        let rational = |num, den| Tree::rational(Rational::from_fraction(num, den).unwrap());
+        check_parse("2/6", rational(1, 3));
+        check_parse("(+ x -1/3)", deftree!(+ x {rational(-1, 3)}));
+        check_parse("(sin (* 2 @pi))", deftree!(sin (* 2 (@ pi))));
+        check_parse("(- x y)", deftree!(- x y));
+        check_parse(
+            "(/ (+ (* k x) (* k y)) (+ x y))",
+            deftree!(/ (+ (* k x) (* k y)) (+ x y)),
+        );
+        check_parse(
+            "(max (min (sqrt (abs x)) (pow x 2))
+                  (+ (sin (cos (tan y))) (log (exp z))))",
+            deftree!(max (min (sqrt (abs x)) (pow x 2))
+                     (+ (sin (cos (tan y))) (log (exp z)))),
+        );
+    }
+
+    #[test]
+    fn t_to_lisp() {
+        let trees = [
+            deftree!(/ (+ (* k x) (* k y)) (+ x y)),
+            deftree!(- (sqrt (abs (- x))) (min (pow x 2.5) (max 1e-10 (exp (log y))))),
+            deftree!(+ (sin x) (- (cos x) (tan 1))),
+            Tree::constant(-2.5),
+            Tree::rational(Rational::from_fraction(-2, 7).unwrap()),
+            Tree::rational(Rational::from_integer(3)) * deftree!(x),
+            deftree!(pow (@ e) (* (@ pi) x)),
+        ];
+        for tree in trees {
+            assert_eq!(tree.to_lisp().parse::<Tree>().unwrap(), tree);
+        }
+        assert_eq!(deftree!(+ x (- 2)).to_lisp(), "(+ x (- 2))");
+        assert_eq!(Tree::rational(Rational::from_integer(3)).to_lisp(), "3/1");
+        // Deep trees don't overflow the stack.
+        let mut nodes = vec![Symbol('x')];
+        nodes.extend((0..100_000).map(|i| Unary(Negate, i)));
+        let lisp = Tree::from_nodes(nodes).unwrap().to_lisp();
+        assert_eq!(lisp.len(), 100_000 * "(- )".len() + 1);
+        assert!(lisp.starts_with("(- (- ") && lisp.contains(" (- x))"));
+        assert_eq!(lisp.parse::<Tree>().unwrap().len(), 100_001);
+    }
+
+    #[test]
+    fn t_shared_lisp() {
+        let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y))
+            .deduplicate(&mut crate::dedup::Deduplicater::new())
+            .unwrap()
+            .prune(&mut Pruner::new());
+        let text = tree.to_shared_lisp();
+        assert_eq!(
+            text,
+            "k x (* $0 $1) y (* $0 $3) (+ $2 $4) (+ $1 $3) (/ $5 $6)"
+        );
+        assert_eq!(parse_shared_tree(&text, 1).unwrap(), tree);
+        // Plain expressions, and expressions that are not used.
+        check_shared("(+ x 1)", deftree!(+ x 1));
+        check_shared("(sin x) y (+ $0 ((sin x)))", deftree!(+ (sin x) (sin x)));
+        // References only work in the shared notation, and only to
+        // earlier expressions.
+        check_syntax_error("(+ x $0)", 1);
+        for text in ["(+ x $0)", "x (+ $0 $1)", "x\n(+ $0 $-1)", "x (+ $0 $a)"] {
+            assert!(matches!(
+                parse_shared_tree(text, 1),
+                Err(ParseError::Syntax { .. })
+            ));
+        }
+    }
+
+    fn check_shared(text: &str, expected: Tree) {
+        assert_eq!(parse_shared_tree(text, 1).unwrap(), expected);
+    }
+
+    #[test]
+    fn t_parse_tree_errors() {
+        check_syntax_error("", 1);
+        check_syntax_error("(+ x y", 1);
+        check_syntax_error("(+ x y))", 1);
+        check_syntax_error("()", 1);
+        check_syntax_error("(+ x)", 1);
+        check_syntax_error("(sqrt x y)", 1);
+        check_syntax_error("(+ x y z)", 1);
+        check_syntax_error("(x y)", 1);
+        check_syntax_error("(+ xy 1)", 1);
+        check_syntax_error("(+ x\n (foo 1))", 2);
+        check_syntax_error("(+ x\n (* y 2)\n", 2);
+        check_syntax_error("NaN", 1);
+        check_syntax_error("1/0", 1);
+        check_syntax_error("1/x", 1);
+        check_syntax_error("(+ x @undefined)", 1);
+    }
+
+    #[test]
+    fn t_parse_rules() {
+        let rules = parse_rules(
+            "
+# Comment.
+double: (+ x x) <=> (* 2 x)   # Trailing comment.
+
+half: (/ x 2) => (* 0.5 x)
+",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].line, 3);
+        assert_eq!(rules[0].template.name(), "double");
+        assert_eq!(rules[0].template.ping(), &deftree!(+ x x));
+        assert_eq!(rules[0].template.pong(), &deftree!(* 2 x));
+        assert!(rules[0].bidirectional);
+        assert_eq!(rules[1].line, 5);
+        assert_eq!(rules[1].template.name(), "half");
+        assert!(!rules[1].bidirectional);
+    }
+
+    #[test]
+    fn t_parse_rules_errors() {
+        fn check(text: &str, line: usize) {
+            match parse_rules(text) {
+                Err(ParseError::Syntax { line: l, .. }) => assert_eq!(l, line),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+                Ok(_) => panic!("Expected an error."),
+            }
+        }
+        check("double (+ x x) => (* 2 x)", 1);
+        check("\ndouble: (+ x x) (* 2 x)", 2);
+        check("a: x => x\n\n2bad: x => x", 3);
+        check("bad name: x => x", 1);
+        check("double: (+ x x => (* 2 x)", 1);
+        check("double: (+ x x) => (* 2 x) y", 1);
+    }
+
+    #[test]
+    fn t_add_rules() {
+        let mut rules = RuleSet::new();
+        let builtin = rules.len();
+        rules
+            .add_rules(
+                "double: (+ x x) <=> (* 2 x)
+                 half: (/ x 2) => (* 0.5 x)",
+            )
+            .unwrap();
+        // 'double' is mirrored, 'half' is not.
+        assert_eq!(rules.len(), builtin + 3);
+        assert!(rules.get("rev_double").is_some());
+        assert!(rules.get("rev_half").is_none());
+        let names = |rules: &RuleSet| -> Vec<String> {
+            rules
+                .templates()
+                .iter()
+                .map(|t| t.name().to_string())
+                .collect()
+        };
+        let before = names(&rules);
+        // Errors are reported with line numbers, and nothing is added.
+        match rules.add_rules("triple: (+ x (+ x x)) => (* 3 x)\n\ndouble: (+ y y) => (* y 2)") {
+            Err(ParseError::InvalidTemplate {
+                line: 3,
+                error: TemplateError::DuplicateName(name),
+            }) => assert_eq!(name, "double"),
+            _ => panic!("Expected a duplicate name error."),
+        }
+        assert!(matches!(
+            rules.add_rules("\nbad: (* x 0) => y"),
+            Err(ParseError::InvalidTemplate {
+                line: 2,
+                error: TemplateError::UnboundSymbol('y'),
+            })
+        ));
+        assert!(rules.get("triple").is_none());
+        assert_eq!(rules.len(), builtin + 3);
+        assert_eq!(names(&rules), before);
+    }
+
+    #[test]
+    fn t_load_rules() {
+        let path = std::env::temp_dir().join("asg_t_load_rules.rules");
+        std::fs::write(&path, "double: (+ x x) <=> (* 2 x)\n").unwrap();
+        let mut rules = RuleSet::empty();
+        rules.load_rules(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(matches!(rules.load_rules(&path), Err(ParseError::Io(_))));
+    }
+}
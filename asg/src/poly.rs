@@ -0,0 +1,810 @@
+use crate::{
+    dedup::Deduplicater,
+    exact::{apply_binary, apply_unary, decimal, Rational},
+    prune::Pruner,
+    tree::{pow, BinaryOp, BinaryOp::*, Node::*, Tree, UnaryOp, UnaryOp::*},
+};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use std::{
+    collections::BTreeMap,
+    ops::{Add, Mul, Neg, Sub},
+};
+
+/// Errors that can occur when converting a tree to a polynomial.
+#[derive(Debug)]
+pub enum PolynomialError {
+    /// The node at this index is not a polynomial operation of its
+    /// inputs. For example a sine of a symbol, a division by
+    /// something other than a non-zero constant, a power that isn't a
+    /// non-negative integer, or a product or power with an exponent
+    /// that doesn't fit in a `u32`.
+    NotPolynomial(usize),
+    /// The node at this index is a constant, or evaluates to a
+    /// constant, that is not finite, such as NaN.
+    NotFinite(usize),
+}
+
+/// Sparse multivariate polynomial with exact rational coefficients. Each
+/// term is stored as the exponents of the variables, in the order of
+/// `vars`, mapped to a non-zero coefficient. Only the variables that
+/// appear in at least one term are kept, so two polynomials are equal
+/// if and only if they have the same terms.
+///
+/// The variables can be of any ordered type. Polynomials converted
+/// from trees use the labels of the symbols, but other passes, such as
+/// `Tree::expand`, use their own indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polynomial<V = char> {
+    vars: Vec<V>,
+    terms: BTreeMap<Vec<u32>, BigRational>,
+}
+
+impl<V: Copy + Ord> Polynomial<V> {
+    /// The polynomial with no terms.
+    pub fn zero() -> Polynomial<V> {
+        Polynomial {
+            vars: vec![],
+            terms: BTreeMap::new(),
+        }
+    }
+
+    pub fn constant(value: BigRational) -> Polynomial<V> {
+        let mut poly = Polynomial::zero();
+        if !value.is_zero() {
+            poly.terms.insert(vec![], value);
+        }
+        poly
+    }
+
+    /// The polynomial consisting of just the variable `label`.
+    pub fn var(label: V) -> Polynomial<V> {
+        Polynomial {
+            vars: vec![label],
+            terms: BTreeMap::from([(vec![1], BigRational::one())]),
+        }
+    }
+
+    /// The sorted variables of this polynomial.
+    pub fn vars(&self) -> &[V] {
+        &self.vars
+    }
+
+    /// Iterate over the terms of this polynomial, as the exponents of
+    /// `vars` and the coefficient.
+    pub fn terms(&self) -> impl Iterator<Item = (&[u32], &BigRational)> {
+        self.terms
+            .iter()
+            .map(|(exps, coeff)| (exps.as_slice(), coeff))
+    }
+
+    /// The term with the lexicographically largest exponents, in the
+    /// order of `vars`.
+    pub fn leading_term(&self) -> Option<(&[u32], &BigRational)> {
+        self.terms
+            .iter()
+            .next_back()
+            .map(|(exps, coeff)| (exps.as_slice(), coeff))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The value of this polynomial if it is a constant.
+    pub fn as_constant(&self) -> Option<BigRational> {
+        match self.terms.len() {
+            0 => Some(BigRational::zero()),
+            1 => self.terms.get(&vec![0; self.vars.len()]).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Highest power of `label` in this polynomial.
+    pub fn degree(&self, label: V) -> u32 {
+        match self.vars.binary_search(&label) {
+            Ok(i) => self.terms.keys().map(|exps| exps[i]).max().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// Highest sum of the exponents of a term, or `None` if it
+    /// doesn't fit in a `u32`.
+    pub fn total_degree(&self) -> Option<u32> {
+        self.terms
+            .keys()
+            .map(|exps| exps.iter().try_fold(0u32, |acc, e| acc.checked_add(*e)))
+            .try_fold(0, |acc, degree| Some(acc.max(degree?)))
+    }
+
+    /// Multiply every coefficient by `factor`.
+    pub fn scale(&self, factor: &BigRational) -> Polynomial<V> {
+        let mut out = Polynomial {
+            vars: self.vars.clone(),
+            terms: BTreeMap::new(),
+        };
+        out.add_terms(self, factor);
+        out.trimmed()
+    }
+
+    /// Partial derivative with respect to `label`.
+    pub fn derivative(&self, label: V) -> Polynomial<V> {
+        let mut out = Polynomial {
+            vars: self.vars.clone(),
+            terms: BTreeMap::new(),
+        };
+        if let Ok(i) = self.vars.binary_search(&label) {
+            for (exps, coeff) in self.terms.iter().filter(|(exps, _)| exps[i] > 0) {
+                let mut exps = exps.clone();
+                exps[i] -= 1;
+                out.add_term(
+                    &exps,
+                    coeff * BigRational::from_integer((exps[i] + 1).into()),
+                );
+            }
+        }
+        out.trimmed()
+    }
+
+    /// Raise this polynomial to the power `exponent`, by repeated
+    /// squaring.
+    ///
+    /// # Panics
+    ///
+    /// If an exponent of the result doesn't fit in a `u32`. See
+    /// `checked_pow`.
+    pub fn pow(&self, exponent: u32) -> Polynomial<V> {
+        self.checked_pow(exponent).expect("Exponent overflow")
+    }
+
+    /// Raise this polynomial to the power `exponent`, or return `None`
+    /// if an exponent of the result doesn't fit in a `u32`.
+    pub fn checked_pow(&self, mut exponent: u32) -> Option<Polynomial<V>> {
+        // Check the degrees up front, before doing any work.
+        for i in 0..self.vars.len() {
+            let degree = self.terms.keys().map(|exps| exps[i]).max().unwrap_or(0);
+            degree.checked_mul(exponent)?;
+        }
+        let mut out = Polynomial::constant(BigRational::one());
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                out = out.checked_mul(&base)?;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = base.checked_mul(&base)?;
+            }
+        }
+        Some(out)
+    }
+
+    /// The product of this polynomial and `rhs`, or `None` if an
+    /// exponent of the product doesn't fit in a `u32`.
+    pub fn checked_mul(&self, rhs: &Polynomial<V>) -> Option<Polynomial<V>> {
+        let (lhs, rhs) = self.unified(rhs);
+        let mut out = Polynomial {
+            vars: lhs.vars.clone(),
+            terms: BTreeMap::new(),
+        };
+        for (e1, c1) in lhs.terms.iter() {
+            for (e2, c2) in rhs.terms.iter() {
+                out.add_term(&multiply_monomials(e1, e2)?, c1 * c2);
+            }
+        }
+        Some(out.trimmed())
+    }
+
+    /// This polynomial over `vars`, which must be a sorted superset
+    /// of the variables of this polynomial.
+    fn with_vars(&self, vars: &[V]) -> Polynomial<V> {
+        let map: Vec<usize> = self
+            .vars
+            .iter()
+            .map(|v| vars.binary_search(v).unwrap())
+            .collect();
+        Polynomial {
+            vars: vars.to_vec(),
+            terms: self
+                .terms
+                .iter()
+                .map(|(exps, coeff)| {
+                    let mut out = vec![0; vars.len()];
+                    for (i, e) in exps.iter().enumerate() {
+                        out[map[i]] = *e;
+                    }
+                    (out, coeff.clone())
+                })
+                .collect(),
+        }
+    }
+
+    /// Both polynomials over the union of their variables.
+    fn unified(&self, other: &Polynomial<V>) -> (Polynomial<V>, Polynomial<V>) {
+        let mut vars: Vec<V> = self.vars.iter().chain(other.vars.iter()).copied().collect();
+        vars.sort();
+        vars.dedup();
+        (self.with_vars(&vars), other.with_vars(&vars))
+    }
+
+    /// Add the terms of `other`, multiplied by `factor`. Both
+    /// polynomials must have the same variables.
+    fn add_terms(&mut self, other: &Polynomial<V>, factor: &BigRational) {
+        for (exps, coeff) in other.terms.iter() {
+            self.add_term(exps, coeff * factor);
+        }
+    }
+
+    fn add_term(&mut self, exps: &[u32], coeff: BigRational) {
+        let sum = match self.terms.get(exps) {
+            Some(prev) => prev + coeff,
+            None => coeff,
+        };
+        if sum.is_zero() {
+            self.terms.remove(exps);
+        } else {
+            self.terms.insert(exps.to_vec(), sum);
+        }
+    }
+
+    /// Remove the variables that don't appear in any term.
+    fn trimmed(self) -> Polynomial<V> {
+        let used: Vec<usize> = (0..self.vars.len())
+            .filter(|i| self.terms.keys().any(|exps| exps[*i] > 0))
+            .collect();
+        if used.len() == self.vars.len() {
+            return self;
+        }
+        Polynomial {
+            vars: used.iter().map(|i| self.vars[*i]).collect(),
+            terms: self
+                .terms
+                .into_iter()
+                .map(|(exps, coeff)| (used.iter().map(|i| exps[*i]).collect(), coeff))
+                .collect(),
+        }
+    }
+
+    /// Divide this polynomial by `divisor`, returning the quotient
+    /// and the remainder. Terms are ordered lexicographically by
+    /// their exponents, in the order of the variables, and the
+    /// leading term of the divisor is repeatedly cancelled against
+    /// the remainder. If `divisor` divides this polynomial, the
+    /// remainder is zero.
+    ///
+    /// # Panics
+    ///
+    /// If `divisor` is zero.
+    pub fn div_rem(&self, divisor: &Polynomial<V>) -> (Polynomial<V>, Polynomial<V>) {
+        let (mut rest, divisor) = self.unified(divisor);
+        let (lead_exps, lead_coeff) = divisor
+            .terms
+            .iter()
+            .next_back()
+            .expect("Division by the zero polynomial");
+        let empty = Polynomial {
+            vars: rest.vars.clone(),
+            terms: BTreeMap::new(),
+        };
+        let (mut quot, mut rem) = (empty.clone(), empty);
+        while let Some((exps, coeff)) = rest.terms.pop_last() {
+            if exps.iter().zip(lead_exps.iter()).all(|(e, l)| e >= l) {
+                let exps: Vec<u32> = exps
+                    .iter()
+                    .zip(lead_exps.iter())
+                    .map(|(e, l)| e - l)
+                    .collect();
+                let factor = coeff / lead_coeff;
+                // The leading term was popped, because it cancels
+                // out.
+                for (e, c) in divisor.terms.iter().rev().skip(1) {
+                    // Smaller than the popped term, so this can't
+                    // overflow.
+                    let exps = multiply_monomials(e, &exps).unwrap();
+                    rest.add_term(&exps, -c * &factor);
+                }
+                quot.add_term(&exps, factor);
+            } else {
+                rem.add_term(&exps, coeff);
+            }
+        }
+        (quot.trimmed(), rem.trimmed())
+    }
+
+    /// The coefficients of the powers of `label`, as polynomials in
+    /// the other variables, indexed by the power.
+    pub(crate) fn coefficients_of(&self, label: V) -> BTreeMap<u32, Polynomial<V>> {
+        let mut out = BTreeMap::<u32, Polynomial<V>>::new();
+        let index = self.vars.binary_search(&label).ok();
+        for (exps, coeff) in self.terms.iter() {
+            let (power, rest) = match index {
+                Some(i) => {
+                    let mut rest = exps.clone();
+                    (std::mem::replace(&mut rest[i], 0), rest)
+                }
+                None => (0, exps.clone()),
+            };
+            let entry = out.entry(power).or_insert_with(|| Polynomial {
+                vars: self.vars.clone(),
+                terms: BTreeMap::new(),
+            });
+            entry.add_term(&rest, coeff.clone());
+        }
+        out.into_iter().map(|(k, p)| (k, p.trimmed())).collect()
+    }
+
+    /// Greatest common divisor of the coefficients of the powers of
+    /// `label`.
+    pub(crate) fn content(&self, label: V) -> Polynomial<V> {
+        self.coefficients_of(label)
+            .values()
+            .fold(Polynomial::zero(), |acc, coeff| acc.gcd(coeff))
+    }
+
+    /// This polynomial divided by its content with respect to
+    /// `label`.
+    fn primitive_part(&self, label: V) -> Polynomial<V> {
+        self.div_rem(&self.content(label)).0
+    }
+
+    /// Pseudo-remainder of the division by `divisor`, as polynomials
+    /// in `label`. This is the remainder of this polynomial
+    /// multiplied by a power of the leading coefficient of `divisor`,
+    /// which avoids dividing the coefficients.
+    fn pseudo_rem(&self, divisor: &Polynomial<V>, label: V) -> Polynomial<V> {
+        let degree = divisor.degree(label);
+        let lead = divisor.coefficients_of(label).remove(&degree).unwrap();
+        let mut rem = self.clone();
+        let mut count = (self.degree(label) + 1).saturating_sub(degree);
+        while !rem.is_zero() && rem.degree(label) >= degree {
+            let power = rem.degree(label);
+            let coeff = rem.coefficients_of(label).remove(&power).unwrap();
+            let shift = &coeff * &Polynomial::var(label).pow(power - degree);
+            rem = &(&lead * &rem) - &(&shift * divisor);
+            count -= 1;
+        }
+        &lead.pow(count) * &rem
+    }
+
+    /// This polynomial, or its negation, so that the leading
+    /// coefficient is positive.
+    fn normalized(&self) -> Polynomial<V> {
+        match self.leading_term() {
+            Some((_, lead)) if lead.is_negative() => -self,
+            _ => self.clone(),
+        }
+    }
+
+    /// Greatest common divisor of this polynomial and `other`, using
+    /// the recursive primitive Euclidean algorithm. The result has a
+    /// positive leading coefficient. Common integer factors are
+    /// included if all coefficients are integers, so the greatest
+    /// common divisor of `6x` and `4x^2` is `2x`. Other constant
+    /// factors are not, and the greatest common divisor of
+    /// polynomials with no common factor is one.
+    pub fn gcd(&self, other: &Polynomial<V>) -> Polynomial<V> {
+        if self.is_zero() {
+            return other.normalized();
+        }
+        if other.is_zero() {
+            return self.normalized();
+        }
+        let (a, b) = self.unified(other);
+        let label = match a.vars.first() {
+            Some(label) => *label,
+            None => {
+                let (a, b) = (a.as_constant().unwrap(), b.as_constant().unwrap());
+                let gcd = match a.is_integer() && b.is_integer() {
+                    true => BigRational::from_integer(gcd_integers(a.to_integer(), b.to_integer())),
+                    false => BigRational::one(),
+                };
+                return Polynomial::constant(gcd);
+            }
+        };
+        let (ca, cb) = (a.content(label), b.content(label));
+        let (mut p, mut q) = (a.div_rem(&ca).0, b.div_rem(&cb).0);
+        if p.degree(label) < q.degree(label) {
+            std::mem::swap(&mut p, &mut q);
+        }
+        while !q.is_zero() {
+            let rem = p.pseudo_rem(&q, label);
+            p = q;
+            q = match rem.is_zero() {
+                true => rem,
+                false => rem.primitive_part(label),
+            };
+        }
+        (&ca.gcd(&cb) * &p.primitive_part(label)).normalized()
+    }
+}
+
+impl Polynomial {
+    /// Convert this polynomial to a tree, using Horner's scheme to
+    /// minimize the number of multiplications. The polynomial is
+    /// nested in the variables in sorted order, i.e. the coefficients
+    /// of the powers of the first variable are polynomials in the
+    /// remaining variables, and so on. Coefficients that can't be
+    /// written as decimals, such as one third, become exact rationals.
+    pub fn to_tree(&self) -> Tree {
+        let terms: Vec<(&[u32], &BigRational)> = self.terms().collect();
+        let tree = horner(&terms, &self.vars);
+        // Trees built from polynomials are always valid.
+        tree.deduplicate(&mut Deduplicater::new())
+            .unwrap()
+            .prune(&mut Pruner::new())
+    }
+}
+
+/// Horner's scheme for `terms`, which are the exponents of `vars` and
+/// the coefficients.
+fn horner(terms: &[(&[u32], &BigRational)], vars: &[char]) -> Tree {
+    if terms.is_empty() {
+        return Tree::constant(0.);
+    }
+    let (x, rest) = match vars.split_first() {
+        Some(split) => split,
+        // Only the constant term is left.
+        None => return coefficient(terms[0].1),
+    };
+    // Coefficients of the powers of x, from the highest power.
+    let mut groups = BTreeMap::<u32, Vec<(&[u32], &BigRational)>>::new();
+    for (exps, coeff) in terms.iter() {
+        groups
+            .entry(exps[0])
+            .or_default()
+            .push((&exps[1..], *coeff));
+    }
+    let power = |degree: u32| match degree {
+        1 => Tree::symbol(*x),
+        _ => pow(Tree::symbol(*x), Tree::constant(degree as f64)),
+    };
+    let mut acc: Option<(Tree, u32)> = None;
+    for (degree, coeffs) in groups.iter().rev() {
+        let coeff = horner(coeffs, rest);
+        acc = Some(match acc {
+            None => (coeff, *degree),
+            Some((acc, prev)) => (sum(product(acc, power(prev - degree)), coeff), *degree),
+        });
+    }
+    match acc {
+        Some((acc, 0)) => acc,
+        Some((acc, degree)) => product(acc, power(degree)),
+        None => unreachable!(),
+    }
+}
+
+fn sum(lhs: Tree, rhs: Tree) -> Tree {
+    match rhs.root() {
+        Constant(val) if *val < 0. => lhs - Tree::constant(-val),
+        Rational(val) if val.is_negative() => lhs - Tree::rational(Rational::new(-val.value())),
+        _ => lhs + rhs,
+    }
+}
+
+fn product(lhs: Tree, rhs: Tree) -> Tree {
+    match lhs.root() {
+        Constant(val) if *val == 1. => rhs,
+        Constant(val) if *val == -1. => -rhs,
+        _ => lhs * rhs,
+    }
+}
+
+/// A tree for the coefficient `value`. Coefficients that are the
+/// decimal value of the nearest `f64`, such as one tenth, become
+/// constants. Others, such as one third, become exact rationals.
+pub(crate) fn coefficient(value: &BigRational) -> Tree {
+    match value.to_f64() {
+        Some(approx) if decimal(approx).as_ref() == Some(value) => Tree::constant(approx),
+        _ => Tree::rational(Rational::new(value.clone())),
+    }
+}
+
+/// `value` as the exponent of a polynomial, if it is a non-negative
+/// integer that fits in a `u32`.
+pub(crate) fn as_exponent(value: &BigRational) -> Option<u32> {
+    match value.is_integer() {
+        true => value.to_integer().to_u32(),
+        false => None,
+    }
+}
+
+/// `op` applied to the constant `value`. The result is exact if it
+/// is rational, see `exact::apply_unary`. Otherwise it is computed in
+/// floating point and read back as a decimal, which loses precision.
+/// If that isn't finite, the floating point value is returned as the
+/// error.
+pub(crate) fn fold_unary(op: UnaryOp, value: &BigRational) -> Result<BigRational, f64> {
+    match apply_unary(op, value) {
+        Some(exact) => Ok(exact),
+        None => from_f64(op.apply(approx(value))),
+    }
+}
+
+/// `op` applied to the constants `a` and `b`, like `fold_unary`.
+pub(crate) fn fold_binary(
+    op: BinaryOp,
+    a: &BigRational,
+    b: &BigRational,
+) -> Result<BigRational, f64> {
+    match apply_binary(op, a, b) {
+        Some(exact) => Ok(exact),
+        None => from_f64(op.apply(approx(a), approx(b))),
+    }
+}
+
+fn approx(value: &BigRational) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+fn from_f64(value: f64) -> Result<BigRational, f64> {
+    decimal(value).ok_or(value)
+}
+
+/// Greatest common divisor of the absolute values of `a` and `b`.
+pub(crate) fn gcd_integers(mut a: BigInt, mut b: BigInt) -> BigInt {
+    while !b.is_zero() {
+        let rem = &a % &b;
+        (a, b) = (b, rem);
+    }
+    a.abs()
+}
+
+/// Exponents of the product of two monomials over the same variables,
+/// or `None` if one of them doesn't fit in a `u32`.
+fn multiply_monomials(a: &[u32], b: &[u32]) -> Option<Vec<u32>> {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x.checked_add(*y))
+        .collect()
+}
+
+impl<V: Copy + Ord> Add for &Polynomial<V> {
+    type Output = Polynomial<V>;
+
+    fn add(self, rhs: &Polynomial<V>) -> Polynomial<V> {
+        let (mut lhs, rhs) = self.unified(rhs);
+        lhs.add_terms(&rhs, &BigRational::one());
+        lhs.trimmed()
+    }
+}
+
+impl<V: Copy + Ord> Sub for &Polynomial<V> {
+    type Output = Polynomial<V>;
+
+    fn sub(self, rhs: &Polynomial<V>) -> Polynomial<V> {
+        let (mut lhs, rhs) = self.unified(rhs);
+        lhs.add_terms(&rhs, &-BigRational::one());
+        lhs.trimmed()
+    }
+}
+
+/// # Panics
+///
+/// If an exponent of the product doesn't fit in a `u32`. See
+/// `Polynomial::checked_mul`.
+impl<V: Copy + Ord> Mul for &Polynomial<V> {
+    type Output = Polynomial<V>;
+
+    fn mul(self, rhs: &Polynomial<V>) -> Polynomial<V> {
+        self.checked_mul(rhs).expect("Exponent overflow")
+    }
+}
+
+impl<V: Copy + Ord> Neg for &Polynomial<V> {
+    type Output = Polynomial<V>;
+
+    fn neg(self) -> Polynomial<V> {
+        self.scale(&-BigRational::one())
+    }
+}
+
+macro_rules! forward_binop {
+    ($trait:ident, $method:ident) => {
+        impl<V: Copy + Ord> $trait for Polynomial<V> {
+            type Output = Polynomial<V>;
+
+            fn $method(self, rhs: Polynomial<V>) -> Polynomial<V> {
+                (&self).$method(&rhs)
+            }
+        }
+    };
+}
+
+forward_binop!(Add, add);
+forward_binop!(Sub, sub);
+forward_binop!(Mul, mul);
+
+impl<V: Copy + Ord> Neg for Polynomial<V> {
+    type Output = Polynomial<V>;
+
+    fn neg(self) -> Polynomial<V> {
+        -&self
+    }
+}
+
+impl Tree {
+    /// Convert this tree to a polynomial, if it only consists of
+    /// additions, subtractions, multiplications, negations, divisions
+    /// by non-zero constants and non-negative integer powers of
+    /// symbols and constants. Operations whose inputs are all
+    /// constants are evaluated. Otherwise
+    /// `PolynomialError::NotPolynomial` is returned with the index of
+    /// the offending node. If a constant is not finite,
+    /// `PolynomialError::NotFinite` is returned instead.
+    ///
+    /// The coefficients are exact. Constants are read as the decimal
+    /// numbers they are printed as, so `0.1` is one tenth. Only
+    /// constant operations whose result isn't rational, such as
+    /// `(sqrt 2)`, are evaluated in floating point, see `fold_unary`.
+    pub fn to_polynomial(&self) -> Result<Polynomial, PolynomialError> {
+        let mut polys = Vec::<Polynomial>::with_capacity(self.len());
+        for (index, node) in self.nodes().iter().enumerate() {
+            let finite = |value: Result<BigRational, f64>| match value {
+                Ok(value) => Ok(Polynomial::constant(value)),
+                Err(_) => Err(PolynomialError::NotFinite(index)),
+            };
+            let poly = match *node {
+                Constant(val) => finite(from_f64(val))?,
+                Rational(val) => Polynomial::constant(val.value()),
+                Named(_) => return Err(PolynomialError::NotPolynomial(index)),
+                Symbol(label) => Polynomial::var(label),
+                Unary(Negate, input) => -&polys[input],
+                Unary(op, input) => match polys[input].as_constant() {
+                    Some(val) => finite(fold_unary(op, &val))?,
+                    None => return Err(PolynomialError::NotPolynomial(index)),
+                },
+                Binary(op, lhs, rhs) => {
+                    let (l, r) = (&polys[lhs], &polys[rhs]);
+                    match (op, l.as_constant(), r.as_constant()) {
+                        (Add, ..) => l + r,
+                        (Subtract, ..) => l - r,
+                        (Multiply, ..) => match l.checked_mul(r) {
+                            Some(product) => product,
+                            None => return Err(PolynomialError::NotPolynomial(index)),
+                        },
+                        (_, Some(a), Some(b)) => finite(fold_binary(op, &a, &b))?,
+                        (Divide, _, Some(b)) if !b.is_zero() => l.scale(&b.recip()),
+                        (Pow, _, Some(b)) => match as_exponent(&b).and_then(|k| l.checked_pow(k)) {
+                            Some(power) => power,
+                            None => return Err(PolynomialError::NotPolynomial(index)),
+                        },
+                        _ => return Err(PolynomialError::NotPolynomial(index)),
+                    }
+                }
+            };
+            polys.push(poly);
+        }
+        Ok(polys.pop().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deftree, verify::numerically_equivalent};
+
+    fn int(value: i64) -> BigRational {
+        BigRational::from_integer(value.into())
+    }
+
+    #[test]
+    fn t_polynomial_arithmetic() {
+        let (x, y) = (Polynomial::var('x'), Polynomial::var('y'));
+        let one = Polynomial::constant(int(1));
+        let p = (&x + &one) * (&x - &one);
+        assert_eq!(p, x.pow(2) - one.clone());
+        assert_eq!(p.vars(), &['x']);
+        assert_eq!(p.degree('x'), 2);
+        assert_eq!(p.degree('y'), 0);
+        let q = (&x + &y).pow(3);
+        assert_eq!(q.total_degree(), Some(3));
+        assert_eq!(q.terms().count(), 4);
+        assert!(q
+            .terms()
+            .all(|(exps, c)| exps.len() == 2 && (*c == int(1) || *c == int(3))));
+        // Variables that cancel out are removed.
+        let r = &q - &y.pow(3);
+        assert_eq!(r.vars(), &['x', 'y']);
+        let r = &(&x + &y) - &y;
+        assert_eq!(r, x);
+        assert!((&r - &x).is_zero());
+        assert_eq!((-&one).as_constant(), Some(int(-1)));
+        assert_eq!(x.as_constant(), None);
+        // Exponents that don't fit in a u32.
+        let big = x.pow(u32::MAX);
+        assert_eq!((&big * &y).total_degree(), None);
+        assert!(big.checked_mul(&x).is_none());
+        assert!(x.pow(2).checked_pow(u32::MAX / 2 + 1).is_none());
+    }
+
+    #[test]
+    fn t_tree_to_polynomial() {
+        let a = deftree!(* (+ x y) (- x y)).to_polynomial().unwrap();
+        let b = deftree!(- (pow x 2) (/ (* 2 (pow y 2)) 2))
+            .to_polynomial()
+            .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(
+            deftree!(+ (* x (sqrt 4)) (pow 2 3))
+                .to_polynomial()
+                .unwrap(),
+            Polynomial::var('x').scale(&int(2)) + Polynomial::constant(int(8))
+        );
+        assert!(matches!(
+            deftree!(+ x (sin y)).to_polynomial(),
+            Err(PolynomialError::NotPolynomial(2))
+        ));
+        assert!(matches!(
+            deftree!(/ 1 x).to_polynomial(),
+            Err(PolynomialError::NotPolynomial(2))
+        ));
+        assert!(matches!(
+            deftree!(pow x 0.5).to_polynomial(),
+            Err(PolynomialError::NotPolynomial(2))
+        ));
+        assert!(matches!(
+            deftree!(* (pow x 3000000000.) (pow x 3000000000.)).to_polynomial(),
+            Err(PolynomialError::NotPolynomial(6))
+        ));
+        assert!(matches!(
+            deftree!(pow (pow x 3000000000.) 2).to_polynomial(),
+            Err(PolynomialError::NotPolynomial(4))
+        ));
+        assert!(matches!(
+            deftree!(* (sqrt (- 1)) x).to_polynomial(),
+            Err(PolynomialError::NotFinite(2))
+        ));
+        // Constants are read as decimals, and the coefficients are
+        // exact.
+        assert!(deftree!(- 0.3 (* 0.1 3)).to_polynomial().unwrap().is_zero());
+        assert_eq!(
+            deftree!(/ x 3).to_polynomial().unwrap().to_tree().to_lisp(),
+            "(* 1/3 x)"
+        );
+        // Exact, even where floating point would overflow.
+        assert!(deftree!(- (* x (pow 10 400)) (* x (pow 10 400)))
+            .to_polynomial()
+            .unwrap()
+            .is_zero());
+    }
+
+    #[test]
+    fn t_polynomial_horner() {
+        let tree = deftree!(+ (- (* 3 (pow x 3)) (* 2 x)) 5);
+        let horner = tree.to_polynomial().unwrap().to_tree();
+        assert_eq!(horner.to_lisp(), "(+ (* (- (* 3 (pow x 2)) 2) x) 5)");
+        numerically_equivalent(&tree, &horner, &[('x', -5., 5.)], 100, 1e-9).unwrap();
+        let tree = deftree!(- (* (+ x (* 2 y)) (+ (pow x 2) y)) (pow y 4));
+        let poly = tree.to_polynomial().unwrap();
+        let horner = poly.to_tree();
+        numerically_equivalent(&tree, &horner, &[('x', -5., 5.), ('y', -5., 5.)], 100, 1e-9)
+            .unwrap();
+        assert_eq!(horner.to_polynomial().unwrap(), poly);
+        assert_eq!(Polynomial::zero().to_tree().to_lisp(), "0");
+        assert_eq!(Polynomial::var('x').pow(4).to_tree().to_lisp(), "(pow x 4)");
+    }
+
+    #[test]
+    fn t_polynomial_gcd() {
+        let poly = |tree: Tree| tree.to_polynomial().unwrap();
+        let a = poly(deftree!(* (+ x y) (- x 1)));
+        let b = poly(deftree!(* (+ x y) (+ x 2)));
+        assert_eq!(a.gcd(&b), poly(deftree!(+ x y)));
+        assert_eq!(
+            poly(deftree!(* 6 x)).gcd(&poly(deftree!(* 4 (pow x 2)))),
+            poly(deftree!(* 2 x))
+        );
+        assert_eq!(
+            poly(deftree!(+ x 1)).gcd(&poly(deftree!(- x 1))),
+            Polynomial::constant(int(1))
+        );
+        assert_eq!(a.gcd(&Polynomial::zero()), a);
+        let (q, r) = poly(deftree!(+ (pow x 3) 1)).div_rem(&poly(deftree!(+ x 1)));
+        assert_eq!(q, poly(deftree!(+ (- (pow x 2) x) 1)));
+        assert!(r.is_zero());
+        let (q, r) = poly(deftree!(+ (pow x 2) 1)).div_rem(&poly(deftree!(+ x 1)));
+        assert_eq!(q, poly(deftree!(- x 1)));
+        assert_eq!(r, Polynomial::constant(int(2)));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     tree::{Node, Node::*, Tree},
-    walk::{DepthWalker, NodeOrdering},
+    walk::DepthWalker,
 };
 
 /// Tree pruner.
@@ -34,7 +34,7 @@ impl Pruner {
         self.indices.resize(nodes.len(), 0);
         // Mark used nodes.
         self.walker
-            .walk_nodes(&nodes, root_index, true, NodeOrdering::Original)
+            .walk_nodes(&nodes, root_index, true)
             .for_each(|(index, _parent)| {
                 self.indices[index] = 1;
             });
@@ -56,6 +56,8 @@ impl Pruner {
                 // We subtract 1 from all indices because we did an inclusive sum.
                 self.pruned.push(match nodes[i] {
                     Constant(val) => Constant(val),
+                    Rational(val) => Rational(val),
+                    Named(val) => Named(val),
                     Symbol(label) => Symbol(label),
                     Unary(op, input) => Unary(op, self.indices[input] - 1),
                     Binary(op, lhs, rhs) => {
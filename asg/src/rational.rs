@@ -0,0 +1,375 @@
+use crate::{
+    dedup::Deduplicater,
+    exact::decimal,
+    poly::{as_exponent, fold_binary, fold_unary, Polynomial},
+    prune::Pruner,
+    tree::{BinaryOp::*, Node::*, Tree, TreeError, UnaryOp::*},
+};
+use num_rational::BigRational;
+use num_traits::{One, Signed};
+
+/*
+Rational functions are normalized bottom up. Every subtree that is
+built from constants and symbols using additions, subtractions,
+multiplications, divisions and integer powers is represented as a
+fraction of two polynomials. Whenever two fractions are combined, the
+greatest common divisor of the numerator and the denominator is
+cancelled. Other operations, such as `sin` or `min`, are kept as they
+are, with the fractions in their inputs converted back to trees.
+
+Cancelling a common factor changes the domain of the tree. The
+original `(x^2 - 1) / (x - 1)` is undefined at `x = 1`, but the
+simplified `x + 1` is defined everywhere. The two trees agree wherever
+the original tree is defined, and the cancelled factors are reported
+alongside the simplified tree, so the caller can keep the condition
+that they are non-zero if it matters.
+*/
+
+/// Result of cancelling the common factors of rational functions in a
+/// tree.
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    /// The simplified tree.
+    pub tree: Tree,
+    /// Factors that were cancelled, and denominators that moved to a
+    /// numerator, such as `y` in `x / (1 / y)`. The original tree is
+    /// undefined wherever one of these is zero, but the simplified
+    /// tree may be defined there.
+    pub nonzero: Vec<Tree>,
+}
+
+/// Numerator and denominator of a rational function, without common
+/// factors.
+#[derive(Clone)]
+struct Fraction {
+    num: Polynomial,
+    den: Polynomial,
+}
+
+/// A subtree, either as a rational function, as a tree if it isn't
+/// one, or left as it is in the original tree if nothing was
+/// cancelled in it.
+enum Part {
+    Fraction(Fraction),
+    Other(Tree),
+    Original,
+}
+
+/// The factors cancelled so far.
+#[derive(Default)]
+struct Cancelled {
+    factors: Vec<Polynomial>,
+    /// Number of cancellations, including repeated factors.
+    count: usize,
+}
+
+impl Cancelled {
+    /// Record that `factor` was cancelled, unless it is a constant.
+    fn push(&mut self, factor: &Polynomial) {
+        if factor.as_constant().is_some() {
+            return;
+        }
+        self.count += 1;
+        if !self.factors.contains(factor) {
+            self.factors.push(factor.clone());
+        }
+    }
+
+    /// Record the `factors`, which could be zero in the inputs of
+    /// `frac`, if they no longer divide the denominator of `frac`.
+    fn vanished(&mut self, frac: &Fraction, factors: &[&Polynomial]) {
+        for factor in factors {
+            if factor.as_constant().is_none() && !frac.den.div_rem(factor).1.is_zero() {
+                self.push(factor);
+            }
+        }
+    }
+}
+
+impl Fraction {
+    fn from(num: Polynomial) -> Fraction {
+        Fraction {
+            num,
+            den: Polynomial::constant(BigRational::one()),
+        }
+    }
+
+    /// The fraction `num / den` after cancelling common factors. The
+    /// cancelled factors are recorded in `cancelled`.
+    fn reduced(num: Polynomial, den: Polynomial, cancelled: &mut Cancelled) -> Fraction {
+        let gcd = num.gcd(&den);
+        let (mut num, mut den) = match gcd.as_constant() {
+            Some(_) => (num, den),
+            None => {
+                cancelled.push(&gcd);
+                (num.div_rem(&gcd).0, den.div_rem(&gcd).0)
+            }
+        };
+        // Keep the leading coefficient of the denominator positive.
+        if den
+            .leading_term()
+            .is_some_and(|(_, lead)| lead.is_negative())
+        {
+            (num, den) = (-num, -den);
+        }
+        Fraction { num, den }
+    }
+
+    fn as_constant(&self) -> Option<BigRational> {
+        Some(self.num.as_constant()? / self.den.as_constant()?)
+    }
+
+    fn to_tree(&self) -> Tree {
+        match self.den.as_constant() {
+            Some(den) if den.is_one() => self.num.to_tree(),
+            _ => self.num.to_tree() / self.den.to_tree(),
+        }
+    }
+}
+
+impl Part {
+    fn constant(value: BigRational) -> Part {
+        Part::Fraction(Fraction::from(Polynomial::constant(value)))
+    }
+
+    /// The result of folding constants. Values that are not finite
+    /// can't be coefficients, so they are kept as trees.
+    fn folded(value: Result<BigRational, f64>) -> Part {
+        match value {
+            Ok(value) => Part::constant(value),
+            Err(value) => Part::Other(Tree::constant(value)),
+        }
+    }
+
+    fn as_constant(&self) -> Option<BigRational> {
+        match self {
+            Part::Fraction(frac) => frac.as_constant(),
+            Part::Other(_) | Part::Original => None,
+        }
+    }
+}
+
+/// The simplified tree of the node at `index` of `tree`. Subtrees in
+/// which nothing was cancelled are copied from `tree`.
+fn output(tree: &Tree, parts: &[Part], changed: &[bool], index: usize) -> Result<Tree, TreeError> {
+    Ok(match (&parts[index], changed[index]) {
+        (Part::Fraction(frac), true) => frac.to_tree(),
+        (Part::Other(tree), true) => tree.clone(),
+        _ => Tree::from_nodes(tree.nodes()[..=index].to_vec())?.prune(&mut Pruner::new()),
+    })
+}
+
+impl Tree {
+    /// Normalize the rational functions in this tree, by representing
+    /// them as fractions of polynomials, and cancelling the greatest
+    /// common divisor of the numerator and the denominator. For
+    /// example `(x^2 - 1) / (x - 1)` becomes `x + 1`. The
+    /// coefficients are exact, as in `Tree::to_polynomial`. The
+    /// polynomials are converted back to trees using Horner's scheme.
+    /// Subtrees in which nothing is cancelled are left as they are.
+    ///
+    /// The simplified tree agrees with this tree wherever this tree
+    /// is defined, but may be defined in more places. The cancelled
+    /// factors, and the denominators that disappear, are returned, see
+    /// `Cancellation`. If the result contains NaN, the appropriate
+    /// `TreeError` is returned.
+    pub fn cancel(self) -> Result<Cancellation, TreeError> {
+        let mut cancelled = Cancelled::default();
+        let mut parts = Vec::<Part>::with_capacity(self.len());
+        let mut changed = Vec::<bool>::with_capacity(self.len());
+        for node in self.nodes() {
+            let before = cancelled.count;
+            let part = match *node {
+                Constant(val) => match decimal(val) {
+                    Some(val) => Part::constant(val),
+                    None => Part::Original,
+                },
+                Rational(val) => Part::constant(val.value()),
+                Named(_) => Part::Original,
+                Symbol(label) => Part::Fraction(Fraction::from(Polynomial::var(label))),
+                Unary(Negate, input) => match &parts[input] {
+                    Part::Fraction(frac) => Part::Fraction(Fraction {
+                        num: -&frac.num,
+                        den: frac.den.clone(),
+                    }),
+                    _ if !changed[input] => Part::Original,
+                    _ => Part::Other(-output(&self, &parts, &changed, input)?),
+                },
+                Unary(op, input) => match parts[input].as_constant() {
+                    Some(val) => Part::folded(fold_unary(op, &val)),
+                    None if !changed[input] => Part::Original,
+                    None => Part::Other(output(&self, &parts, &changed, input)?.unary_op(op)),
+                },
+                Binary(op, lhs, rhs) => {
+                    let (l, r) = (&parts[lhs], &parts[rhs]);
+                    let frac = match (op, l, r) {
+                        // Products and powers whose exponents overflow
+                        // are kept as they are.
+                        (Add | Subtract, Part::Fraction(a), Part::Fraction(b)) => {
+                            let terms = if a.den == b.den {
+                                Some((a.num.clone(), b.num.clone(), a.den.clone()))
+                            } else {
+                                a.num.checked_mul(&b.den).and_then(|lnum| {
+                                    let rnum = b.num.checked_mul(&a.den)?;
+                                    Some((lnum, rnum, a.den.checked_mul(&b.den)?))
+                                })
+                            };
+                            terms.map(|(lnum, rnum, den)| {
+                                let num = match op {
+                                    Add => &lnum + &rnum,
+                                    _ => &lnum - &rnum,
+                                };
+                                Fraction::reduced(num, den, &mut cancelled)
+                            })
+                        }
+                        (Multiply, Part::Fraction(a), Part::Fraction(b)) => {
+                            match (a.num.checked_mul(&b.num), a.den.checked_mul(&b.den)) {
+                                (Some(num), Some(den)) => {
+                                    Some(Fraction::reduced(num, den, &mut cancelled))
+                                }
+                                _ => None,
+                            }
+                        }
+                        (Divide, Part::Fraction(a), Part::Fraction(b)) if !b.num.is_zero() => {
+                            match (a.num.checked_mul(&b.den), a.den.checked_mul(&b.num)) {
+                                (Some(num), Some(den)) => {
+                                    let frac = Fraction::reduced(num, den, &mut cancelled);
+                                    // The denominator of the divisor moves
+                                    // to the numerator.
+                                    cancelled.vanished(&frac, &[&b.num, &b.den]);
+                                    Some(frac)
+                                }
+                                _ => None,
+                            }
+                        }
+                        // Powers of constants are folded below.
+                        (Pow, Part::Fraction(a), _) if a.as_constant().is_none() => {
+                            let k = r.as_constant();
+                            let powers = k.as_ref().and_then(|k| {
+                                let n = as_exponent(&k.abs())?;
+                                Some((a.num.checked_pow(n)?, a.den.checked_pow(n)?, k))
+                            });
+                            match powers {
+                                Some((num, den, k)) if !k.is_negative() => {
+                                    Some(Fraction { num, den })
+                                }
+                                Some((num, den, _)) => {
+                                    let frac = Fraction::reduced(den, num, &mut cancelled);
+                                    cancelled.vanished(&frac, &[&a.num, &a.den]);
+                                    Some(frac)
+                                }
+                                None => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    match (frac, l.as_constant(), r.as_constant()) {
+                        (Some(frac), ..) => Part::Fraction(frac),
+                        (None, Some(a), Some(b)) => Part::folded(fold_binary(op, &a, &b)),
+                        (None, ..) if !changed[lhs] && !changed[rhs] => Part::Original,
+                        (None, ..) => Part::Other(
+                            output(&self, &parts, &changed, lhs)?
+                                .binary_op(output(&self, &parts, &changed, rhs)?, op),
+                        ),
+                    }
+                }
+            };
+            changed.push(
+                cancelled.count > before
+                    || match *node {
+                        Constant(_) | Rational(_) | Named(_) | Symbol(_) => false,
+                        Unary(_, input) => changed[input],
+                        Binary(_, lhs, rhs) => changed[lhs] || changed[rhs],
+                    },
+            );
+            parts.push(part);
+        }
+        let tree = output(&self, &parts, &changed, self.root_index())?
+            .validated()?
+            .deduplicate(&mut Deduplicater::new())?
+            .prune(&mut Pruner::new());
+        Ok(Cancellation {
+            tree,
+            nonzero: cancelled.factors.iter().map(|p| p.to_tree()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{deftree, tree::TreeError, verify::numerically_equivalent};
+
+    #[test]
+    fn t_cancel() {
+        let tree = deftree!(/ (- (pow x 2) 1) (- x 1));
+        let result = tree.clone().cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(+ x 1)");
+        let nonzero: Vec<_> = result.nonzero.iter().map(|t| t.to_lisp()).collect();
+        assert_eq!(nonzero, vec!["(- x 1)"]);
+        // The trees differ at x = 1, where the original is undefined.
+        numerically_equivalent(&tree, &result.tree, &[('x', 1.5, 5.)], 100, 1e-9).unwrap();
+        // Nothing to cancel.
+        let result = deftree!(/ 1 x).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(/ 1 x)");
+        assert!(result.nonzero.is_empty());
+        let result = deftree!(/ x x).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "1");
+        assert_eq!(result.nonzero.len(), 1);
+        // Sums of fractions.
+        let result = deftree!(+ (/ a (+ b 1)) (/ (* a b) (+ b 1)))
+            .cancel()
+            .unwrap();
+        assert_eq!(result.tree.to_lisp(), "a");
+        assert_eq!(result.nonzero[0].to_lisp(), "(+ b 1)");
+        // Denominators that disappear are reported too.
+        let result = deftree!(/ x (/ 1 y)).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(* y x)");
+        let nonzero: Vec<_> = result.nonzero.iter().map(|t| t.to_lisp()).collect();
+        assert_eq!(nonzero, vec!["y"]);
+        let result = deftree!(pow (/ x (+ y 1)) (- 1)).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(/ (+ y 1) x)");
+        let nonzero: Vec<_> = result.nonzero.iter().map(|t| t.to_lisp()).collect();
+        assert_eq!(nonzero, vec!["(+ y 1)"]);
+        // Subtrees in which nothing cancels are left as they are.
+        let result = deftree!(* (+ x 1) (+ x 1)).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(* (+ x 1) (+ x 1))");
+        assert!(result.nonzero.is_empty());
+        let result = deftree!(* (sqrt (- 1)) x).cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(* (sqrt (- 1)) x)");
+        assert!(matches!(
+            deftree!(* (sqrt (- (/ x x) 2)) x).cancel(),
+            Err(TreeError::ContainsNaN)
+        ));
+        // Exponents that don't fit in a u32.
+        let result = deftree!(* (pow x 3000000000.) (pow x 3000000000.))
+            .cancel()
+            .unwrap();
+        assert_eq!(
+            result.tree.to_lisp(),
+            "(* (pow x 3000000000) (pow x 3000000000))"
+        );
+    }
+
+    #[test]
+    fn t_cancel_multivariate() {
+        let tree = deftree!(/ (- (* (pow x 2) y) y) (* 2 (+ (* x y) y)));
+        let result = tree.clone().cancel().unwrap();
+        assert_eq!(result.tree.to_lisp(), "(/ (- x 1) 2)");
+        numerically_equivalent(
+            &tree,
+            &result.tree,
+            &[('x', 0., 5.), ('y', 0.5, 5.)],
+            100,
+            1e-9,
+        )
+        .unwrap();
+        // Negative powers and nested non-polynomial operations.
+        let tree = deftree!(+ (sin (* (pow (+ x 1) (- 2)) (- (pow x 2) 1))) (pow x 0.5));
+        let result = tree.clone().cancel().unwrap();
+        assert_eq!(
+            result.tree.to_lisp(),
+            "(+ (sin (/ (- x 1) (+ x 1))) (pow x 0.5))"
+        );
+        numerically_equivalent(&tree, &result.tree, &[('x', 0., 5.)], 100, 1e-9).unwrap();
+    }
+}
@@ -1,237 +1,325 @@
 use crate::{
-    mutate::{MutationError, Mutations, TemplateCapture},
-    template::get_templates,
+    cost::{CostFunction, Heuristic, NodeCount},
+    egraph::EGraph,
+    facts::Facts,
+    mutate::{MutationError, TemplateCapture},
+    template::RuleSet,
     tree::Tree,
-    tree::{Node, Node::*},
 };
-use std::collections::{BinaryHeap, HashMap};
+use std::{
+    sync::atomic::AtomicBool,
+    time::{Duration, Instant},
+};
+
+pub use crate::egraph::SaturationLimits;
 
-struct Heuristic {
-    stack: Vec<(usize, usize)>, // index, depth
-    last_visit: Vec<Option<usize>>,
+/// One step of a simplification, i.e. the application of one
+/// template to the previous tree.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// The tree after this step.
+    pub tree: Tree,
+    /// Name of the template that was applied.
+    pub rule: String,
+    /// Index of the node in the previous tree that matched the ping
+    /// of the template.
+    pub node_index: usize,
+    /// Symbols of the template, and the indices of the nodes in the
+    /// previous tree they were bound to.
+    pub bindings: Vec<(char, usize)>,
 }
 
-/// If two nodes have the same node as their one of their inputs, it
-/// creates a diamond shaped loop. If this loop is small, i.e. if the
-/// lowest common ancestor of the two nodes is near by, It is more
-/// likely that a known template will match with the tree and be able
-/// to simplify it. The euler-walk heuristic penalizes diamond shaped
-/// loops based on their size, i.e. larger loops have a higher cost
-/// than smaller loops. While the heuristic tries to add up the
-/// lengths of all diamond shaped loops, the value might not always be
-/// exact, depending on the traversal order. But the value is
-/// guaranteed to have a positive correlation with the number of
-/// diamond shaped loops and their sizes.
-impl Heuristic {
-    pub fn new() -> Heuristic {
-        Heuristic {
-            stack: Vec::new(),
-            last_visit: Vec::new(),
-        }
-    }
+/// Progress of a search, reported to `ReduceOptions::progress` after
+/// each explored candidate.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Number of candidates explored so far.
+    pub iterations: usize,
+    /// Number of candidates waiting to be explored.
+    pub heap_size: usize,
+    /// Lowest `select` cost of the candidates explored so far.
+    pub best_cost: usize,
+    /// Time since the search started.
+    pub elapsed: Duration,
+}
 
-    /// In a typical depth first traversal, you just push the children
-    /// of the current node onto the stack. Instead, if you also push
-    /// the node itself, before every child, it results in an euler
-    /// walk. This is very useful because for any pair of nodes 'a'
-    /// and 'b', euler-walk necessarily contains a subpath that starts
-    /// at 'a' and ends at 'b' or starts at 'b' and ends at
-    /// 'a'. Furthermore, this subpath necessarily goes through the
-    /// lowest common ancestor of 'a' and 'b'. That means, if we're
-    /// visiting a node for the second (or more) time from a parent,
-    /// we've detected a diamond shaped loop, and the number of nodes
-    /// traversed since the last visit roughly correlates to the size
-    /// of the diamond shaped loop.
-    fn euler_walk_cost(&mut self, nodes: &[Node], root: usize) -> usize {
-        // Reset all buffers.
-        self.stack.clear();
-        self.stack.reserve(nodes.len());
-        self.last_visit.clear();
-        self.last_visit.resize(nodes.len(), None);
-        // Start the Euler walk.
-        self.stack.push((root, 0));
-        let mut prevdepth: usize = 0;
-        let mut counter: usize = 0;
-        let mut sum: usize = 0;
-        while let Some((i, depth)) = self.stack.pop() {
-            match self.last_visit[i] {
-                // Accumulate the size of the diamond shaped loop.
-                Some(last) if prevdepth < depth => sum += counter - last,
-                // Push children if visiting for the first time.
-                None => match &nodes[i] {
-                    Constant(_) | Symbol(_) => {} // No children to push.
-                    Unary(_, input) => self
-                        .stack
-                        .extend_from_slice(&[(i, depth), (*input, depth + 1)]),
-                    Binary(_, lhs, rhs) => self.stack.extend_from_slice(&[
-                        (i, depth),
-                        (*rhs, depth + 1),
-                        (i, depth),
-                        (*lhs, depth + 1),
-                    ]),
-                },
-                _ => {} // Do nothing.
-            }
-            // Record visit, update counter and depth.
-            self.last_visit[i] = Some(counter);
-            counter += 1;
-            prevdepth = depth;
+/// Receives the progress of a search.
+pub type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+/// Why a search stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// There were no candidates left to explore.
+    Exhausted,
+    /// `ReduceOptions::max_iter` candidates were explored.
+    MaxIterations,
+    /// `ReduceOptions::deadline` has passed.
+    Deadline,
+    /// `ReduceOptions::cancel` was set.
+    Cancelled,
+}
+
+/// Budgets and hooks for `reduce_with_options`. When any of the
+/// budgets runs out, the search stops and the best tree found so far
+/// is returned.
+pub struct ReduceOptions<'a> {
+    /// Facts about the symbols, used to check the guards of the
+    /// templates.
+    pub facts: Facts,
+    /// Maximum number of candidates to explore.
+    pub max_iter: usize,
+    /// Stop searching after this instant.
+    pub deadline: Option<Instant>,
+    /// Maximum number of candidates waiting to be explored. When
+    /// there are more, the most expensive ones are dropped.
+    pub max_heap_size: Option<usize>,
+    /// Candidates with more than this many nodes in addition to the
+    /// nodes of the (compacted) starting tree are not explored.
+    pub max_growth: Option<usize>,
+    /// Maximum number of ways to group the operands of each sum or
+    /// product when matching a template with it.
+    pub max_groupings: Option<usize>,
+    /// Stop searching as soon as this is set. It is checked before
+    /// exploring each candidate, so it can be set from another thread.
+    pub cancel: Option<&'a AtomicBool>,
+    /// Called after each explored candidate.
+    pub progress: Option<ProgressCallback<'a>>,
+}
+
+impl Default for ReduceOptions<'_> {
+    fn default() -> Self {
+        ReduceOptions {
+            facts: Facts::new(),
+            max_iter: 100,
+            deadline: None,
+            max_heap_size: None,
+            max_growth: None,
+            max_groupings: None,
+            cancel: None,
+            progress: None,
         }
-        return sum;
     }
+}
 
-    fn cost(&mut self, tree: &Tree) -> usize {
-        tree.len() + self.euler_walk_cost(tree.nodes(), tree.root_index())
-    }
+/// The result of `reduce_with_options`.
+#[derive(Debug, Clone)]
+pub struct Reduction {
+    /// Steps leading to the best tree that was found.
+    pub steps: Vec<Step>,
+    /// Why the search stopped.
+    pub stop: StopReason,
+    /// Number of candidates that were explored.
+    pub iterations: usize,
+}
+
+/// Simplify `tree` using the templates in `rules`. At most
+/// `max_iter` candidates are explored. The steps leading from `tree`
+/// to the simplest tree that was found are returned, along with the
+/// templates that were applied in each step.
+///
+/// Nothing is assumed about the symbols in `tree`, so templates with
+/// guards are only applied where the guards can be inferred from the
+/// tree itself. Use `reduce_with_facts` to provide known facts.
+pub fn reduce(tree: Tree, rules: &RuleSet, max_iter: usize) -> Result<Vec<Step>, MutationError> {
+    reduce_with_facts(tree, rules, Facts::new(), max_iter)
 }
 
-struct Candidate {
+/// Same as `reduce`, but the guards of the templates are checked
+/// against the given `facts` about the symbols in `tree`.
+pub fn reduce_with_facts(
     tree: Tree,
-    prev: usize,
-    steps: usize,
-    complexity: usize,
+    rules: &RuleSet,
+    facts: Facts,
+    max_iter: usize,
+) -> Result<Vec<Step>, MutationError> {
+    let mut search = Heuristic::new();
+    let mut select = Heuristic::new();
+    reduce_with_costs(tree, rules, facts, &mut search, &mut select, max_iter)
 }
 
-impl Candidate {
-    pub fn cost(&self) -> usize {
-        self.steps + self.complexity
-    }
+/// Same as `reduce_with_facts`, but with custom cost functions. The
+/// `search` cost decides which candidates are explored first, and
+/// the `select` cost decides which of the explored candidates is
+/// returned. For example, the search can be guided by a cost that
+/// favors trees that are likely to simplify further, while the
+/// result is the tree that is cheapest to evaluate.
+pub fn reduce_with_costs<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    facts: Facts,
+    search: &mut S,
+    select: &mut F,
+    max_iter: usize,
+) -> Result<Vec<Step>, MutationError> {
+    let options = ReduceOptions {
+        facts,
+        max_iter,
+        ..Default::default()
+    };
+    Ok(reduce_with_options(tree, rules, options, search, select)?.steps)
 }
 
-impl PartialOrd for Candidate {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.cost().partial_cmp(&self.cost())
-    }
+/// Same as `reduce_with_costs`, but the search is bounded by the
+/// budgets in `options`, can be cancelled, and reports its progress.
+/// When the search stops for any reason, the steps leading to the
+/// best tree explored so far are returned.
+pub fn reduce_with_options<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions<'_>,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    crate::search::best_first(tree, rules, options, search, select)
 }
 
-impl PartialEq for Candidate {
-    fn eq(&self, other: &Self) -> bool {
-        self.tree == other.tree
-    }
+/// Same as `reduce_parallel_with_costs`, using `Heuristic` as both
+/// the search and the select cost.
+pub fn reduce_parallel(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions<'_>,
+    threads: usize,
+) -> Result<Reduction, MutationError> {
+    let mut search = Heuristic::new();
+    let mut select = Heuristic::new();
+    reduce_parallel_with_costs(tree, rules, options, &mut search, &mut select, threads)
 }
-impl Ord for Candidate {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.cost().cmp(&self.cost())
+
+/// Same as `reduce_with_options`, but up to `threads` candidates are
+/// expanded at the same time, each on its own thread with its own
+/// `TemplateCapture` and its own clone of the `search` cost. The
+/// candidates are explored in batches: the `threads` best candidates
+/// are taken from the heap, their mutations are found in parallel,
+/// and the mutations are added to the heap in the order of the
+/// candidates they came from. The threads skip mutations that were
+/// already explored, using the set of explored trees shared by all
+/// threads. The result only depends on the inputs and the number of
+/// threads, not on how the threads are scheduled.
+pub fn reduce_parallel_with_costs<S, F>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions<'_>,
+    search: &mut S,
+    select: &mut F,
+    threads: usize,
+) -> Result<Reduction, MutationError>
+where
+    S: CostFunction + Clone + Send,
+    F: CostFunction,
+{
+    crate::search::parallel(tree, rules, options, search, select, threads)
+}
+
+/// How `reduce_with_strategy` searches for simpler trees. Every
+/// strategy respects the budgets in `ReduceOptions`, and returns the
+/// best tree it explored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always explore the cheapest candidate found so far. This is
+    /// what `reduce_with_options` does.
+    BestFirst,
+    /// Explore the trees one step at a time, keeping only the `width`
+    /// cheapest new trees of each step.
+    Beam { width: usize },
+    /// Depth first search up to one step from the start, then up to
+    /// two steps, and so on until `max_depth` steps.
+    IterativeDeepening { max_depth: usize },
+    /// Repeatedly apply a randomly chosen template that does not
+    /// increase the search cost, until there are none left. Then
+    /// start over from the original tree, `restarts` times. The
+    /// random choices are determined by `seed`.
+    HillClimbing { restarts: usize, seed: u64 },
+}
+
+impl Strategy {
+    /// Simplify `tree` with this strategy, using `Heuristic` as both
+    /// the search and the select cost.
+    pub fn reduce(
+        &self,
+        tree: Tree,
+        rules: &RuleSet,
+        options: ReduceOptions<'_>,
+    ) -> Result<Reduction, MutationError> {
+        let mut search = Heuristic::new();
+        let mut select = Heuristic::new();
+        reduce_with_strategy(tree, rules, options, *self, &mut search, &mut select)
     }
 }
-impl Eq for Candidate {}
-
-pub fn reduce(tree: Tree, max_iter: usize) -> Result<Vec<Tree>, MutationError> {
-    let mut capture = TemplateCapture::new();
-    let tree = capture.make_compact_tree(tree, None)?;
-    let mut hfn = Heuristic::new();
-    let mut explored = Vec::<Candidate>::with_capacity(max_iter);
-    let mut indexmap = HashMap::<u64, usize>::new();
-    let mut hashbuf = Vec::<u64>::new();
-    let mut heap = BinaryHeap::<Candidate>::with_capacity(get_templates().len() * max_iter / 2); // Estimate.
-    let mut min_complexity = usize::MAX;
-    let mut best_candidate = 0;
-    let start_complexity = hfn.cost(&tree);
-    heap.push(Candidate {
-        tree,
-        prev: 0,
-        steps: 0,
-        complexity: start_complexity,
-    });
-    while let Some(cand) = heap.pop() {
-        let hash = cand.tree.hash(&mut hashbuf);
-        let index = explored.len();
-        match indexmap.insert(hash, index) {
-            Some(_old) => {
-                continue;
-            }
-            None => {
-                explored.push(cand);
-            }
-        }
-        let cand = explored.last().unwrap();
-        if cand.complexity < min_complexity {
-            min_complexity = cand.complexity;
-            best_candidate = index;
-        }
-        if explored.len() == max_iter {
-            break;
+
+/// Same as `reduce_with_options`, but the candidates are explored
+/// using the given `strategy`. `ReduceOptions::max_heap_size` only
+/// applies to `Strategy::BestFirst`.
+pub fn reduce_with_strategy<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions<'_>,
+    strategy: Strategy,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    use crate::search::{beam, best_first, hill_climbing, iterative_deepening};
+    match strategy {
+        Strategy::BestFirst => best_first(tree, rules, options, search, select),
+        Strategy::Beam { width } => beam(tree, rules, options, width, search, select),
+        Strategy::IterativeDeepening { max_depth } => {
+            iterative_deepening(tree, rules, options, max_depth, search, select)
         }
-        for mutation in Mutations::of(&cand.tree, &mut capture) {
-            let tree = mutation?;
-            let complexity = hfn.cost(&tree);
-            heap.push(Candidate {
-                tree,
-                prev: index,
-                steps: cand.steps + 1,
-                complexity,
-            });
+        Strategy::HillClimbing { restarts, seed } => {
+            hill_climbing(tree, rules, options, restarts, seed, search, select)
         }
     }
-    let mut steps = Vec::<Tree>::new();
-    let mut i = best_candidate;
-    while explored[i].prev != i {
-        let cand = &explored[i];
-        steps.push(cand.tree.clone());
-        i = cand.prev;
-    }
-    steps.reverse();
-    return Ok(steps);
+}
+
+/// Simplify `tree` using equality saturation. Instead of searching
+/// through one tree at a time like `reduce`, all templates in `rules`
+/// are applied to an e-graph, which represents all the trees that
+/// were found so far at once. This is done until no new trees can be
+/// found, or one of the `limits` is reached. Then the tree with the
+/// fewest nodes is returned. Unlike `reduce`, the steps leading to
+/// that tree are not known.
+///
+/// The guards of the templates are checked against `facts`.
+pub fn saturate(
+    tree: Tree,
+    rules: &RuleSet,
+    facts: Facts,
+    limits: &SaturationLimits,
+) -> Result<Tree, MutationError> {
+    saturate_with_cost(tree, rules, facts, &mut NodeCount, limits)
+}
+
+/// Same as `saturate`, but the tree with the lowest `cost` is
+/// returned. The tree is chosen using `CostFunction::node_cost`.
+pub fn saturate_with_cost<C: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    facts: Facts,
+    cost: &mut C,
+    limits: &SaturationLimits,
+) -> Result<Tree, MutationError> {
+    let mut graph = EGraph::new(facts);
+    let root = graph.add_tree(&tree);
+    graph.saturate(rules, limits);
+    let tree = graph
+        .extract(root, cost)
+        .map_err(MutationError::TreeCreationError)?;
+    TemplateCapture::new().make_compact_tree(tree, None)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{dedup::Deduplicater, deftree, prune::Pruner};
-
-    #[test]
-    fn t_euler_walk_depth_1() {
-        let mut dedup = Deduplicater::new();
-        let mut pruner = Pruner::new();
-        let mut h = Heuristic::new();
-        let tree = deftree!(+ x x)
-            .deduplicate(&mut dedup)
-            .unwrap()
-            .prune(&mut pruner);
-        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 2);
-    }
-
-    #[test]
-    fn t_euler_walk_depth_2() {
-        let mut dedup = Deduplicater::new();
-        let mut pruner = Pruner::new();
-        let mut h = Heuristic::new();
-        let tree = deftree!(+ (* 2 x) (* 3 x))
-            .deduplicate(&mut dedup)
-            .unwrap()
-            .prune(&mut pruner);
-        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 6);
-    }
-
-    #[test]
-    fn t_euler_walk_multiple() {
-        let mut dedup = Deduplicater::new();
-        let mut pruner = Pruner::new();
-        let mut h = Heuristic::new();
-        let tree = deftree!(+ (+ (* 2 x) (* 3 x)) (* 4 x))
-            .deduplicate(&mut dedup)
-            .unwrap()
-            .prune(&mut pruner);
-        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 13);
-        // Make sure the same heuristic instance can be reused on other trees.
-        let tree = deftree!(+ (+ (* 2 x) (* 3 x)) (+ (* 4 x) 2))
-            .deduplicate(&mut dedup)
-            .unwrap()
-            .prune(&mut pruner);
-        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 33);
-    }
-
-    #[test]
-    fn t_euler_walk_non_leaf() {
-        let mut dedup = Deduplicater::new();
-        let mut pruner = Pruner::new();
-        let mut h = Heuristic::new();
-        let tree = deftree!(+ (* 2 (+ x y)) (* (+ x y) 3))
-            .deduplicate(&mut dedup)
-            .unwrap()
-            .prune(&mut pruner);
-        assert_eq!(h.euler_walk_cost(tree.nodes(), tree.root_index()), 4);
-    }
+    use crate::{
+        cost::{Depth, Flops},
+        dedup::Deduplicater,
+        deftree,
+        facts::Condition,
+        mutate::{test::assume_positive, Mutations},
+        prune::Pruner,
+        test::util::compare_trees,
+    };
+    use std::sync::atomic::Ordering;
 
     fn check_heuristic_and_mutations(before: Tree, after: Tree) {
         // Make sure the 'after' tree has lower cost than the 'before
@@ -242,11 +330,12 @@ mod test {
         let before = before.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
         let after = after.deduplicate(&mut dedup).unwrap().prune(&mut pruner);
         let mut h = Heuristic::new();
-        let mut capture = TemplateCapture::new();
+        let mut capture = TemplateCapture::with_facts(assume_positive(&before.symbols()));
+        let rules = RuleSet::new();
         assert!(h.cost(&before) > h.cost(&after));
         assert_eq!(
             1,
-            Mutations::of(&before, &mut capture)
+            Mutations::of(&before, &rules, &mut capture)
                 .filter_map(|t| if t.unwrap().equivalent(&after) {
                     Some(())
                 } else {
@@ -275,15 +364,420 @@ mod test {
     #[test]
     fn t_reduce_0() {
         let tree = deftree!(/ (+ (* p x) (* p y)) (+ x y));
-        let steps = reduce(tree, 8).unwrap();
-        assert!(steps.last().unwrap().equivalent(&deftree!(p)));
+        // x + y could be zero, so the fraction can't be cancelled.
+        let steps = reduce(tree.clone(), &RuleSet::new(), 8).unwrap();
+        assert!(!steps.last().unwrap().tree.equivalent(&deftree!(p)));
+        let facts = Facts::new()
+            .with('x', Condition::Positive)
+            .with('y', Condition::Positive);
+        let steps = reduce_with_facts(tree, &RuleSet::new(), facts, 8).unwrap();
+        assert!(steps.last().unwrap().tree.equivalent(&deftree!(p)));
     }
 
     #[test]
     fn t_reduce_1() {
         let tree = deftree!(sqrt (+ (pow (/ x (sqrt (+ (pow x 2) (pow y 2)))) 2)
                                   (pow (/ y (sqrt (+ (pow x 2) (pow y 2)))) 2)));
-        let steps = reduce(tree, 8).unwrap();
-        assert!(steps.last().unwrap().equivalent(&deftree!(1)));
+        let facts = assume_positive(&['x', 'y']);
+        let steps = reduce_with_facts(tree, &RuleSet::new(), facts, 8).unwrap();
+        assert!(steps.last().unwrap().tree.equivalent(&deftree!(1)));
+    }
+
+    #[test]
+    fn t_reduce_trig() {
+        let check = |tree: Tree, expected: Tree| {
+            let steps = reduce(tree.clone(), &RuleSet::new(), 8).unwrap();
+            let result = &steps.last().unwrap().tree;
+            assert!(result.equivalent(&expected), "{}", result.to_lisp());
+            compare_trees(
+                &tree,
+                result,
+                &[('x', -1.5, 1.5), ('y', -1.5, 1.5)],
+                100,
+                1e-12,
+            );
+        };
+        check(
+            deftree!(* 3 (+ (pow (sin (- x)) 2) (pow (cos x) 2))),
+            deftree!(3),
+        );
+        check(
+            deftree!(- (pow (cos (* 2 x)) 2) (pow (sin (* 2 x)) 2)),
+            deftree!(cos (* 4 x)),
+        );
+        // Cancelling cos(x) in sin(x) / cos(x) * cos(x) needs it to
+        // be nonzero, so only the parity is used here.
+        check(deftree!(* (tan x) (cos (- x))), deftree!(* (tan x) (cos x)));
+        check(
+            deftree!(* 2 (* (sin (/ (+ x y) 2)) (cos (/ (- x y) 2)))),
+            deftree!(+ (sin x) (sin y)),
+        );
+    }
+
+    #[test]
+    fn t_reduce_log_exp() {
+        let tree = deftree!(- (log (* x (exp y))) (log x));
+        // Without knowing the sign of x, log(x * e^y) can't be split.
+        let steps = reduce(tree.clone(), &RuleSet::new(), 8).unwrap();
+        assert!(!steps
+            .last()
+            .is_some_and(|step| step.tree.equivalent(&deftree!(y))));
+        let facts = assume_positive(&['x']);
+        let steps = reduce_with_facts(tree.clone(), &RuleSet::new(), facts, 8).unwrap();
+        let result = &steps.last().unwrap().tree;
+        assert!(result.equivalent(&deftree!(y)), "{}", result.to_lisp());
+        compare_trees(
+            &tree,
+            result,
+            &[('x', 0.1, 10.), ('y', -5., 5.)],
+            100,
+            1e-12,
+        );
+        // The exponent comes out of the log of a positive base.
+        let steps = reduce(deftree!(log (pow (exp x) 3)), &RuleSet::new(), 8).unwrap();
+        let result = &steps.last().unwrap().tree;
+        assert!(result.equivalent(&deftree!(* 3 x)), "{}", result.to_lisp());
+    }
+
+    #[test]
+    fn t_reduce_provenance() {
+        let tree = deftree!(/ (+ (* p x) (* p y)) (+ x y));
+        let facts = assume_positive(&['x', 'y']);
+        let rules = RuleSet::new();
+        let steps = reduce_with_facts(tree.clone(), &rules, facts.clone(), 8).unwrap();
+        assert!(!steps.is_empty());
+        // Applying the recorded template at the recorded node of the
+        // previous tree must reproduce each step.
+        let mut capture = TemplateCapture::with_facts(facts);
+        let mut prev = capture.make_compact_tree(tree, None).unwrap();
+        for step in steps {
+            let template = rules.get(&step.rule).unwrap();
+            let mut mutations = Mutations::of(&prev, &rules, &mut capture);
+            let mut found = false;
+            while let Some(mutation) = mutations.next() {
+                let mutated = mutation.unwrap();
+                let m = mutations.last_match().unwrap();
+                if m.template.name() == template.name()
+                    && m.node_index == step.node_index
+                    && m.bindings == step.bindings
+                {
+                    assert!(mutated.equivalent(&step.tree));
+                    found = true;
+                }
+            }
+            assert!(found, "Step not reproduced: {}", step.rule);
+            prev = step.tree;
+        }
+    }
+
+    #[test]
+    fn t_reduce_custom_rules() {
+        use crate::template::Template;
+        let tree = deftree!(+ (sin x) (sin x));
+        // Nothing to do without any templates.
+        assert!(reduce(tree.clone(), &RuleSet::empty(), 8)
+            .unwrap()
+            .is_empty());
+        let mut rules = RuleSet::empty();
+        rules
+            .add(Template::from("double", deftree!(+ a a), deftree!(* 2 a)))
+            .unwrap();
+        let steps = reduce(tree, &rules, 8).unwrap();
+        assert!(steps
+            .last()
+            .unwrap()
+            .tree
+            .equivalent(&deftree!(* 2 (sin x))));
+    }
+
+    #[test]
+    fn t_reduce_named_constants() {
+        use crate::template::Template;
+        let mut rules = RuleSet::empty();
+        rules
+            .add_oneway(Template::from("log_e", deftree!(log (@ e)), deftree!(1)))
+            .unwrap();
+        rules
+            .add_oneway(Template::from("sin_pi", deftree!(sin (@ pi)), deftree!(0)))
+            .unwrap();
+        let tree = deftree!(+ (* (@ pi) (log (@ e))) (* x (sin (@ pi))));
+        let expected = deftree!(@ pi);
+        let steps = reduce(tree.clone(), &rules, 8).unwrap();
+        assert_eq!(steps.last().unwrap().tree, expected);
+        let limits = SaturationLimits::default();
+        let result = saturate(tree, &rules, Facts::new(), &limits).unwrap();
+        assert_eq!(result, expected);
+        // Named constants are only matched by the same constant.
+        let tree = deftree!(sin (@ e));
+        assert!(reduce(tree, &rules, 8).unwrap().is_empty());
+    }
+
+    #[test]
+    fn t_saturate() {
+        let limits = SaturationLimits {
+            max_nodes: 2000,
+            ..Default::default()
+        };
+        let tree = deftree!(/ (+ (* p x) (* p y)) (+ x y));
+        let facts = assume_positive(&['x', 'y']);
+        let result = saturate(tree, &RuleSet::new(), facts, &limits).unwrap();
+        assert!(result.equivalent(&deftree!(p)));
+        let tree = deftree!(sqrt (+ (pow (/ x (sqrt (+ (pow x 2) (pow y 2)))) 2)
+                                  (pow (/ y (sqrt (+ (pow x 2) (pow y 2)))) 2)));
+        let facts = assume_positive(&['x', 'y']);
+        let result = saturate(tree, &RuleSet::new(), facts, &limits).unwrap();
+        assert!(result.equivalent(&deftree!(1)));
+        // Computed constants.
+        let tree = deftree!(* 2 (* 3 (+ 1 (+ 2 (sin x)))));
+        let result = saturate(tree, &RuleSet::new(), Facts::new(), &limits).unwrap();
+        assert!(result.equivalent(&deftree!(* 6 (+ 3 (sin x)))));
+    }
+
+    #[test]
+    fn t_reduce_with_costs() {
+        /// Prefers larger trees, to make sure the selection is
+        /// independent of the search.
+        struct Largest;
+        impl CostFunction for Largest {
+            fn cost(&mut self, tree: &Tree) -> usize {
+                usize::MAX - tree.len()
+            }
+        }
+        // Combining the exponents saves a pow.
+        let tree = deftree!(* (pow x (log y)) (pow x (sin y)));
+        let facts = assume_positive(&['x', 'y']);
+        let rules = RuleSet::new();
+        let steps = reduce_with_costs(
+            tree.clone(),
+            &rules,
+            facts.clone(),
+            &mut Heuristic::new(),
+            &mut Flops,
+            8,
+        )
+        .unwrap();
+        assert!(steps
+            .last()
+            .unwrap()
+            .tree
+            .equivalent(&deftree!(pow x (+ (log y) (sin y)))));
+        let steps = reduce_with_costs(
+            tree.clone(),
+            &rules,
+            facts.clone(),
+            &mut Heuristic::new(),
+            &mut Largest,
+            8,
+        )
+        .unwrap();
+        let start = TemplateCapture::new()
+            .make_compact_tree(tree.clone(), None)
+            .unwrap();
+        assert!(steps.last().unwrap().tree.len() > start.len());
+        let result = saturate_with_cost(
+            tree,
+            &rules,
+            facts,
+            &mut Depth::default(),
+            &SaturationLimits {
+                max_nodes: 2000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(Depth::default().cost(&result), 4);
+    }
+
+    #[test]
+    fn t_reduce_options() {
+        let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
+        let run = |options: ReduceOptions| {
+            reduce_with_options(
+                tree.clone(),
+                &RuleSet::new(),
+                ReduceOptions {
+                    facts: assume_positive(&['x', 'y']),
+                    ..options
+                },
+                &mut Heuristic::new(),
+                &mut Heuristic::new(),
+            )
+            .unwrap()
+        };
+        let done = run(ReduceOptions {
+            max_iter: 10,
+            ..Default::default()
+        });
+        assert_eq!(done.stop, StopReason::MaxIterations);
+        assert_eq!(done.iterations, 10);
+        assert!(done.steps.last().unwrap().tree.equivalent(&deftree!(k)));
+        // Cancelled before exploring anything.
+        let cancel = AtomicBool::new(true);
+        let cancelled = run(ReduceOptions {
+            cancel: Some(&cancel),
+            ..Default::default()
+        });
+        assert_eq!(cancelled.stop, StopReason::Cancelled);
+        assert_eq!(cancelled.iterations, 0);
+        assert!(cancelled.steps.is_empty());
+        // Cancelled from the progress callback.
+        let cancel = AtomicBool::new(false);
+        let mut reports = Vec::new();
+        let cancelled = run(ReduceOptions {
+            cancel: Some(&cancel),
+            progress: Some(Box::new(|p: &Progress| {
+                reports.push(p.clone());
+                if p.iterations == 5 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            })),
+            ..Default::default()
+        });
+        assert_eq!(cancelled.stop, StopReason::Cancelled);
+        assert_eq!(cancelled.iterations, 5);
+        assert_eq!(reports.len(), 5);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[1].iterations == w[0].iterations + 1 && w[1].best_cost <= w[0].best_cost));
+        // Deadline in the past.
+        let late = run(ReduceOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        });
+        assert_eq!(late.stop, StopReason::Deadline);
+        assert!(late.steps.is_empty());
+        // Bounded growth and heap.
+        let start = TemplateCapture::new()
+            .make_compact_tree(tree.clone(), None)
+            .unwrap();
+        let bounded = run(ReduceOptions {
+            max_iter: 50,
+            max_growth: Some(0),
+            max_heap_size: Some(4),
+            ..Default::default()
+        });
+        assert!(bounded.steps.iter().all(|s| s.tree.len() <= start.len()));
+        assert!(bounded.iterations <= 50);
+    }
+
+    #[test]
+    fn t_reduce_parallel() {
+        let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
+        let run = |threads: usize| {
+            let options = ReduceOptions {
+                facts: assume_positive(&['x', 'y']),
+                max_iter: 20,
+                ..Default::default()
+            };
+            reduce_parallel(tree.clone(), &RuleSet::new(), options, threads).unwrap()
+        };
+        let summary = |result: &Reduction| {
+            result
+                .steps
+                .iter()
+                .map(|s| (s.rule.clone(), s.node_index))
+                .collect::<Vec<_>>()
+        };
+        for threads in [1, 2, 4] {
+            let first = run(threads);
+            // Without mirrors that rewrite lone symbols, the search runs
+            // out of candidates before the budget.
+            assert_eq!(first.stop, StopReason::Exhausted);
+            assert!(first.steps.last().unwrap().tree.equivalent(&deftree!(k)));
+            for _ in 0..3 {
+                assert_eq!(summary(&run(threads)), summary(&first));
+            }
+        }
+        // Custom costs.
+        let run = |threads: usize| {
+            let options = ReduceOptions {
+                facts: assume_positive(&['x', 'y']),
+                max_iter: 20,
+                ..Default::default()
+            };
+            reduce_parallel_with_costs(
+                tree.clone(),
+                &RuleSet::new(),
+                options,
+                &mut Depth::default(),
+                &mut Flops,
+                threads,
+            )
+            .unwrap()
+        };
+        for threads in [1, 4] {
+            let first = run(threads);
+            assert!(first.steps.last().unwrap().tree.equivalent(&deftree!(k)));
+            assert_eq!(summary(&run(threads)), summary(&first));
+        }
+        // The select cost is only used by the calling thread, so it
+        // doesn't have to be cloned.
+        struct Counting<'a>(&'a mut usize);
+        impl CostFunction for Counting<'_> {
+            fn cost(&mut self, tree: &Tree) -> usize {
+                *self.0 += 1;
+                tree.len()
+            }
+        }
+        let mut calls = 0;
+        let options = ReduceOptions {
+            facts: assume_positive(&['x', 'y']),
+            max_iter: 20,
+            ..Default::default()
+        };
+        let result = reduce_parallel_with_costs(
+            tree.clone(),
+            &RuleSet::new(),
+            options,
+            &mut Depth::default(),
+            &mut Counting(&mut calls),
+            2,
+        )
+        .unwrap();
+        assert_eq!(calls, result.iterations);
+    }
+
+    #[test]
+    fn t_reduce_strategies() {
+        let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
+        let run = |strategy: Strategy| {
+            let options = ReduceOptions {
+                facts: assume_positive(&['x', 'y']),
+                max_iter: 200,
+                ..Default::default()
+            };
+            strategy
+                .reduce(tree.clone(), &RuleSet::new(), options)
+                .unwrap()
+        };
+        let summary = |result: &Reduction| {
+            result
+                .steps
+                .iter()
+                .map(|s| (s.rule.clone(), s.node_index))
+                .collect::<Vec<_>>()
+        };
+        for strategy in [
+            Strategy::BestFirst,
+            Strategy::Beam { width: 4 },
+            Strategy::IterativeDeepening { max_depth: 3 },
+            Strategy::HillClimbing {
+                restarts: 8,
+                seed: 42,
+            },
+        ] {
+            let result = run(strategy);
+            assert!(result.iterations <= 200);
+            assert!(
+                result.steps.last().unwrap().tree.equivalent(&deftree!(k)),
+                "{:?}",
+                strategy
+            );
+            assert_eq!(summary(&run(strategy)), summary(&result));
+        }
+        // Not enough depth to cancel (x + y).
+        let shallow = run(Strategy::IterativeDeepening { max_depth: 2 });
+        assert!(shallow.steps.len() <= 2);
+        assert!(!shallow.steps.last().unwrap().tree.equivalent(&deftree!(k)));
     }
 }
@@ -0,0 +1,618 @@
+use crate::{
+    cost::CostFunction,
+    dedup::equivalent,
+    mutate::{Match, MutationError, Mutations, TemplateCapture},
+    reduce::{Progress, ReduceOptions, Reduction, Step, StopReason},
+    template::RuleSet,
+    tree::Tree,
+    walk::DepthWalker,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{atomic::Ordering, mpsc, Arc},
+    time::Instant,
+};
+
+pub(crate) struct Candidate<'a> {
+    pub tree: Tree,
+    pub prev: usize,
+    /// The match that produced this candidate from the previous one.
+    pub origin: Option<Match<'a>>,
+    pub steps: usize,
+    pub complexity: usize,
+}
+
+impl Candidate<'_> {
+    pub fn cost(&self) -> usize {
+        self.steps + self.complexity
+    }
+}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        other.cost().partial_cmp(&self.cost())
+    }
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tree == other.tree
+    }
+}
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.cost().cmp(&self.cost())
+    }
+}
+impl Eq for Candidate<'_> {}
+
+/// Indices of the explored candidates, keyed by the hashes of their
+/// trees. Trees with the same hash are compared structurally, so a
+/// hash collision cannot cause a new tree to be mistaken for an
+/// explored one.
+#[derive(Default)]
+pub(crate) struct ExploredSet {
+    buckets: HashMap<u64, Vec<usize>>,
+}
+
+impl ExploredSet {
+    /// Check if a tree equivalent to `tree`, whose hash is `hash`,
+    /// was explored.
+    pub fn contains(
+        &self,
+        hash: u64,
+        tree: &Tree,
+        explored: &[Candidate],
+        walkers: &mut (DepthWalker, DepthWalker),
+    ) -> bool {
+        self.buckets.get(&hash).is_some_and(|bucket| {
+            bucket.iter().any(|&i| {
+                let other = &explored[i].tree;
+                equivalent(
+                    tree.root_index(),
+                    other.root_index(),
+                    tree.nodes(),
+                    other.nodes(),
+                    &mut walkers.0,
+                    &mut walkers.1,
+                )
+            })
+        })
+    }
+
+    /// Record that the candidate at `index` in `explored`, whose
+    /// tree has the given `hash`, was explored.
+    pub fn insert(&mut self, hash: u64, index: usize) {
+        self.buckets.entry(hash).or_default().push(index);
+    }
+}
+
+/// The explored candidates and the set of their trees, as shared with
+/// the threads of a `parallel` search.
+type Explored<'r> = (Vec<Candidate<'r>>, ExploredSet);
+
+/// State shared by all search strategies: the explored candidates,
+/// the best one among them, and the budgets from `ReduceOptions`.
+pub(crate) struct Search<'r, 'o> {
+    pub rules: &'r RuleSet,
+    pub options: ReduceOptions<'o>,
+    pub capture: TemplateCapture,
+    pub explored: Vec<Candidate<'r>>,
+    /// Candidates with more nodes than this are discarded.
+    pub max_len: usize,
+    started: Instant,
+    hashbuf: Vec<u64>,
+    walkers: (DepthWalker, DepthWalker),
+    min_cost: usize,
+    best: usize,
+}
+
+impl<'r, 'o> Search<'r, 'o> {
+    /// Prepare to search for simplifications of `tree`. The search
+    /// and the candidate for the compacted `tree` are returned.
+    pub fn new<S: CostFunction>(
+        tree: Tree,
+        rules: &'r RuleSet,
+        options: ReduceOptions<'o>,
+        search: &mut S,
+    ) -> Result<(Search<'r, 'o>, Candidate<'r>), MutationError> {
+        let started = Instant::now();
+        let mut capture = TemplateCapture::with_facts(options.facts.clone());
+        capture.set_max_groupings(options.max_groupings);
+        let tree = capture.make_compact_tree(tree, None)?;
+        let max_len = match options.max_growth {
+            Some(growth) => tree.len().saturating_add(growth),
+            None => usize::MAX,
+        };
+        let complexity = search.cost(&tree);
+        let start = Candidate {
+            tree,
+            prev: 0,
+            origin: None,
+            steps: 0,
+            complexity,
+        };
+        let state = Search {
+            rules,
+            explored: Vec::with_capacity(options.max_iter),
+            options,
+            capture,
+            max_len,
+            started,
+            hashbuf: Vec::new(),
+            walkers: (DepthWalker::new(), DepthWalker::new()),
+            min_cost: usize::MAX,
+            best: 0,
+        };
+        Ok((state, start))
+    }
+
+    /// The reason to stop searching, if any of the budgets ran out.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        if self
+            .options
+            .cancel
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            Some(StopReason::Cancelled)
+        } else if self.options.deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(StopReason::Deadline)
+        } else if self.explored.len() >= self.options.max_iter {
+            Some(StopReason::MaxIterations)
+        } else {
+            None
+        }
+    }
+
+    /// Explore `cand`, unless a tree equivalent to it is in
+    /// `seen`. Returns the index of the explored candidate. `pending`
+    /// is the number of candidates waiting to be explored, which is
+    /// reported to the progress callback.
+    pub fn visit<F: CostFunction>(
+        &mut self,
+        cand: Candidate<'r>,
+        seen: &mut ExploredSet,
+        pending: usize,
+        select: &mut F,
+    ) -> Option<usize> {
+        let hash = cand.tree.hash(&mut self.hashbuf);
+        if seen.contains(hash, &cand.tree, &self.explored, &mut self.walkers) {
+            return None;
+        }
+        let index = self.explored.len();
+        seen.insert(hash, index);
+        let cost = select.cost(&cand.tree);
+        self.explored.push(cand);
+        if cost < self.min_cost {
+            self.min_cost = cost;
+            self.best = index;
+        }
+        if let Some(progress) = self.options.progress.as_mut() {
+            progress(&Progress {
+                iterations: self.explored.len(),
+                heap_size: pending,
+                best_cost: self.min_cost,
+                elapsed: self.started.elapsed(),
+            });
+        }
+        Some(index)
+    }
+
+    /// Add the explored candidate at `index` to `seen`.
+    pub fn mark(&mut self, seen: &mut ExploredSet, index: usize) {
+        seen.insert(self.explored[index].tree.hash(&mut self.hashbuf), index);
+    }
+
+    /// All mutations of the explored candidate at `index`, that are
+    /// not too large.
+    pub fn expand<S: CostFunction>(
+        &mut self,
+        index: usize,
+        search: &mut S,
+    ) -> Result<Vec<Candidate<'r>>, MutationError> {
+        let parent = &self.explored[index];
+        let mut children = Vec::new();
+        let mut mutations = Mutations::of(&parent.tree, self.rules, &mut self.capture);
+        while let Some(mutation) = mutations.next() {
+            let tree = mutation?;
+            if tree.len() > self.max_len {
+                continue;
+            }
+            let complexity = search.cost(&tree);
+            children.push(Candidate {
+                tree,
+                prev: index,
+                origin: mutations.last_match(),
+                steps: parent.steps + 1,
+                complexity,
+            });
+        }
+        Ok(children)
+    }
+
+    /// The steps leading to the best explored candidate.
+    pub fn finish(self, stop: StopReason) -> Reduction {
+        Reduction {
+            steps: collect_steps(&self.explored, self.best),
+            stop,
+            iterations: self.explored.len(),
+        }
+    }
+}
+
+/// Always explore the cheapest candidate that was found so far.
+pub(crate) fn best_first<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    let max_heap_size = options.max_heap_size;
+    let (mut state, start) = Search::new(tree, rules, options, search)?;
+    let mut seen = ExploredSet::default();
+    let mut heap = BinaryHeap::<Candidate>::new();
+    heap.push(start);
+    while let Some(cand) = heap.pop() {
+        if let Some(reason) = state.stop_reason() {
+            return Ok(state.finish(reason));
+        }
+        let index = match state.visit(cand, &mut seen, heap.len(), select) {
+            Some(index) => index,
+            None => continue,
+        };
+        if let Some(reason) = state.stop_reason() {
+            return Ok(state.finish(reason));
+        }
+        heap.extend(state.expand(index, search)?);
+        if let Some(max_heap_size) = max_heap_size {
+            truncate_heap(&mut heap, max_heap_size);
+        }
+    }
+    Ok(state.finish(StopReason::Exhausted))
+}
+
+/// Same as `best_first`, but the `threads` cheapest candidates are
+/// expanded at the same time. The threads are started once, and each
+/// one uses its own clone of the `search` cost for the whole search.
+pub(crate) fn parallel<S: CostFunction + Clone + Send, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions,
+    search: &mut S,
+    select: &mut F,
+    threads: usize,
+) -> Result<Reduction, MutationError> {
+    let threads = threads.max(1);
+    let max_heap_size = options.max_heap_size;
+    let mut searches: Vec<S> = (0..threads).map(|_| search.clone()).collect();
+    let (mut state, start) = Search::new(tree, rules, options, search)?;
+    let mut captures: Vec<TemplateCapture> = (0..threads)
+        .map(|_| {
+            let mut capture = TemplateCapture::with_facts(state.options.facts.clone());
+            capture.set_max_groupings(state.options.max_groupings);
+            capture
+        })
+        .collect();
+    let max_len = state.max_len;
+    std::thread::scope(|scope| {
+        // Each worker receives the index of a candidate to expand,
+        // along with the explored candidates, and sends back the
+        // mutations of that candidate. The explored candidates are
+        // only shared while a batch is expanded, and handed back to
+        // `state` in between.
+        let workers: Vec<_> = captures
+            .iter_mut()
+            .zip(searches.iter_mut())
+            .map(|(capture, search)| {
+                let (job_tx, job_rx) = mpsc::channel::<(Arc<Explored>, usize)>();
+                let (result_tx, result_rx) = mpsc::channel();
+                scope.spawn(move || {
+                    let mut hashbuf = Vec::<u64>::new();
+                    let mut walkers = (DepthWalker::new(), DepthWalker::new());
+                    for (shared, index) in job_rx {
+                        let (explored, seen) = &*shared;
+                        let parent = &explored[index];
+                        let mut children = Vec::new();
+                        let mut mutations = Mutations::of(&parent.tree, rules, capture);
+                        let result = loop {
+                            let tree = match mutations.next() {
+                                Some(Ok(tree)) => tree,
+                                Some(Err(e)) => break Err(e),
+                                None => break Ok(children),
+                            };
+                            if tree.len() > max_len
+                                || seen.contains(
+                                    tree.hash(&mut hashbuf),
+                                    &tree,
+                                    explored,
+                                    &mut walkers,
+                                )
+                            {
+                                continue;
+                            }
+                            let complexity = search.cost(&tree);
+                            children.push(Candidate {
+                                tree,
+                                prev: index,
+                                origin: mutations.last_match(),
+                                steps: parent.steps + 1,
+                                complexity,
+                            });
+                        };
+                        // Let go of the explored candidates before
+                        // reporting, so they can be handed back.
+                        drop(shared);
+                        if result_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                });
+                (job_tx, result_rx)
+            })
+            .collect();
+        let mut seen = ExploredSet::default();
+        let mut heap = BinaryHeap::<Candidate>::new();
+        heap.push(start);
+        let mut batch = Vec::<usize>::with_capacity(threads);
+        loop {
+            batch.clear();
+            while batch.len() < threads {
+                let cand = match heap.pop() {
+                    Some(cand) => cand,
+                    None => break,
+                };
+                if let Some(reason) = state.stop_reason() {
+                    return Ok(state.finish(reason));
+                }
+                if let Some(index) = state.visit(cand, &mut seen, heap.len(), select) {
+                    batch.push(index);
+                }
+            }
+            if let Some(reason) = state.stop_reason() {
+                return Ok(state.finish(reason));
+            }
+            if batch.is_empty() {
+                return Ok(state.finish(StopReason::Exhausted));
+            }
+            let shared = Arc::new((std::mem::take(&mut state.explored), seen));
+            for (&index, (job_tx, _)) in batch.iter().zip(workers.iter()) {
+                job_tx
+                    .send((shared.clone(), index))
+                    .expect("Reduce thread panicked.");
+            }
+            let mut expanded = Vec::with_capacity(batch.len());
+            for (_, result_rx) in workers.iter().take(batch.len()) {
+                expanded.push(result_rx.recv().expect("Reduce thread panicked."));
+            }
+            (state.explored, seen) = match Arc::try_unwrap(shared) {
+                Ok(explored) => explored,
+                Err(_) => unreachable!("The workers hold on to the explored candidates."),
+            };
+            for children in expanded {
+                heap.extend(children?);
+            }
+            if let Some(max_heap_size) = max_heap_size {
+                truncate_heap(&mut heap, max_heap_size);
+            }
+        }
+    })
+}
+
+/// Explore the trees one step at a time, keeping only the `width`
+/// cheapest new trees of each step.
+pub(crate) fn beam<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions,
+    width: usize,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    let width = width.max(1);
+    let (mut state, start) = Search::new(tree, rules, options, search)?;
+    let mut seen = ExploredSet::default();
+    if let Some(reason) = state.stop_reason() {
+        return Ok(state.finish(reason));
+    }
+    let mut frontier: Vec<usize> = state
+        .visit(start, &mut seen, 0, select)
+        .into_iter()
+        .collect();
+    while !frontier.is_empty() {
+        let mut children = Vec::new();
+        for &index in frontier.iter() {
+            if let Some(reason) = state.stop_reason() {
+                return Ok(state.finish(reason));
+            }
+            children.extend(state.expand(index, search)?);
+        }
+        // Stable, so ties are broken by the order of the frontier.
+        children.sort_by_key(|c| c.complexity);
+        frontier.clear();
+        let mut pending = children.len();
+        for child in children {
+            if frontier.len() == width {
+                break;
+            }
+            if let Some(reason) = state.stop_reason() {
+                return Ok(state.finish(reason));
+            }
+            pending -= 1;
+            if let Some(index) = state.visit(child, &mut seen, pending, select) {
+                frontier.push(index);
+            }
+        }
+    }
+    Ok(state.finish(StopReason::Exhausted))
+}
+
+/// Depth first search, first up to one step from the start, then up
+/// to two steps and so on, until `max_depth` steps. The cheapest
+/// mutations of a tree are searched first. A tree is not explored
+/// twice in the same pass, even if it is reached by a shorter path
+/// the second time. The next pass will reach it by the shorter path.
+pub(crate) fn iterative_deepening<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions,
+    max_depth: usize,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    let (mut state, start) = Search::new(tree, rules, options, search)?;
+    if let Some(reason) = state.stop_reason() {
+        return Ok(state.finish(reason));
+    }
+    let mut seen = ExploredSet::default();
+    let root = match state.visit(start, &mut seen, 0, select) {
+        Some(root) => root,
+        None => return Ok(state.finish(StopReason::Exhausted)),
+    };
+    for depth in 1..=max_depth {
+        if depth > 1 {
+            seen = ExploredSet::default();
+            state.mark(&mut seen, root);
+        }
+        let mut stack = vec![(root, 0)];
+        let mut cut_off = false;
+        while let Some((index, steps)) = stack.pop() {
+            if steps == depth {
+                cut_off = true;
+                continue;
+            }
+            if let Some(reason) = state.stop_reason() {
+                return Ok(state.finish(reason));
+            }
+            let mut children = state.expand(index, search)?;
+            // Push the cheapest last, so it is searched first.
+            children.sort_by_key(|c| Reverse(c.complexity));
+            for child in children {
+                if let Some(reason) = state.stop_reason() {
+                    return Ok(state.finish(reason));
+                }
+                if let Some(child) = state.visit(child, &mut seen, stack.len(), select) {
+                    stack.push((child, steps + 1));
+                }
+            }
+        }
+        if !cut_off {
+            // Every tree within reach was explored.
+            break;
+        }
+    }
+    Ok(state.finish(StopReason::Exhausted))
+}
+
+/// Starting from `tree`, repeatedly apply a random mutation that does
+/// not increase the search cost, until there are no such mutations
+/// left. Then start over from `tree`, `restarts` times.
+pub(crate) fn hill_climbing<S: CostFunction, F: CostFunction>(
+    tree: Tree,
+    rules: &RuleSet,
+    options: ReduceOptions,
+    restarts: usize,
+    seed: u64,
+    search: &mut S,
+    select: &mut F,
+) -> Result<Reduction, MutationError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (mut state, start) = Search::new(tree, rules, options, search)?;
+    if let Some(reason) = state.stop_reason() {
+        return Ok(state.finish(reason));
+    }
+    let mut seen = ExploredSet::default();
+    let root = match state.visit(start, &mut seen, 0, select) {
+        Some(root) => root,
+        None => return Ok(state.finish(StopReason::Exhausted)),
+    };
+    for climb in 0..=restarts {
+        if climb > 0 {
+            seen = ExploredSet::default();
+            state.mark(&mut seen, root);
+        }
+        let mut current = root;
+        loop {
+            if let Some(reason) = state.stop_reason() {
+                return Ok(state.finish(reason));
+            }
+            let complexity = state.explored[current].complexity;
+            let mut children = state.expand(current, search)?;
+            children.retain(|c| c.complexity <= complexity);
+            let mut next = None;
+            while !children.is_empty() && next.is_none() {
+                let child = children.swap_remove(rng.gen_range(0..children.len()));
+                if let Some(reason) = state.stop_reason() {
+                    return Ok(state.finish(reason));
+                }
+                next = state.visit(child, &mut seen, children.len(), select);
+            }
+            match next {
+                Some(index) => current = index,
+                None => break, // Local minimum.
+            }
+        }
+    }
+    Ok(state.finish(StopReason::Exhausted))
+}
+
+/// Drop the most expensive candidates from `heap`, until it has at
+/// most `max_size` candidates.
+fn truncate_heap(heap: &mut BinaryHeap<Candidate>, max_size: usize) {
+    if heap.len() > max_size {
+        // Sorted from the most to the least expensive.
+        let mut sorted = std::mem::take(heap).into_sorted_vec();
+        sorted.drain(..(sorted.len() - max_size));
+        *heap = BinaryHeap::from(sorted);
+    }
+}
+
+/// The steps leading from the first of the `explored` candidates to
+/// the candidate at index `best`.
+fn collect_steps(explored: &[Candidate], best: usize) -> Vec<Step> {
+    let mut steps = Vec::<Step>::new();
+    let mut i = best;
+    while explored.get(i).is_some_and(|cand| cand.prev != i) {
+        let cand = &explored[i];
+        if let Some(origin) = &cand.origin {
+            steps.push(Step {
+                tree: cand.tree.clone(),
+                rule: origin.template.name().to_string(),
+                node_index: origin.node_index,
+                bindings: origin.bindings.clone(),
+            });
+        }
+        i = cand.prev;
+    }
+    steps.reverse();
+    steps
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deftree;
+
+    #[test]
+    fn t_explored_set_collisions() {
+        let candidate = |tree: Tree| Candidate {
+            tree,
+            prev: 0,
+            origin: None,
+            steps: 0,
+            complexity: 0,
+        };
+        let explored = vec![candidate(deftree!(+ x y)), candidate(deftree!(* x y))];
+        let mut walkers = (DepthWalker::new(), DepthWalker::new());
+        // Pretend both trees have the same hash.
+        let mut set = ExploredSet::default();
+        set.insert(42, 0);
+        assert!(set.contains(42, &deftree!(+ y x), &explored, &mut walkers));
+        assert!(!set.contains(42, &deftree!(* x y), &explored, &mut walkers));
+        assert!(!set.contains(7, &deftree!(+ x y), &explored, &mut walkers));
+        set.insert(42, 1);
+        assert!(set.contains(42, &deftree!(* x y), &explored, &mut walkers));
+    }
+}
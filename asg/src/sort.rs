@@ -1,6 +1,6 @@
 use crate::{
     tree::{Node, Node::*},
-    walk::{DepthWalker, NodeOrdering},
+    walk::DepthWalker,
 };
 
 #[derive(Debug)]
@@ -46,10 +46,7 @@ impl TopoSorter {
         // Compute depths of all nodes.
         self.depths.clear();
         self.depths.resize(nodes.len(), 0);
-        for (index, maybe_parent) in
-            self.walker
-                .walk_nodes(&nodes, root_index, false, NodeOrdering::Original)
-        {
+        for (index, maybe_parent) in self.walker.walk_nodes(&nodes, root_index, false) {
             if let Some(parent) = maybe_parent {
                 self.depths[index] = usize::max(self.depths[index], 1 + self.depths[parent]);
                 if self.depths[index] >= nodes.len() {
@@ -83,6 +80,8 @@ impl TopoSorter {
             .extend(self.sorted_indices.iter().map(|index| -> Node {
                 match nodes[*index] {
                     Constant(v) => Constant(v),
+                    Rational(v) => Rational(v),
+                    Named(v) => Named(v),
                     Symbol(label) => Symbol(label),
                     Unary(op, input) => Unary(op, self.index_map[input]),
                     Binary(op, lhs, rhs) => Binary(op, self.index_map[lhs], self.index_map[rhs]),
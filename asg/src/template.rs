@@ -1,6 +1,7 @@
 use lazy_static::lazy_static;
 
 use crate::{
+    facts::Condition,
     mutate::TemplateCapture,
     tree::{Node::*, Tree},
 };
@@ -10,20 +11,52 @@ macro_rules! deftemplate {
     (($($tt:tt)*)) => { // Unwrap parens.
         parsetemplate!($($tt)*)
     };
-    ($name: ident ping ($($ping:tt) *) pong ($($pong:tt) *)) => {
+    ($name: ident ping ($($ping:tt) *) pong ($($pong:tt) *)
+     $(constant ($($clabel:ident) +))?
+     $(guard ($label:ident $cond:ident))*) => {
         Template::from(
             stringify!($name),
             $crate::deftree!(($($ping) *)),
             $crate::deftree!(($($pong) *))
-        )
+        )$($(.constant(stringify!($clabel).chars().next().unwrap()))+)?
+        $(.guard(stringify!($label).chars().next().unwrap(), Condition::$cond))*
     };
 }
 
+/// Errors that can occur when adding templates to a `RuleSet`.
+#[derive(Debug)]
+pub enum TemplateError {
+    /// A symbol in the pong of the template does not appear in the
+    /// ping, so it can never be bound when the template is applied.
+    UnboundSymbol(char),
+    /// A template (or its mirror) with the same name already exists.
+    DuplicateName(String),
+    /// A guard refers to a symbol that does not appear in the ping.
+    UnboundGuard(char),
+    /// A symbol declared as constant does not appear in the ping.
+    UnboundConstant(char),
+}
+
+/// A rewrite rule. When the `ping` tree matches a subtree, that
+/// subtree is replaced with the `pong` tree, with the symbols of the
+/// pong substituted with the subtrees they were bound to in the ping.
+///
+/// Symbols declared as constant only match constant nodes. Subtrees
+/// of the pong that only depend on such symbols and literal constants
+/// are computed when the template is applied, so the template
+///
+/// ```text
+/// ping (* c (* d x)) pong (* (* c d) x) constant (c d)
+/// ```
+///
+/// rewrites `(* 2 (* 3 x))` into `(* 6 x)`.
 #[derive(Clone)]
 pub struct Template {
     name: String,
     ping: Tree,
     pong: Tree,
+    constants: Vec<char>,
+    guards: Vec<(char, Condition)>,
 }
 
 /// Check the capture to see if every symbol in src is bound to every
@@ -43,7 +76,7 @@ fn complete_capture(capture: &TemplateCapture, src: &Tree, dst: &Tree) -> bool {
     lhs.clear();
     lhs.extend(capture.bindings().iter().filter_map(|(_label, index)| {
         match &dst.nodes()[*index] {
-            Constant(_) | Unary(_, _) | Binary(_, _, _) => None,
+            Constant(_) | Rational(_) | Named(_) | Unary(_, _) | Binary(_, _, _) => None,
             Symbol(l) => Some(l),
         }
     }));
@@ -57,28 +90,90 @@ fn complete_capture(capture: &TemplateCapture, src: &Tree, dst: &Tree) -> bool {
 }
 
 impl Template {
+    /// Create a new template with the given `name`, that rewrites
+    /// `ping` into `pong`.
     pub fn from(name: &str, ping: Tree, pong: Tree) -> Template {
         Template {
             name: name.to_string(),
             ping,
             pong,
+            constants: vec![],
+            guards: vec![],
+        }
+    }
+
+    /// Only let the symbol with `label` match constant nodes.
+    pub fn constant(mut self, label: char) -> Template {
+        if !self.constants.contains(&label) {
+            self.constants.push(label);
         }
+        self
+    }
+
+    /// Only apply this template if the subtree bound to the symbol
+    /// with `label` satisfies `condition`. A template with more than
+    /// one guard is only applied if all of them are satisfied.
+    pub fn guard(mut self, label: char, condition: Condition) -> Template {
+        self.guards.push((label, condition));
+        self
     }
 
-    fn valid(self) -> Option<Template> {
+    fn check(&self) -> Result<(), TemplateError> {
         // All symbols in pong must be present in ping too. Otherwise
         // the template cannot be applied to a tree.
         let symbols = self.ping().symbols();
         for label in self.pong().symbols() {
             match symbols.iter().find(|&c| *c == label) {
                 Some(_) => {} // Do nothing.
-                None => return None,
+                None => return Err(TemplateError::UnboundSymbol(label)),
             }
         }
-        return Some(self);
+        if let Some(label) = self.constants.iter().find(|l| !symbols.contains(l)) {
+            return Err(TemplateError::UnboundConstant(*label));
+        }
+        match self.guards.iter().find(|(l, _)| !symbols.contains(l)) {
+            Some((label, _)) => Err(TemplateError::UnboundGuard(*label)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check if the pong has subtrees that are computed from constant
+    /// symbols when this template is applied.
+    fn computes_constants(&self) -> bool {
+        let nodes = self.pong.nodes();
+        let mut is_const = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let (flag, computed) = match node {
+                Constant(_) | Rational(_) => (true, false),
+                // Named constants are never computed.
+                Named(_) => (false, false),
+                Symbol(label) => (self.constants.contains(label), false),
+                Unary(_, input) => (is_const[*input], is_const[*input]),
+                Binary(_, lhs, rhs) => {
+                    let flag = is_const[*lhs] && is_const[*rhs];
+                    (flag, flag)
+                }
+            };
+            if computed {
+                return true;
+            }
+            is_const.push(flag);
+        }
+        false
     }
 
     fn mirrored(&self) -> Option<Template> {
+        // Computed constants can't be matched, so there is no way to
+        // undo the computation.
+        if self.computes_constants() {
+            return None;
+        }
+        // A lone symbol matches every subtree, so the mirror would blow
+        // up every tree it is applied to. Guards don't help, because
+        // they hold for plenty of subtrees, e.g. every constant.
+        if matches!(self.pong.root(), Symbol(_)) {
+            return None;
+        }
         let out = Template {
             name: {
                 const REV: &str = "rev_";
@@ -89,14 +184,22 @@ impl Template {
             },
             ping: self.pong.clone(),
             pong: self.ping.clone(),
-        }
-        .valid()?;
+            constants: self.constants.clone(),
+            // The mirror is only valid on the same domain.
+            guards: self.guards.clone(),
+        };
+        out.check().ok()?;
         // Make sure the template is not symmetric. If it is,
         // mirroring will produce a redundant template. It's no harm,
         // but no use either. So in the end it is harmful because it
-        // wastes resources.
+        // wastes resources. This only depends on the structure of the
+        // template, so the guards are left out of the check.
+        let unguarded = Template {
+            guards: vec![],
+            ..out.clone()
+        };
         let mut capture = TemplateCapture::new();
-        if capture.next_match(&out, self.ping())
+        if capture.next_match(&unguarded, self.ping())
             && complete_capture(&capture, out.ping(), self.ping())
         {
             return None;
@@ -104,6 +207,10 @@ impl Template {
         return Some(out);
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn ping(&self) -> &Tree {
         &self.ping
     }
@@ -111,6 +218,14 @@ impl Template {
     pub fn pong(&self) -> &Tree {
         &self.pong
     }
+
+    pub fn constants(&self) -> &[char] {
+        &self.constants
+    }
+
+    pub fn guards(&self) -> &[(char, Condition)] {
+        &self.guards
+    }
 }
 
 lazy_static! {
@@ -122,6 +237,8 @@ lazy_static! {
         deftemplate!(min_of_sqrt
                      ping (min (sqrt a) (sqrt b))
                      pong (sqrt (min a b))
+                     guard (a NonNegative)
+                     guard (b NonNegative)
         ),
         deftemplate!(rearrange_frac
                      ping (* (/ a b) (/ x y))
@@ -138,18 +255,24 @@ lazy_static! {
         deftemplate!(divide_by_self
                      ping (/ a a)
                      pong (1.0)
+                     guard (a NonZero)
         ),
         deftemplate!(distribute_pow_div
                      ping (pow (/ a b) k)
                      pong (/ (pow a k) (pow b k))
+                     guard (a Positive)
+                     guard (b Positive)
         ),
         deftemplate!(distribute_pow_mul
                      ping (pow (* a b) k)
                      pong (* (pow a k) (pow b k))
+                     guard (a Positive)
+                     guard (b Positive)
         ),
         deftemplate!(square_sqrt
                      ping (pow (sqrt a) 2.)
                      pong (a)
+                     guard (a NonNegative)
         ),
         deftemplate!(sqrt_square
                      ping (sqrt (pow a 2.))
@@ -162,15 +285,27 @@ lazy_static! {
         deftemplate!(mul_exponents
                      ping (pow (pow a x) y)
                      pong (pow a (* x y))
+                     guard (a Positive)
         ),
         deftemplate!(add_exponents
                      ping (* (pow a x) (pow a y))
                      pong (pow a (+ x y))
+                     guard (a Positive)
         ),
         deftemplate!(add_frac
                      ping (+ (/ a d) (/ b d))
                      pong (/ (+ a b) d)
         ),
+        deftemplate!(combine_mul_constants
+                     ping (* c (* d x))
+                     pong (* (* c d) x)
+                     constant (c d)
+        ),
+        deftemplate!(combine_add_constants
+                     ping (+ c (+ d x))
+                     pong (+ (+ c d) x)
+                     constant (c d)
+        ),
 
         // ====== Min and max simplifications ======
 
@@ -196,19 +331,243 @@ lazy_static! {
                      ping (min (+ a x) (+ b x))
                      pong (+ x (min a b))
         ),
+
+        // ====== Trigonometric identities ======
+
+        // Pythagorean identities.
+        deftemplate!(pythagoras
+                     ping (+ (pow (sin x) 2.) (pow (cos x) 2.))
+                     pong (1.0)
+        ),
+        deftemplate!(pythagoras_sin
+                     ping (- 1. (pow (cos x) 2.))
+                     pong (pow (sin x) 2.)
+        ),
+        deftemplate!(pythagoras_cos
+                     ping (- 1. (pow (sin x) 2.))
+                     pong (pow (cos x) 2.)
+        ),
+        deftemplate!(pythagoras_tan
+                     ping (+ 1. (pow (tan x) 2.))
+                     pong (/ 1. (pow (cos x) 2.))
+        ),
+        deftemplate!(tan_quotient
+                     ping (tan x)
+                     pong (/ (sin x) (cos x))
+        ),
+        // Double and half angles. The half angle identities are
+        // squared, to avoid choosing the sign of the square root.
+        deftemplate!(double_angle_sin
+                     ping (sin (* 2. x))
+                     pong (* 2. (* (sin x) (cos x)))
+        ),
+        deftemplate!(double_angle_cos
+                     ping (cos (* 2. x))
+                     pong (- (pow (cos x) 2.) (pow (sin x) 2.))
+        ),
+        deftemplate!(half_angle_sin
+                     ping (pow (sin (/ x 2.)) 2.)
+                     pong (/ (- 1. (cos x)) 2.)
+        ),
+        deftemplate!(half_angle_cos
+                     ping (pow (cos (/ x 2.)) 2.)
+                     pong (/ (+ 1. (cos x)) 2.)
+        ),
+        // Sums to products.
+        deftemplate!(sum_to_product_sin
+                     ping (+ (sin a) (sin b))
+                     pong (* 2. (* (sin (/ (+ a b) 2.)) (cos (/ (- a b) 2.))))
+        ),
+        deftemplate!(diff_to_product_sin
+                     ping (- (sin a) (sin b))
+                     pong (* 2. (* (cos (/ (+ a b) 2.)) (sin (/ (- a b) 2.))))
+        ),
+        deftemplate!(sum_to_product_cos
+                     ping (+ (cos a) (cos b))
+                     pong (* 2. (* (cos (/ (+ a b) 2.)) (cos (/ (- a b) 2.))))
+        ),
+        deftemplate!(diff_to_product_cos
+                     ping (- (cos a) (cos b))
+                     pong (* 2. (* (sin (/ (+ a b) 2.)) (sin (/ (- b a) 2.))))
+        ),
+        // Parity.
+        deftemplate!(sin_odd
+                     ping (sin (- x))
+                     pong (- (sin x))
+        ),
+        deftemplate!(cos_even
+                     ping (cos (- x))
+                     pong (cos x)
+        ),
+        deftemplate!(tan_odd
+                     ping (tan (- x))
+                     pong (- (tan x))
+        ),
+
+        // ====== Logarithms and exponentials ======
+
+        // The logarithms of non-positive numbers are not defined, so
+        // these are only applied where the arguments are known to be
+        // positive, in both directions.
+        deftemplate!(log_of_product
+                     ping (log (* a b))
+                     pong (+ (log a) (log b))
+                     guard (a Positive)
+                     guard (b Positive)
+        ),
+        deftemplate!(log_of_quotient
+                     ping (log (/ a b))
+                     pong (- (log a) (log b))
+                     guard (a Positive)
+                     guard (b Positive)
+        ),
+        deftemplate!(log_of_pow
+                     ping (log (pow a k))
+                     pong (* k (log a))
+                     guard (a Positive)
+        ),
+        deftemplate!(log_of_sqrt
+                     ping (log (sqrt a))
+                     pong (/ (log a) 2.)
+                     guard (a Positive)
+        ),
+        deftemplate!(log_of_exp
+                     ping (log (exp x))
+                     pong (x)
+        ),
+        deftemplate!(exp_of_log
+                     ping (exp (log x))
+                     pong (x)
+                     guard (x Positive)
+        ),
+        deftemplate!(exp_of_sum
+                     ping (exp (+ a b))
+                     pong (* (exp a) (exp b))
+        ),
+        deftemplate!(exp_of_diff
+                     ping (exp (- a b))
+                     pong (/ (exp a) (exp b))
+        ),
+        deftemplate!(pow_as_exp
+                     ping (pow a k)
+                     pong (exp (* k (log a)))
+                     guard (a Positive)
+        ),
     ];
 
-    static ref MIRRORED_TEMPLATES: Vec<Template> = mirrored(&TEMPLATES);
+    static ref BUILTIN_RULES: RuleSet = {
+        let mut rules = RuleSet::empty();
+        for template in TEMPLATES.iter() {
+            rules.push(template.clone(), template.mirrored());
+        }
+        rules
+    };
+}
+
+/// A set of templates used to mutate and simplify trees.
+///
+/// Every template added to the set is checked for validity, and its
+/// mirror, i.e. the template that rewrites the pong back into the
+/// ping, is added automatically, unless it is redundant or invalid, or
+/// it would rewrite almost any subtree, i.e. the pong is a single
+/// symbol.
+#[derive(Clone)]
+pub struct RuleSet {
+    /// Templates used for matching. The templates as they were added
+    /// come first, followed by their mirrors in the same order.
+    templates: Vec<Template>,
+    /// Whether each of the templates that were added has a mirror.
+    mirrored: Vec<bool>,
 }
 
-fn mirrored(templates: &Vec<Template>) -> Vec<Template> {
-    let mut out = templates.clone();
-    out.extend(templates.iter().filter_map(|t| t.mirrored()));
-    return out;
+impl RuleSet {
+    /// Create a rule set with all the built-in templates.
+    pub fn new() -> RuleSet {
+        BUILTIN_RULES.clone()
+    }
+
+    /// Create a rule set with no templates.
+    pub fn empty() -> RuleSet {
+        RuleSet {
+            templates: vec![],
+            mirrored: vec![],
+        }
+    }
+
+    /// All templates in this set, including the mirrored ones.
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+
+    /// Number of templates in this set, including the mirrored ones.
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// Find a template by name. Mirrored templates can be found by
+    /// their names too.
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.iter().find(|t| t.name == name)
+    }
+
+    /// Add `template` and its mirror to this set. The template is
+    /// rejected if it is invalid, or if its name or the name of its
+    /// mirror is already taken.
+    pub fn add(&mut self, template: Template) -> Result<(), TemplateError> {
+        self.insert(template, true)
+    }
+
+    /// Add `template` to this set, without its mirror. This is useful
+    /// for rewrites that should only ever be applied in one
+    /// direction.
+    pub fn add_oneway(&mut self, template: Template) -> Result<(), TemplateError> {
+        self.insert(template, false)
+    }
+
+    /// Remove the template with the given `name`, along with its
+    /// mirror, and return it. Mirrored templates cannot be removed on
+    /// their own.
+    pub fn remove(&mut self, name: &str) -> Option<Template> {
+        let originals = self.mirrored.len();
+        let index = self.templates[..originals]
+            .iter()
+            .position(|t| t.name == name)?;
+        if self.mirrored.remove(index) {
+            let mirror = originals + self.mirrored[..index].iter().filter(|m| **m).count();
+            self.templates.remove(mirror);
+        }
+        Some(self.templates.remove(index))
+    }
+
+    fn insert(&mut self, template: Template, mirror: bool) -> Result<(), TemplateError> {
+        template.check()?;
+        let mirrored = if mirror { template.mirrored() } else { None };
+        for name in std::iter::once(&template.name).chain(mirrored.iter().map(|m| &m.name)) {
+            if self.get(name).is_some() {
+                return Err(TemplateError::DuplicateName(name.clone()));
+            }
+        }
+        self.push(template, mirrored);
+        Ok(())
+    }
+
+    /// Add `template` after the other templates that were added, and
+    /// its `mirror`, if any, after the other mirrors.
+    fn push(&mut self, template: Template, mirror: Option<Template>) {
+        self.templates.insert(self.mirrored.len(), template);
+        self.mirrored.push(mirror.is_some());
+        self.templates.extend(mirror);
+    }
 }
 
-pub fn get_templates() -> &'static Vec<Template> {
-    &MIRRORED_TEMPLATES
+impl Default for RuleSet {
+    fn default() -> Self {
+        RuleSet::new()
+    }
 }
 
 #[cfg(test)]
@@ -217,8 +576,8 @@ pub mod test {
     use std::collections::HashSet;
 
     #[cfg(test)]
-    pub fn get_template_by_name(name: &str) -> Option<&Template> {
-        get_templates().iter().find(|&t| t.name == name)
+    pub fn get_template_by_name(name: &str) -> Option<&'static Template> {
+        BUILTIN_RULES.get(name)
     }
 
     #[test]
@@ -233,6 +592,128 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn t_rule_set_add_remove() {
+        use crate::deftree;
+        let mut rules = RuleSet::new();
+        let builtin = rules.len();
+        assert_eq!(builtin, BUILTIN_RULES.len());
+        // Adding a template also adds its mirror.
+        rules
+            .add(Template::from("double", deftree!(+ x x), deftree!(* 2. x)))
+            .unwrap();
+        assert_eq!(rules.len(), builtin + 2);
+        assert!(rules.get("double").is_some());
+        assert!(rules.get("rev_double").is_some());
+        // Names must be unique, including the names of mirrors.
+        assert!(matches!(
+            rules.add(Template::from("double", deftree!(* x 1.), deftree!(x))),
+            Err(TemplateError::DuplicateName(_))
+        ));
+        assert!(matches!(
+            rules.add(Template::from("rev_double", deftree!(- x x), deftree!(0.))),
+            Err(TemplateError::DuplicateName(_))
+        ));
+        // Pong can't have symbols that are not in the ping.
+        assert!(matches!(
+            rules.add(Template::from("bad", deftree!(* x 0.), deftree!(y))),
+            Err(TemplateError::UnboundSymbol('y'))
+        ));
+        // Symmetric templates are not mirrored.
+        rules
+            .add(Template::from(
+                "swap_sub",
+                deftree!(- (- a b) c),
+                deftree!(- (- a c) b),
+            ))
+            .unwrap();
+        assert_eq!(rules.len(), builtin + 3);
+        // With or without guards.
+        rules
+            .add(
+                Template::from("swap_div", deftree!(/ (/ a b) c), deftree!(/ (/ a c) b))
+                    .guard('b', Condition::NonZero)
+                    .guard('c', Condition::NonZero),
+            )
+            .unwrap();
+        assert_eq!(rules.len(), builtin + 4);
+        // Templates that would rewrite a lone symbol are not mirrored,
+        // with or without guards.
+        rules
+            .add(Template::from("add_zero", deftree!(+ x 0.), deftree!(x)))
+            .unwrap();
+        assert_eq!(rules.len(), builtin + 5);
+        assert!(rules.get("rev_add_zero").is_none());
+        assert!(rules.get("rev_log_of_exp").is_none());
+        assert!(rules.get("rev_exp_of_log").is_none());
+        assert!(rules.remove("add_zero").is_some());
+        // Removing a template also removes its mirror.
+        assert!(rules.remove("rev_double").is_none());
+        assert_eq!(rules.remove("double").unwrap().name(), "double");
+        assert!(rules.get("rev_double").is_none());
+        assert_eq!(rules.len(), builtin + 2);
+        // Built-in templates can be removed too.
+        assert!(rules.remove("distribute_mul").is_some());
+        assert!(rules.get("rev_distribute_mul").is_none());
+        assert!(RuleSet::empty().is_empty());
+        // The templates that were added come first, followed by their
+        // mirrors, in the same order.
+        let mut rules = RuleSet::empty();
+        for (name, ping, pong) in [
+            ("a", deftree!(+ x x), deftree!(* 2. x)),
+            ("b", deftree!(+ x 0.), deftree!(x)),
+            ("c", deftree!(* x x), deftree!(pow x 2.)),
+            ("d", deftree!(- x x), deftree!(* 0. x)),
+        ] {
+            rules.add(Template::from(name, ping, pong)).unwrap();
+        }
+        let names = |rules: &RuleSet| -> Vec<String> {
+            rules
+                .templates()
+                .iter()
+                .map(|t| t.name().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(&rules),
+            ["a", "b", "c", "d", "rev_a", "rev_c", "rev_d"]
+        );
+        rules.remove("c").unwrap();
+        assert_eq!(names(&rules), ["a", "b", "d", "rev_a", "rev_d"]);
+        rules.remove("a").unwrap();
+        assert_eq!(names(&rules), ["b", "d", "rev_d"]);
+    }
+
+    #[test]
+    fn t_constant_symbols() {
+        use crate::deftree;
+        let mut rules = RuleSet::empty();
+        // Templates that compute constants are not mirrored.
+        rules
+            .add(
+                Template::from(
+                    "sub_constants",
+                    deftree!(- c (- d x)),
+                    deftree!(+ (- c d) x),
+                )
+                .constant('c')
+                .constant('d'),
+            )
+            .unwrap();
+        assert_eq!(rules.len(), 1);
+        // Templates that only move constants around are.
+        rules
+            .add(Template::from("move_constant", deftree!(* x c), deftree!(* c x)).constant('c'))
+            .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules.get("rev_move_constant").unwrap().constants(), &['c']);
+        assert!(matches!(
+            rules.add(Template::from("bad", deftree!(* x 1.), deftree!(x)).constant('c')),
+            Err(TemplateError::UnboundConstant('c'))
+        ));
+        assert!(BUILTIN_RULES.get("rev_combine_mul_constants").is_none());
+    }
+
     #[test]
     fn t_check_templates() {
         let mut checked: HashSet<&str> = HashSet::with_capacity(TEMPLATES.len());
@@ -308,6 +789,16 @@ pub mod test {
                 &[('a', -10., 10.), ('b', -10., 10.), ('d', -10., 10.)],
                 1e-12,
             );
+            check_one(
+                "combine_mul_constants",
+                &[('c', -10., 10.), ('d', -10., 10.), ('x', -10., 10.)],
+                1e-12,
+            );
+            check_one(
+                "combine_add_constants",
+                &[('c', -10., 10.), ('d', -10., 10.), ('x', -10., 10.)],
+                1e-12,
+            );
         }
         {
             // === Other templates ===
@@ -329,6 +820,58 @@ pub mod test {
                 0.,
             );
         }
+        {
+            // === Trigonometric templates ===
+            check_one("pythagoras", &[('x', -10., 10.)], 1e-14);
+            check_one("pythagoras_sin", &[('x', -10., 10.)], 1e-14);
+            check_one("pythagoras_cos", &[('x', -10., 10.)], 1e-14);
+            // Away from the poles of tan.
+            check_one("pythagoras_tan", &[('x', -1.5, 1.5)], 1e-12);
+            check_one("tan_quotient", &[('x', -1.5, 1.5)], 1e-12);
+            check_one("double_angle_sin", &[('x', -10., 10.)], 1e-14);
+            check_one("double_angle_cos", &[('x', -10., 10.)], 1e-14);
+            check_one("half_angle_sin", &[('x', -10., 10.)], 1e-14);
+            check_one("half_angle_cos", &[('x', -10., 10.)], 1e-14);
+            check_one(
+                "sum_to_product_sin",
+                &[('a', -10., 10.), ('b', -10., 10.)],
+                1e-14,
+            );
+            check_one(
+                "diff_to_product_sin",
+                &[('a', -10., 10.), ('b', -10., 10.)],
+                1e-14,
+            );
+            check_one(
+                "sum_to_product_cos",
+                &[('a', -10., 10.), ('b', -10., 10.)],
+                1e-14,
+            );
+            check_one(
+                "diff_to_product_cos",
+                &[('a', -10., 10.), ('b', -10., 10.)],
+                1e-14,
+            );
+            check_one("sin_odd", &[('x', -10., 10.)], 0.);
+            check_one("cos_even", &[('x', -10., 10.)], 0.);
+            check_one("tan_odd", &[('x', -1.5, 1.5)], 0.);
+        }
+        {
+            // === Logarithm and exponential templates ===
+            check_one("log_of_product", &[('a', 0.1, 10.), ('b', 0.1, 10.)], 1e-14);
+            check_one(
+                "log_of_quotient",
+                &[('a', 0.1, 10.), ('b', 0.1, 10.)],
+                1e-14,
+            );
+            check_one("log_of_pow", &[('a', 0.1, 10.), ('k', -5., 5.)], 1e-13);
+            check_one("log_of_sqrt", &[('a', 0.1, 10.)], 1e-15);
+            check_one("log_of_exp", &[('x', -10., 10.)], 1e-14);
+            check_one("exp_of_log", &[('x', 0.1, 10.)], 1e-14);
+            check_one("exp_of_sum", &[('a', -5., 5.), ('b', -5., 5.)], 1e-10);
+            check_one("exp_of_diff", &[('a', -5., 5.), ('b', -5., 5.)], 1e-10);
+            check_one("pow_as_exp", &[('a', 0.1, 10.), ('k', -3., 3.)], 1e-10);
+        }
         {
             // Make sure all templates have been checked.
             let unchecked = TEMPLATES
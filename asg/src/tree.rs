@@ -1,5 +1,5 @@
 /// Represents an operation with one input.
-#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum UnaryOp {
     Negate,
     Sqrt,
@@ -12,7 +12,7 @@ pub enum UnaryOp {
 }
 
 /// Represents an operation with two inputs.
-#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum BinaryOp {
     Add,
     Subtract,
@@ -95,8 +95,23 @@ impl BinaryOp {
             Max => true,
         }
     }
+
+    /// Check if the binary op is associative.
+    pub fn is_associative(&self) -> bool {
+        use BinaryOp::*;
+        match self {
+            Add => true,
+            Subtract => false,
+            Multiply => true,
+            Divide => false,
+            Pow => false,
+            Min => true,
+            Max => true,
+        }
+    }
 }
 
+use crate::{exact::Rational, named::NamedConstant};
 use {BinaryOp::*, UnaryOp::*};
 
 /// Errors that can occur when constructing a tree.
@@ -114,6 +129,10 @@ pub enum TreeError {
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Node {
     Constant(f64),
+    /// Exact rational constant, see `Rational`.
+    Rational(Rational),
+    /// Constant represented by its name, see `NamedConstant`.
+    Named(NamedConstant),
     Symbol(char),
     Unary(UnaryOp, usize),
     Binary(BinaryOp, usize, usize),
@@ -135,6 +154,27 @@ impl Tree {
         }
     }
 
+    /// Create a tree representing an exact rational constant.
+    pub fn rational(value: Rational) -> Tree {
+        Tree {
+            nodes: vec![Rational(value)],
+        }
+    }
+
+    /// Create a tree representing a named constant.
+    pub fn named(value: NamedConstant) -> Tree {
+        Tree {
+            nodes: vec![Named(value)],
+        }
+    }
+
+    /// Create a tree from a list of `nodes`. The nodes are expected
+    /// to be topologically sorted, with the root as the last node. If
+    /// they are not valid, the appropriate `TreeError` is returned.
+    pub fn from_nodes(nodes: Vec<Node>) -> Result<Tree, TreeError> {
+        Tree { nodes }.validated()
+    }
+
     /// Create a tree representing a symbol with the given `label`.
     pub fn symbol(label: char) -> Tree {
         Tree {
@@ -210,12 +250,14 @@ impl Tree {
         return Ok(self);
     }
 
-    fn binary_op(mut self, other: Tree, op: BinaryOp) -> Tree {
+    pub(crate) fn binary_op(mut self, other: Tree, op: BinaryOp) -> Tree {
         let offset: usize = self.nodes.len();
         self.nodes
             .reserve(self.nodes.len() + other.nodes.len() + 1usize);
         self.nodes.extend(other.nodes.iter().map(|node| match node {
             Constant(value) => Constant(*value),
+            Rational(value) => Rational(*value),
+            Named(value) => Named(*value),
             Symbol(label) => Symbol(label.clone()),
             Unary(op, input) => Unary(*op, *input + offset),
             Binary(op, lhs, rhs) => Binary(*op, *lhs + offset, *rhs + offset),
@@ -225,7 +267,7 @@ impl Tree {
         return self;
     }
 
-    fn unary_op(mut self, op: UnaryOp) -> Tree {
+    pub(crate) fn unary_op(mut self, op: UnaryOp) -> Tree {
         self.nodes.push(Unary(op, self.root_index()));
         return self;
     }
@@ -328,6 +370,18 @@ impl From<f64> for Tree {
     }
 }
 
+impl From<Rational> for Tree {
+    fn from(value: Rational) -> Self {
+        Self::rational(value)
+    }
+}
+
+impl From<NamedConstant> for Tree {
+    fn from(value: NamedConstant) -> Self {
+        Self::named(value)
+    }
+}
+
 impl From<char> for Tree {
     fn from(c: char) -> Self {
         return Self::symbol(c);
@@ -343,21 +397,43 @@ impl PartialOrd for Node {
         match (self, other) {
             // Constant
             (Constant(a), Constant(b)) => a.partial_cmp(b),
+            (Constant(_), Rational(_)) => Some(Less),
+            (Constant(_), Named(_)) => Some(Less),
             (Constant(_), Symbol(_)) => Some(Less),
             (Constant(_), Unary(..)) => Some(Less),
             (Constant(_), Binary(..)) => Some(Less),
+            // Rational
+            (Rational(_), Constant(_)) => Some(Greater),
+            (Rational(a), Rational(b)) => Some(a.cmp(b)),
+            (Rational(_), Named(_)) => Some(Less),
+            (Rational(_), Symbol(_)) => Some(Less),
+            (Rational(_), Unary(..)) => Some(Less),
+            (Rational(_), Binary(..)) => Some(Less),
+            // Named
+            (Named(_), Constant(_)) => Some(Greater),
+            (Named(_), Rational(_)) => Some(Greater),
+            (Named(a), Named(b)) => Some(a.cmp(b)),
+            (Named(_), Symbol(_)) => Some(Less),
+            (Named(_), Unary(..)) => Some(Less),
+            (Named(_), Binary(..)) => Some(Less),
             // Symbol
             (Symbol(_), Constant(_)) => Some(Greater),
+            (Symbol(_), Rational(_)) => Some(Greater),
+            (Symbol(_), Named(_)) => Some(Greater),
             (Symbol(a), Symbol(b)) => Some(a.cmp(b)),
             (Symbol(_), Unary(..)) => Some(Less),
             (Symbol(_), Binary(..)) => Some(Less),
             // Unary
             (Unary(..), Constant(_)) => Some(Greater),
+            (Unary(..), Rational(_)) => Some(Greater),
+            (Unary(..), Named(_)) => Some(Greater),
             (Unary(..), Symbol(_)) => Some(Greater),
             (Unary(op1, _), Unary(op2, _)) => Some(op1.index().cmp(&op2.index())),
             (Unary(..), Binary(..)) => Some(Less),
             // Binary
             (Binary(..), Constant(_)) => Some(Greater),
+            (Binary(..), Rational(_)) => Some(Greater),
+            (Binary(..), Named(_)) => Some(Greater),
             (Binary(..), Symbol(_)) => Some(Greater),
             (Binary(..), Unary(..)) => Some(Greater),
             (Binary(op1, ..), Binary(op2, ..)) => Some(op1.index().cmp(&op2.index())),
@@ -0,0 +1,270 @@
+use crate::{
+    eval::{EvaluationError, Evaluator},
+    facts::Props,
+    template::{RuleSet, Template},
+    tree::Tree,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Values that commonly break rewrite rules, e.g. by dividing by
+/// zero, flipping signs or changing the result of a power. These are
+/// tried, along with the bounds of the domains, before sampling
+/// random values.
+const EDGE_VALUES: [f64; 9] = [0., 1., -1., 0.5, -0.5, 2., -2., 1e-8, -1e-8];
+
+/// Seed of the random number generator, so the results are
+/// reproducible.
+const SEED: u64 = 42;
+
+/// Values of the symbols for which two trees don't evaluate to the
+/// same result.
+#[derive(Debug)]
+pub struct Counterexample {
+    /// Value of each symbol.
+    pub values: Vec<(char, f64)>,
+    /// Value of the first tree.
+    pub lhs: f64,
+    /// Value of the second tree.
+    pub rhs: f64,
+}
+
+/// Errors that can occur when numerically verifying trees and
+/// templates.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// A symbol in one of the trees doesn't have a domain to sample
+    /// its values from.
+    MissingDomain(char),
+    /// One of the trees could not be evaluated.
+    Evaluation(EvaluationError),
+    /// The trees evaluate to different values.
+    Mismatch(Counterexample),
+}
+
+/// Check if `a` and `b` are within `tolerance` of each other. The
+/// tolerance is relative for values larger than 1. Two NaN's, or two
+/// infinities of the same sign are considered equal, because in both
+/// cases the trees agree that the result is undefined or unbounded.
+fn approx_equal(a: f64, b: f64, tolerance: f64) -> bool {
+    if a.is_nan() || b.is_nan() {
+        return a.is_nan() && b.is_nan();
+    }
+    if a.is_infinite() || b.is_infinite() {
+        return a == b;
+    }
+    f64::abs(a - b) <= tolerance * f64::max(1., f64::max(a.abs(), b.abs()))
+}
+
+/// Evaluate `a` and `b` with values of their symbols sampled from
+/// `domains`, and make sure they produce the same values within
+/// `tolerance`. Each entry in `domains` consists of the label of a
+/// symbol, and the lower and upper bounds of its values.
+///
+/// Combinations of edge values within the domains, such as the
+/// bounds, zero and one, are tried first. After that, `samples` sets
+/// of values are sampled at random. The number of edge value
+/// combinations is also limited to `samples`. The first set of values
+/// for which the trees differ is returned as a counterexample.
+pub fn numerically_equivalent(
+    a: &Tree,
+    b: &Tree,
+    domains: &[(char, f64, f64)],
+    samples: usize,
+    tolerance: f64,
+) -> Result<(), VerifyError> {
+    check_samples(a, b, domains, samples, tolerance, |_| true)
+}
+
+/// Make sure `template` doesn't change the value of the trees it is
+/// applied to, by comparing the ping and the pong of the template
+/// using `numerically_equivalent`. Values that don't satisfy the
+/// guards of the template are skipped.
+pub fn verify_template(
+    template: &Template,
+    domains: &[(char, f64, f64)],
+    samples: usize,
+    tolerance: f64,
+) -> Result<(), VerifyError> {
+    check_samples(
+        template.ping(),
+        template.pong(),
+        domains,
+        samples,
+        tolerance,
+        |values| {
+            template.guards().iter().all(|(label, condition)| {
+                match values.iter().find(|(l, _)| l == label) {
+                    Some((_, value)) => Props::of_value(*value).satisfies(*condition),
+                    None => true,
+                }
+            })
+        },
+    )
+}
+
+/// Verify all templates in `rules`, including the mirrored ones,
+/// using `verify_template`. The values of all symbols are sampled
+/// from the same `domain`, i.e. lower and upper bounds. The names of
+/// the templates that fail verification are returned along with the
+/// errors.
+pub fn verify_rules(
+    rules: &RuleSet,
+    domain: (f64, f64),
+    samples: usize,
+    tolerance: f64,
+) -> Vec<(String, VerifyError)> {
+    let (lower, upper) = domain;
+    rules
+        .templates()
+        .iter()
+        .filter_map(|template| {
+            let domains: Vec<_> = template
+                .ping()
+                .symbols()
+                .iter()
+                .map(|label| (*label, lower, upper))
+                .collect();
+            verify_template(template, &domains, samples, tolerance)
+                .err()
+                .map(|e| (template.name().to_string(), e))
+        })
+        .collect()
+}
+
+fn check_samples<F>(
+    a: &Tree,
+    b: &Tree,
+    domains: &[(char, f64, f64)],
+    samples: usize,
+    tolerance: f64,
+    mut accept: F,
+) -> Result<(), VerifyError>
+where
+    F: FnMut(&[(char, f64)]) -> bool,
+{
+    for label in a.symbols().iter().chain(b.symbols().iter()) {
+        if !domains.iter().any(|(l, _, _)| l == label) {
+            return Err(VerifyError::MissingDomain(*label));
+        }
+    }
+    let mut eval_a = Evaluator::new(a);
+    let mut eval_b = Evaluator::new(b);
+    let mut values: Vec<(char, f64)> = domains.iter().map(|(l, lo, _)| (*l, *lo)).collect();
+    let mut check = |values: &[(char, f64)]| -> Result<(), VerifyError> {
+        if !accept(values) {
+            return Ok(());
+        }
+        for (label, value) in values {
+            eval_a.set_var(*label, *value);
+            eval_b.set_var(*label, *value);
+        }
+        let lhs = eval_a.run().map_err(VerifyError::Evaluation)?;
+        let rhs = eval_b.run().map_err(VerifyError::Evaluation)?;
+        if approx_equal(lhs, rhs, tolerance) {
+            Ok(())
+        } else {
+            Err(VerifyError::Mismatch(Counterexample {
+                values: values.to_vec(),
+                lhs,
+                rhs,
+            }))
+        }
+    };
+    // Try combinations of edge values.
+    let edges: Vec<Vec<f64>> = domains
+        .iter()
+        .map(|(_, lower, upper)| {
+            let mut edges = vec![*lower, *upper];
+            for value in EDGE_VALUES {
+                if *lower <= value && value <= *upper && !edges.contains(&value) {
+                    edges.push(value);
+                }
+            }
+            edges
+        })
+        .collect();
+    let mut indices = vec![0usize; domains.len()];
+    for _ in 0..samples {
+        for ((_, value), (edges, i)) in values.iter_mut().zip(edges.iter().zip(indices.iter())) {
+            *value = edges[*i];
+        }
+        check(&values)?;
+        // Advance to the next combination, like an odometer.
+        let mut done = true;
+        for (i, edges) in indices.iter_mut().zip(edges.iter()) {
+            *i += 1;
+            if *i < edges.len() {
+                done = false;
+                break;
+            }
+            *i = 0;
+        }
+        if done {
+            break;
+        }
+    }
+    // Try random values.
+    let mut rng = StdRng::seed_from_u64(SEED);
+    for _ in 0..samples {
+        for ((_, value), (_, lower, upper)) in values.iter_mut().zip(domains.iter()) {
+            *value = *lower + rng.gen::<f64>() * (*upper - *lower);
+        }
+        check(&values)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deftree;
+
+    #[test]
+    fn t_numerically_equivalent() {
+        let domains = [('x', -10., 10.), ('y', -10., 10.)];
+        assert!(numerically_equivalent(
+            &deftree!(pow (+ x y) 2),
+            &deftree!(+ (+ (* x x) (* y y)) (* 2 (* x y))),
+            &domains,
+            100,
+            1e-12
+        )
+        .is_ok());
+        // Edge values are tried before random ones.
+        match numerically_equivalent(&deftree!(/ x x), &deftree!(1), &domains, 100, 1e-12) {
+            Err(VerifyError::Mismatch(c)) => {
+                assert_eq!(c.values[0], ('x', 0.));
+                assert!(c.lhs.is_nan());
+                assert_eq!(c.rhs, 1.);
+            }
+            _ => panic!("Expected a counterexample"),
+        }
+        match numerically_equivalent(&deftree!(abs x), &deftree!(x), &domains, 100, 1e-12) {
+            Err(VerifyError::Mismatch(c)) => assert!(c.values[0].1 < 0.),
+            _ => panic!("Expected a counterexample"),
+        }
+        assert!(matches!(
+            numerically_equivalent(&deftree!(+ x z), &deftree!(x), &domains, 10, 0.),
+            Err(VerifyError::MissingDomain('z'))
+        ));
+    }
+
+    #[test]
+    fn t_verify_template() {
+        let rules = RuleSet::new();
+        // The guard of this template keeps 0 out of the domain.
+        let template = rules.get("divide_by_self").unwrap();
+        assert!(verify_template(template, &[('a', -10., 10.)], 100, 1e-12).is_ok());
+        let template = Template::from("bad", deftree!(sqrt (pow a 2)), deftree!(a));
+        assert!(verify_template(&template, &[('a', -10., 10.)], 100, 1e-12).is_err());
+        assert!(verify_template(&template, &[('a', 0., 10.)], 100, 1e-12).is_ok());
+    }
+
+    #[test]
+    fn t_verify_rules() {
+        // All the built-in templates, including the mirrored ones,
+        // must hold on the same domain, given their guards.
+        let failed = verify_rules(&RuleSet::new(), (-10., 10.), 200, 1e-9);
+        assert!(failed.is_empty(), "{:?}", failed);
+    }
+}
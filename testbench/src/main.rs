@@ -1,10 +1,17 @@
-use asg::{deftree, reduce::reduce};
+use asg::{
+    deftree,
+    facts::{Condition::Positive, Facts},
+    reduce::reduce_with_facts,
+    template::RuleSet,
+};
 
 fn main() {
     let tree = deftree!(/ (+ (* k x) (* k y)) (+ x y));
     let max_iter = 10;
     println!("${}$\n", tree.to_latex());
-    let steps = reduce(tree, max_iter).unwrap();
+    // Cancelling (x + y) / (x + y) requires x + y to be non-zero.
+    let facts = Facts::new().with('x', Positive).with('y', Positive);
+    let steps = reduce_with_facts(tree, &RuleSet::new(), facts, max_iter).unwrap();
     for step in steps {
         println!("$= {}$\n", step.to_latex());
     }